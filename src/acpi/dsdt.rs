@@ -0,0 +1,27 @@
+use super::{find_table, SdtHeader};
+
+// ACPI 6.5 specification, section 5.2.11.1
+
+const DSDT_SIGNATURE: &[u8; 4] = b"DSDT";
+
+// Differentiated System Description Table. Not listed in the RSDT/XSDT, the
+// FADT points at it. What's in it is AML bytecode there's no interpreter for,
+// so this only gets at the raw definition block.
+#[derive(Clone, Copy)]
+pub struct Dsdt {
+    table: &'static SdtHeader,
+}
+
+impl Dsdt {
+    pub fn find() -> Option<Self> {
+        find_table(DSDT_SIGNATURE).map(|table| Dsdt { table })
+    }
+
+    pub fn header(&self) -> &'static SdtHeader {
+        self.table
+    }
+
+    pub fn aml(&self) -> &'static [u8] {
+        self.table.data()
+    }
+}
@@ -0,0 +1,112 @@
+use core::mem::size_of;
+use super::{find_table, GenericAddress, SdtHeader};
+
+// https://wiki.osdev.org/FADT
+// ACPI 6.5 specification, section 5.2.9
+
+const FADT_SIGNATURE: &[u8; 4] = b"FACP";
+
+// IA-PC boot architecture flags
+const BOOT_ARCH_8042: u16 = 1 << 1;
+const BOOT_ARCH_NO_CMOS_RTC: u16 = 1 << 5;
+
+// fixed feature flags
+pub const FLAG_RESET_REG_SUPPORTED: u32 = 1 << 10;
+pub const FLAG_HW_REDUCED_ACPI: u32 = 1 << 20;
+
+// Everything up to the 64-bit register blocks of ACPI 2.0. Older, shorter
+// revisions leave the fields they don't have zeroed.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct Fadt {
+    pub header: SdtHeader,
+    pub firmware_ctrl: u32,
+    pub dsdt: u32,
+    reserved0: u8,
+    pub preferred_pm_profile: u8,
+    pub sci_interrupt: u16,
+    pub smi_command: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub s4bios_request: u8,
+    pub pstate_control: u8,
+    pub pm1a_event_block: u32,
+    pub pm1b_event_block: u32,
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+    pub pm2_control_block: u32,
+    pub pm_timer_block: u32,
+    pub gpe0_block: u32,
+    pub gpe1_block: u32,
+    pub pm1_event_length: u8,
+    pub pm1_control_length: u8,
+    pub pm2_control_length: u8,
+    pub pm_timer_length: u8,
+    pub gpe0_block_length: u8,
+    pub gpe1_block_length: u8,
+    pub gpe1_base: u8,
+    pub cstate_control: u8,
+    pub c2_latency: u16,
+    pub c3_latency: u16,
+    pub flush_size: u16,
+    pub flush_stride: u16,
+    pub duty_offset: u8,
+    pub duty_width: u8,
+    pub day_alarm: u8,
+    pub month_alarm: u8,
+    pub century: u8,
+    pub iapc_boot_arch: u16,
+    reserved1: u8,
+    pub flags: u32,
+    pub reset_register: GenericAddress,
+    pub reset_value: u8,
+    pub arm_boot_arch: u16,
+    pub minor_version: u8,
+    pub x_firmware_ctrl: u64,
+    pub x_dsdt: u64,
+    pub x_pm1a_event_block: GenericAddress,
+    pub x_pm1b_event_block: GenericAddress,
+    pub x_pm1a_control_block: GenericAddress,
+    pub x_pm1b_control_block: GenericAddress,
+    pub x_pm2_control_block: GenericAddress,
+    pub x_pm_timer_block: GenericAddress,
+    pub x_gpe0_block: GenericAddress,
+    pub x_gpe1_block: GenericAddress,
+}
+
+impl Fadt {
+    // Fixed ACPI Description Table, copied out so missing fields read as 0
+    pub fn find() -> Option<Self> {
+        let bytes = find_table(FADT_SIGNATURE)?.bytes();
+        let mut fadt = [0u8; size_of::<Fadt>()];
+        let len = bytes.len().min(fadt.len());
+        fadt[..len].copy_from_slice(&bytes[..len]);
+        Some(unsafe { core::ptr::read_unaligned(fadt.as_ptr() as *const Fadt) })
+    }
+
+    // Physical address of the DSDT, the 64-bit one if it's there
+    pub fn dsdt_address(&self) -> u64 {
+        match self.x_dsdt {
+            0 => self.dsdt as u64,
+            address => address,
+        }
+    }
+
+    // CMOS register with the century, if the RTC has one
+    pub fn century_register(&self) -> Option<u8> {
+        Some(self.century).filter(|&reg| reg != 0)
+    }
+
+    pub fn has_cmos_rtc(&self) -> bool {
+        self.iapc_boot_arch & BOOT_ARCH_NO_CMOS_RTC == 0
+    }
+
+    // Only meaningful from revision 3 on, older ones always say no
+    pub fn has_8042(&self) -> bool {
+        self.iapc_boot_arch & BOOT_ARCH_8042 != 0
+    }
+
+    pub fn is_hardware_reduced(&self) -> bool {
+        self.flags & FLAG_HW_REDUCED_ACPI != 0
+    }
+}
@@ -0,0 +1,57 @@
+use super::{find_table, GenericAddress, SdtHeader, ADDRESS_SPACE_MEMORY};
+
+// https://wiki.osdev.org/HPET
+// IA-PC HPET specification 1.0a, section 3.2.4
+
+const HPET_SIGNATURE: &[u8; 4] = b"HPET";
+
+const ID_COMPARATORS_SHIFT: u32 = 8;
+const ID_COMPARATORS_MASK: u32 = 0x1F;
+const ID_COUNTER_64BIT: u32 = 1 << 13;
+const ID_LEGACY_REPLACEMENT: u32 = 1 << 15;
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct HpetHeader {
+    header: SdtHeader,
+    event_timer_block_id: u32,
+    base_address: GenericAddress,
+    hpet_number: u8,
+    minimum_tick: u16,
+    page_protection: u8,
+}
+
+// HPET Description Table, one per timer block
+#[derive(Debug, Clone, Copy)]
+pub struct HpetTable {
+    pub base_address: u64,
+    pub hpet_number: u8,
+    // smallest periodic interval that won't lose interrupts, in counter ticks
+    pub minimum_tick: u16,
+    pub comparators: u8,
+    pub counter_64bit: bool,
+    pub legacy_replacement: bool,
+}
+
+impl HpetTable {
+    // The first timer block, if it's memory mapped like it should be
+    pub fn find() -> Option<Self> {
+        let table = find_table(HPET_SIGNATURE)?;
+        if (table.length as usize) < core::mem::size_of::<HpetHeader>() {
+            return None;
+        }
+        let raw = unsafe { core::ptr::read_unaligned(table as *const SdtHeader as *const HpetHeader) };
+        if raw.base_address.address_space != ADDRESS_SPACE_MEMORY {
+            return None;
+        }
+        let id = raw.event_timer_block_id;
+        Some(HpetTable {
+            base_address: raw.base_address.address,
+            hpet_number: raw.hpet_number,
+            minimum_tick: raw.minimum_tick,
+            comparators: ((id >> ID_COMPARATORS_SHIFT) & ID_COMPARATORS_MASK) as u8 + 1,
+            counter_64bit: id & ID_COUNTER_64BIT != 0,
+            legacy_replacement: id & ID_LEGACY_REPLACEMENT != 0,
+        })
+    }
+}
@@ -0,0 +1,196 @@
+use core::mem::size_of;
+use super::{find_table, SdtHeader};
+
+// https://wiki.osdev.org/MADT
+
+const MADT_SIGNATURE: &[u8; 4] = b"APIC";
+// dual 8259s are present as well
+const FLAG_PCAT_COMPAT: u32 = 1 << 0;
+
+const ENTRY_LOCAL_APIC: u8 = 0;
+const ENTRY_IO_APIC: u8 = 1;
+const ENTRY_INTERRUPT_SOURCE_OVERRIDE: u8 = 2;
+const ENTRY_NMI_SOURCE: u8 = 3;
+const ENTRY_LOCAL_APIC_NMI: u8 = 4;
+const ENTRY_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+const ENTRY_LOCAL_X2APIC: u8 = 9;
+const ENTRY_LOCAL_X2APIC_NMI: u8 = 10;
+
+// MPS INTI flags of overrides and NMI entries
+const POLARITY_MASK: u16 = 0b11;
+const POLARITY_ACTIVE_LOW: u16 = 0b11;
+const TRIGGER_MASK: u16 = 0b11 << 2;
+const TRIGGER_LEVEL: u16 = 0b11 << 2;
+
+// local APIC entries with this set belong to a usable processor
+pub const LOCAL_APIC_ENABLED: u32 = 1 << 0;
+// processor uid that means all processors in NMI entries
+pub const ALL_PROCESSORS: u32 = 0xFF;
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct MadtHeader {
+    header: SdtHeader,
+    local_apic_address: u32,
+    flags: u32,
+}
+
+// Polarity and trigger mode of an interrupt input, from MPS INTI flags.
+// "Conforms to the bus" comes out as ISA's active high, edge triggered.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IntiFlags {
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+impl IntiFlags {
+    pub fn from_bits(flags: u16) -> Self {
+        IntiFlags {
+            active_low: flags & POLARITY_MASK == POLARITY_ACTIVE_LOW,
+            level_triggered: flags & TRIGGER_MASK == TRIGGER_LEVEL,
+        }
+    }
+}
+
+// A usable processor and the local APIC that belongs to it
+#[derive(Debug, Clone, Copy)]
+pub struct Processor {
+    pub uid: u32,
+    pub apic_id: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum MadtEntry {
+    LocalApic { processor_uid: u32, apic_id: u32, flags: u32 },
+    IoApic { id: u8, address: u32, gsi_base: u32 },
+    InterruptSourceOverride { bus: u8, source: u8, gsi: u32, flags: IntiFlags },
+    NmiSource { gsi: u32, flags: IntiFlags },
+    LocalApicNmi { processor_uid: u32, lint: u8, flags: IntiFlags },
+    LocalApicAddressOverride { address: u64 },
+    Unknown(u8),
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+impl MadtEntry {
+    // `data` is the whole entry including its type and length bytes
+    fn parse(data: &[u8]) -> Self {
+        match data[0] {
+            ENTRY_LOCAL_APIC => MadtEntry::LocalApic {
+                processor_uid: data[2] as u32,
+                apic_id: data[3] as u32,
+                flags: u32_at(data, 4),
+            },
+            ENTRY_IO_APIC => MadtEntry::IoApic {
+                id: data[2],
+                address: u32_at(data, 4),
+                gsi_base: u32_at(data, 8),
+            },
+            ENTRY_INTERRUPT_SOURCE_OVERRIDE => MadtEntry::InterruptSourceOverride {
+                bus: data[2],
+                source: data[3],
+                gsi: u32_at(data, 4),
+                flags: IntiFlags::from_bits(u16_at(data, 8)),
+            },
+            ENTRY_NMI_SOURCE => MadtEntry::NmiSource {
+                flags: IntiFlags::from_bits(u16_at(data, 2)),
+                gsi: u32_at(data, 4),
+            },
+            ENTRY_LOCAL_APIC_NMI => MadtEntry::LocalApicNmi {
+                processor_uid: data[2] as u32,
+                flags: IntiFlags::from_bits(u16_at(data, 3)),
+                lint: data[5],
+            },
+            ENTRY_LOCAL_APIC_ADDRESS_OVERRIDE => MadtEntry::LocalApicAddressOverride {
+                address: u64_at(data, 4),
+            },
+            ENTRY_LOCAL_X2APIC => MadtEntry::LocalApic {
+                apic_id: u32_at(data, 4),
+                flags: u32_at(data, 8),
+                processor_uid: u32_at(data, 12),
+            },
+            ENTRY_LOCAL_X2APIC_NMI => MadtEntry::LocalApicNmi {
+                flags: IntiFlags::from_bits(u16_at(data, 2)),
+                processor_uid: u32_at(data, 4),
+                lint: data[8],
+            },
+            other => MadtEntry::Unknown(other),
+        }
+    }
+}
+
+pub struct MadtEntries {
+    data: &'static [u8],
+}
+
+impl Iterator for MadtEntries {
+    type Item = MadtEntry;
+
+    fn next(&mut self) -> Option<MadtEntry> {
+        if self.data.len() < 2 {
+            return None;
+        }
+        let len = self.data[1] as usize;
+        // a zero length entry would loop forever, a too long one is garbage
+        if len < 2 || len > self.data.len() {
+            return None;
+        }
+        let (entry, rest) = self.data.split_at(len);
+        self.data = rest;
+        Some(MadtEntry::parse(entry))
+    }
+}
+
+// Multiple APIC Description Table
+#[derive(Clone, Copy)]
+pub struct Madt {
+    table: &'static SdtHeader,
+}
+
+impl Madt {
+    pub fn find() -> Option<Self> {
+        find_table(MADT_SIGNATURE).map(|table| Madt { table })
+    }
+
+    fn header(&self) -> MadtHeader {
+        unsafe { core::ptr::read_unaligned(self.table as *const SdtHeader as *const MadtHeader) }
+    }
+
+    // Physical address of the local APIC, after any 64-bit override
+    pub fn local_apic_address(&self) -> u64 {
+        self.entries()
+            .find_map(|entry| match entry {
+                MadtEntry::LocalApicAddressOverride { address } => Some(address),
+                _ => None,
+            })
+            .unwrap_or(self.header().local_apic_address as u64)
+    }
+
+    pub fn has_8259(&self) -> bool {
+        self.header().flags & FLAG_PCAT_COMPAT != 0
+    }
+
+    pub fn processors(&self) -> impl Iterator<Item = Processor> {
+        self.entries().filter_map(|entry| match entry {
+            MadtEntry::LocalApic { processor_uid, apic_id, flags } if flags & LOCAL_APIC_ENABLED != 0 => {
+                Some(Processor { uid: processor_uid, apic_id })
+            }
+            _ => None,
+        })
+    }
+
+    pub fn entries(&self) -> MadtEntries {
+        let table = self.table.bytes();
+        MadtEntries { data: &table[size_of::<MadtHeader>().min(table.len())..] }
+    }
+}
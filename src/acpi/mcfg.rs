@@ -0,0 +1,39 @@
+use core::mem::size_of;
+use super::{find_table, SdtHeader};
+
+// https://wiki.osdev.org/PCI_Express
+// PCI Firmware specification 3.2, section 4.1.2
+
+const MCFG_SIGNATURE: &[u8; 4] = b"MCFG";
+// reserved bytes between the header and the first entry
+const ENTRIES_OFFSET: usize = size_of::<SdtHeader>() + 8;
+
+// Where one PCI segment's extended configuration space is memory mapped
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct McfgEntry {
+    pub base_address: u64,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+    reserved: u32,
+}
+
+// PCI Express memory mapped configuration space table
+#[derive(Clone, Copy)]
+pub struct Mcfg {
+    table: &'static SdtHeader,
+}
+
+impl Mcfg {
+    pub fn find() -> Option<Self> {
+        find_table(MCFG_SIGNATURE).map(|table| Mcfg { table })
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = McfgEntry> {
+        let bytes = self.table.bytes();
+        bytes[ENTRIES_OFFSET.min(bytes.len())..]
+            .chunks_exact(size_of::<McfgEntry>())
+            .map(|entry| unsafe { core::ptr::read_unaligned(entry.as_ptr() as *const McfgEntry) })
+    }
+}
@@ -0,0 +1,250 @@
+use core::mem::size_of;
+use x86::bits64::paging::{PAddr, PTFlags};
+use crate::memory::{self, mmio};
+use crate::sync::SpinLock;
+pub mod dsdt;
+pub mod fadt;
+pub mod hpet;
+pub mod madt;
+pub mod mcfg;
+
+// https://wiki.osdev.org/RSDP
+// https://wiki.osdev.org/RSDT
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+// real mode segment of the EBDA is stored here by the BIOS
+const EBDA_SEGMENT_PTR: u64 = 0x40E;
+const EBDA_SEARCH_SIZE: u64 = 1024;
+const BIOS_AREA: core::ops::Range<u64> = 0xE0000..0x100000;
+const RSDP_ALIGN: u64 = 16;
+// bytes the revision 0 checksum covers
+const RSDP_V1_LENGTH: usize = 20;
+
+const MAX_TABLES: usize = 32;
+// way past any real table, including big DSDTs; a header claiming more is corrupt
+const MAX_TABLE_LENGTH: usize = 16 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum AcpiError {
+    NoRsdp,
+    Map(memory::vmm::MapError),
+    // the RSDT/XSDT is damaged, nothing it points at can be trusted
+    BadChecksum([u8; 4]),
+    // header length shorter than a header or absurdly large
+    BadLength([u8; 4], u32),
+}
+
+// All bytes of a table, checksum field included, add up to 0
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // revision 2 and up
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+impl SdtHeader {
+    pub fn signature(&self) -> &str {
+        core::str::from_utf8(&self.signature).unwrap_or("????")
+    }
+
+    // Whole table including the header
+    pub fn bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self as *const Self as *const u8, self.length as usize) }
+    }
+
+    // Everything after the header
+    pub fn data(&self) -> &[u8] {
+        &self.bytes()[size_of::<SdtHeader>()..]
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.length as usize >= size_of::<SdtHeader>() && checksum_ok(self.bytes())
+    }
+}
+
+// Where a register lives, in memory, I/O space or elsewhere
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct GenericAddress {
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+pub const ADDRESS_SPACE_MEMORY: u8 = 0;
+pub const ADDRESS_SPACE_IO: u8 = 1;
+
+// Every table the RSDT/XSDT points at, mapped for good
+struct Tables {
+    tables: [Option<&'static SdtHeader>; MAX_TABLES],
+}
+
+static TABLES: SpinLock<Tables> = SpinLock::new(Tables { tables: [None; MAX_TABLES] });
+
+// Physical memory below the end of the boot mapping is already there
+unsafe fn low_memory<T>(paddr: u64) -> *const T {
+    (memory::HIGHER_HALF + paddr) as *const T
+}
+
+// The signature alone turns up in random data now and then, so only take it
+// with a matching checksum
+unsafe fn find_rsdp_in(range: core::ops::Range<u64>) -> Option<Rsdp> {
+    range.step_by(RSDP_ALIGN as usize)
+        .filter(|&addr| &*low_memory::<[u8; 8]>(addr) == RSDP_SIGNATURE)
+        .map(|addr| core::ptr::read_unaligned(low_memory::<Rsdp>(addr)))
+        .find(|rsdp| rsdp.is_valid())
+}
+
+#[derive(Debug, Clone, Copy)]
+enum RootTable {
+    Rsdt(PAddr),
+    Xsdt(PAddr),
+}
+
+impl Rsdp {
+    fn is_valid(&self) -> bool {
+        let bytes = unsafe {
+            core::slice::from_raw_parts(self as *const Self as *const u8, size_of::<Rsdp>())
+        };
+        match self.revision {
+            0 => checksum_ok(&bytes[..RSDP_V1_LENGTH]),
+            _ => checksum_ok(&bytes[..RSDP_V1_LENGTH]) && checksum_ok(bytes),
+        }
+    }
+
+    fn root_table(&self) -> RootTable {
+        match self.revision {
+            0 => RootTable::Rsdt(PAddr(self.rsdt_address as u64)),
+            _ => RootTable::Xsdt(PAddr(self.xsdt_address)),
+        }
+    }
+}
+
+// The bootloader passes the RSDP along if it has it, otherwise
+// look where the BIOS leaves it
+fn find_root_table() -> Option<RootTable> {
+    let info = memory::boot_info();
+    if let Some(tag) = info.rsdp_v2_tag().filter(|tag| tag.checksum_is_valid()) {
+        return Some(RootTable::Xsdt(PAddr(tag.xsdt_address() as u64)));
+    }
+    if let Some(tag) = info.rsdp_v1_tag().filter(|tag| tag.checksum_is_valid()) {
+        return Some(RootTable::Rsdt(PAddr(tag.rsdt_address() as u64)));
+    }
+    unsafe {
+        let ebda = (*low_memory::<u16>(EBDA_SEGMENT_PTR) as u64) << 4;
+        find_rsdp_in(ebda..ebda + EBDA_SEARCH_SIZE)
+            .or_else(|| find_rsdp_in(BIOS_AREA))
+            .map(|rsdp| rsdp.root_table())
+    }
+}
+
+// Map a whole table given its physical address. The length comes from
+// firmware before anything has been checked, so it's sanity checked first.
+fn map_table(paddr: PAddr) -> Result<&'static SdtHeader, AcpiError> {
+    let header = mmio::map_physical(paddr, size_of::<SdtHeader>(), PTFlags::empty())
+        .map_err(AcpiError::Map)?;
+    let header = unsafe { &*header.as_ptr::<SdtHeader>() };
+    let length = header.length as usize;
+    if !(size_of::<SdtHeader>()..=MAX_TABLE_LENGTH).contains(&length) {
+        return Err(AcpiError::BadLength(header.signature, header.length));
+    }
+    if length == size_of::<SdtHeader>() {
+        return Ok(header);
+    }
+    let table = mmio::map_physical(paddr, length, PTFlags::empty()).map_err(AcpiError::Map)?;
+    Ok(unsafe { &*table.as_ptr::<SdtHeader>() })
+}
+
+// Find the root table and map everything it lists, plus the DSDT the FADT
+// points at. Tables with a bad checksum are left out.
+pub fn init() -> Result<(), AcpiError> {
+    let (root, entry_size) = match find_root_table().ok_or(AcpiError::NoRsdp)? {
+        RootTable::Rsdt(paddr) => (map_table(paddr)?, size_of::<u32>()),
+        RootTable::Xsdt(paddr) => (map_table(paddr)?, size_of::<u64>()),
+    };
+    if !root.is_valid() {
+        return Err(AcpiError::BadChecksum(root.signature));
+    }
+    let mut tables = TABLES.lock();
+    let mut count = 0;
+    for entry in root.data().chunks_exact(entry_size) {
+        let paddr = match entry_size {
+            4 => u32::from_le_bytes(entry.try_into().unwrap()) as u64,
+            _ => u64::from_le_bytes(entry.try_into().unwrap()),
+        };
+        let table = match map_table(PAddr(paddr)) {
+            Ok(table) => table,
+            Err(AcpiError::BadLength(signature, length)) => {
+                warn!("ACPI table {} at {:#x} claims {} bytes, ignoring it",
+                    core::str::from_utf8(&signature).unwrap_or("?"), paddr, length);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        if !table.is_valid() {
+            warn!("ACPI table {} at {:#x} has a bad checksum, ignoring it", table.signature(), paddr);
+            continue;
+        }
+        if count == MAX_TABLES {
+            warn!("more than {} ACPI tables, ignoring the rest", MAX_TABLES);
+            break;
+        }
+        tables.tables[count] = Some(table);
+        count += 1;
+    }
+    drop(tables);
+
+    if let Some(paddr) = fadt::Fadt::find().map(|fadt| fadt.dsdt_address()).filter(|&paddr| paddr != 0) {
+        match map_table(PAddr(paddr)) {
+            Ok(dsdt) => {
+                let mut tables = TABLES.lock();
+                match tables.tables.iter_mut().find(|slot| slot.is_none()) {
+                    Some(slot) if dsdt.is_valid() => *slot = Some(dsdt),
+                    Some(_) => warn!("DSDT has a bad checksum, ignoring it"),
+                    None => warn!("no room for the DSDT"),
+                }
+            }
+            Err(AcpiError::BadLength(_, length)) => warn!("DSDT claims {} bytes, ignoring it", length),
+            Err(e) => return Err(e),
+        }
+    }
+
+    for table in TABLES.lock().tables.iter().flatten() {
+        let oem = core::str::from_utf8(&table.oem_id).unwrap_or("?");
+        debug!("ACPI {} rev {} {} ({} bytes)", table.signature(), table.revision, oem, { table.length });
+    }
+    Ok(())
+}
+
+// First table with the given signature, e.g. `b"APIC"` for the MADT
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static SdtHeader> {
+    TABLES.lock().tables.iter().flatten().find(|table| &table.signature == signature).copied()
+}
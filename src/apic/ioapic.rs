@@ -0,0 +1,134 @@
+use core::ptr::{read_volatile, write_volatile};
+use x86::bits64::paging::{PAddr, VAddr};
+use crate::memory::mmio;
+use crate::memory::vmm::MapError;
+
+// https://wiki.osdev.org/IOAPIC
+
+// index register, the selected register shows up in the window
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+const REGS_SIZE: usize = 0x20;
+
+const REG_ID: u32 = 0x00;
+const REG_VERSION: u32 = 0x01;
+// two 32-bit registers per pin
+const REG_REDIRECTION_TABLE: u32 = 0x10;
+
+const ENTRY_ACTIVE_LOW: u64 = 1 << 13;
+const ENTRY_LEVEL_TRIGGERED: u64 = 1 << 15;
+const ENTRY_MASKED: u64 = 1 << 16;
+const ENTRY_DEST_SHIFT: u64 = 56;
+
+// Fixed delivery to one local APIC in physical destination mode
+#[derive(Debug, Clone, Copy)]
+pub struct RedirectionEntry {
+    pub vector: u8,
+    pub active_low: bool,
+    pub level_triggered: bool,
+    pub masked: bool,
+    pub dest: u8,
+}
+
+impl RedirectionEntry {
+    fn bits(&self) -> u64 {
+        let mut bits = self.vector as u64 | (self.dest as u64) << ENTRY_DEST_SHIFT;
+        if self.active_low {
+            bits |= ENTRY_ACTIVE_LOW;
+        }
+        if self.level_triggered {
+            bits |= ENTRY_LEVEL_TRIGGERED;
+        }
+        if self.masked {
+            bits |= ENTRY_MASKED;
+        }
+        bits
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApic {
+    base: VAddr,
+    id: u8,
+    gsi_base: u32,
+    pins: u32,
+}
+
+impl IoApic {
+    pub fn new(id: u8, paddr: PAddr, gsi_base: u32) -> Result<Self, MapError> {
+        let base = mmio::map_mmio(paddr, REGS_SIZE)?;
+        let mut io = IoApic { base, id, gsi_base, pins: 0 };
+        io.pins = ((io.read(REG_VERSION) >> 16) & 0xFF) + 1;
+        Ok(io)
+    }
+
+    fn read(&self, reg: u32) -> u32 {
+        unsafe {
+            write_volatile((self.base.as_u64() + IOREGSEL) as *mut u32, reg);
+            read_volatile((self.base.as_u64() + IOWIN) as *const u32)
+        }
+    }
+
+    fn write(&self, reg: u32, value: u32) {
+        unsafe {
+            write_volatile((self.base.as_u64() + IOREGSEL) as *mut u32, reg);
+            write_volatile((self.base.as_u64() + IOWIN) as *mut u32, value);
+        }
+    }
+
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    pub fn hardware_id(&self) -> u8 {
+        ((self.read(REG_ID) >> 24) & 0xF) as u8
+    }
+
+    pub fn gsi_base(&self) -> u32 {
+        self.gsi_base
+    }
+
+    pub fn pins(&self) -> u32 {
+        self.pins
+    }
+
+    pub fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.pins
+    }
+
+    fn read_entry_bits(&self, pin: u8) -> u64 {
+        let reg = REG_REDIRECTION_TABLE + pin as u32 * 2;
+        self.read(reg) as u64 | (self.read(reg + 1) as u64) << 32
+    }
+
+    fn write_entry_bits(&self, pin: u8, bits: u64) {
+        let reg = REG_REDIRECTION_TABLE + pin as u32 * 2;
+        // mask while the entry is half written
+        self.write(reg, ENTRY_MASKED as u32);
+        self.write(reg + 1, (bits >> 32) as u32);
+        self.write(reg, bits as u32);
+    }
+
+    pub fn write_entry(&self, pin: u8, entry: RedirectionEntry) {
+        self.write_entry_bits(pin, entry.bits());
+    }
+
+    pub fn set_masked(&self, pin: u8, masked: bool) {
+        let reg = REG_REDIRECTION_TABLE + pin as u32 * 2;
+        let low = self.read(reg);
+        self.write(reg, match masked {
+            true => low | ENTRY_MASKED as u32,
+            false => low & !(ENTRY_MASKED as u32),
+        });
+    }
+
+    pub fn is_masked(&self, pin: u8) -> bool {
+        self.read_entry_bits(pin) & ENTRY_MASKED != 0
+    }
+
+    pub fn mask_all(&self) {
+        for pin in 0..self.pins as u8 {
+            self.write_entry_bits(pin, ENTRY_MASKED);
+        }
+    }
+}
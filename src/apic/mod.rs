@@ -0,0 +1,418 @@
+use core::ptr::{read_volatile, write_volatile};
+use x86::bits64::paging::{PAddr, VAddr, BASE_PAGE_SIZE};
+use x86::cpuid::CpuId;
+use x86::msr::{rdmsr, wrmsr, IA32_APIC_BASE};
+use crate::acpi::madt::{IntiFlags, Madt, MadtEntry, ALL_PROCESSORS, LOCAL_APIC_ENABLED};
+use crate::interrupts::{self, InterruptStackFrame};
+use crate::irqchip::{self, InterruptController, RouteError, ISA_IRQS, ISA_VECTOR_BASE};
+use crate::memory::mmio;
+use crate::memory::vmm::MapError;
+use crate::pic8259::{set_pic1_mask, set_pic2_mask};
+use crate::sync::SpinLock;
+use ioapic::{IoApic, RedirectionEntry};
+pub mod ioapic;
+
+// https://wiki.osdev.org/APIC
+// Intel SDM vol. 3A, chapter 10
+
+// register offsets in the xAPIC MMIO page; x2APIC has them as MSRs at
+// X2APIC_MSR_BASE + offset / 16
+pub const REG_ID: u32 = 0x020;
+pub const REG_VERSION: u32 = 0x030;
+pub const REG_TPR: u32 = 0x080;
+pub const REG_EOI: u32 = 0x0B0;
+pub const REG_SPURIOUS: u32 = 0x0F0;
+pub const REG_ERROR_STATUS: u32 = 0x280;
+pub const REG_LVT_TIMER: u32 = 0x320;
+pub const REG_LVT_THERMAL: u32 = 0x330;
+pub const REG_LVT_PERF: u32 = 0x340;
+pub const REG_LVT_LINT0: u32 = 0x350;
+pub const REG_LVT_LINT1: u32 = 0x360;
+pub const REG_LVT_ERROR: u32 = 0x370;
+pub const REG_TIMER_INITIAL: u32 = 0x380;
+pub const REG_TIMER_CURRENT: u32 = 0x390;
+pub const REG_TIMER_DIVIDE: u32 = 0x3E0;
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+const APIC_BASE_ADDR_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+const SPURIOUS_ENABLE: u32 = 1 << 8;
+pub const LVT_MASKED: u32 = 1 << 16;
+const LVT_DELIVERY_NMI: u32 = 0b100 << 8;
+const LVT_ACTIVE_LOW: u32 = 1 << 13;
+const LVT_LEVEL_TRIGGERED: u32 = 1 << 15;
+
+// low 4 bits must be set on old xAPICs, 0xFF works everywhere
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+const MAX_IO_APICS: usize = 8;
+// GSIs that can be handed out to devices outside the ISA range
+const MAX_GSIS: usize = 256;
+
+#[derive(Debug)]
+pub enum ApicError {
+    NotPresent,
+    NoMadt,
+    NoIoApic,
+    Map(MapError),
+}
+
+#[derive(Debug)]
+pub enum GsiError {
+    // no I/O APIC has a pin for it
+    NoSuchGsi(u32),
+    // an ISA IRQ or another device has it already
+    InUse(u32),
+    // redirection entries only have room for 8-bit APIC ids
+    DestinationTooHigh(u32),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum LocalApic {
+    XApic(VAddr),
+    X2Apic,
+}
+
+impl LocalApic {
+    pub fn read(&self, reg: u32) -> u32 {
+        unsafe {
+            match self {
+                LocalApic::XApic(base) => read_volatile((base.as_u64() + reg as u64) as *const u32),
+                LocalApic::X2Apic => rdmsr(X2APIC_MSR_BASE + (reg >> 4)) as u32,
+            }
+        }
+    }
+
+    pub fn write(&self, reg: u32, value: u32) {
+        unsafe {
+            match self {
+                LocalApic::XApic(base) => write_volatile((base.as_u64() + reg as u64) as *mut u32, value),
+                LocalApic::X2Apic => wrmsr(X2APIC_MSR_BASE + (reg >> 4), value as u64),
+            }
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        match self {
+            LocalApic::XApic(_) => self.read(REG_ID) >> 24,
+            LocalApic::X2Apic => self.read(REG_ID),
+        }
+    }
+
+    pub fn version(&self) -> u8 {
+        self.read(REG_VERSION) as u8
+    }
+
+    // number of LVT entries
+    pub fn max_lvt(&self) -> u8 {
+        ((self.read(REG_VERSION) >> 16) as u8) + 1
+    }
+
+    pub fn eoi(&self) {
+        self.write(REG_EOI, 0);
+    }
+
+    fn clear_errors(&self) {
+        // the register latches on write, so twice
+        self.write(REG_ERROR_STATUS, 0);
+        self.write(REG_ERROR_STATUS, 0);
+    }
+}
+
+static LOCAL_APIC: SpinLock<Option<LocalApic>> = SpinLock::new(None);
+
+// Local APIC of this CPU, once `init` has set it up
+pub fn local_apic() -> Option<LocalApic> {
+    *LOCAL_APIC.lock()
+}
+
+// This CPU's APIC id as a redirection entry destination, or the id if it
+// doesn't fit
+fn this_cpu() -> Result<u8, u32> {
+    let id = local_apic().map_or(0, |lapic| lapic.id());
+    u8::try_from(id).map_err(|_| id)
+}
+
+fn supported() -> (bool, bool) {
+    CpuId::new().get_feature_info().map_or((false, false), |info| (info.has_apic(), info.has_x2apic()))
+}
+
+// Switch the local APIC on, in x2APIC mode if the CPU has it
+fn init_local_apic(madt: &Madt) -> Result<LocalApic, ApicError> {
+    let (apic, x2apic) = supported();
+    if !apic {
+        return Err(ApicError::NotPresent);
+    }
+    let base = unsafe { rdmsr(IA32_APIC_BASE) };
+    let lapic = match x2apic {
+        true => {
+            unsafe { wrmsr(IA32_APIC_BASE, base | APIC_BASE_ENABLE | APIC_BASE_X2APIC); }
+            LocalApic::X2Apic
+        }
+        false => {
+            unsafe { wrmsr(IA32_APIC_BASE, base | APIC_BASE_ENABLE); }
+            let paddr = match madt.local_apic_address() {
+                0 => base & APIC_BASE_ADDR_MASK,
+                address => address,
+            };
+            let vaddr = mmio::map_mmio(PAddr(paddr), BASE_PAGE_SIZE).map_err(ApicError::Map)?;
+            LocalApic::XApic(vaddr)
+        }
+    };
+
+    // nothing comes in through the LVT until someone asks for it; LINT0 is
+    // where the 8259 is wired in virtual wire mode, which we're leaving
+    lapic.write(REG_LVT_TIMER, LVT_MASKED);
+    lapic.write(REG_LVT_LINT0, LVT_MASKED);
+    lapic.write(REG_LVT_LINT1, LVT_MASKED);
+    lapic.write(REG_LVT_ERROR, LVT_MASKED);
+    if lapic.max_lvt() > 4 {
+        lapic.write(REG_LVT_PERF, LVT_MASKED);
+    }
+    if lapic.max_lvt() > 5 {
+        lapic.write(REG_LVT_THERMAL, LVT_MASKED);
+    }
+    lapic.clear_errors();
+
+    // LINT pins the firmware says carry NMIs, for us or for everyone
+    let id = lapic.id();
+    let uid = madt.entries().find_map(|entry| match entry {
+        MadtEntry::LocalApic { processor_uid, apic_id, flags }
+            if apic_id == id && flags & LOCAL_APIC_ENABLED != 0 => Some(processor_uid),
+        _ => None,
+    });
+    for entry in madt.entries() {
+        if let MadtEntry::LocalApicNmi { processor_uid, lint, flags } = entry {
+            if processor_uid != ALL_PROCESSORS && Some(processor_uid) != uid {
+                continue;
+            }
+            let mut lvt = LVT_DELIVERY_NMI;
+            if flags.active_low {
+                lvt |= LVT_ACTIVE_LOW;
+            }
+            if flags.level_triggered {
+                lvt |= LVT_LEVEL_TRIGGERED;
+            }
+            lapic.write(if lint == 0 { REG_LVT_LINT0 } else { REG_LVT_LINT1 }, lvt);
+        }
+    }
+
+    lapic.write(REG_TPR, 0);
+    lapic.write(REG_SPURIOUS, SPURIOUS_ENABLE | SPURIOUS_VECTOR as u32);
+    Ok(lapic)
+}
+
+// Where an ISA IRQ ends up on the I/O APICs
+#[derive(Debug, Clone, Copy)]
+struct IsaRoute {
+    gsi: u32,
+    active_low: bool,
+    level_triggered: bool,
+}
+
+struct ApicState {
+    io_apics: [Option<IoApic>; MAX_IO_APICS],
+    // None for IRQs whose pin was taken over by another IRQ's override
+    isa: [Option<IsaRoute>; ISA_IRQS as usize],
+    // GSIs given to devices with `claim_gsi`
+    claimed: [u64; MAX_GSIS / 64],
+}
+
+impl ApicState {
+    fn isa_route(&self, irq: u8) -> Option<IsaRoute> {
+        self.isa.get(irq as usize).copied().flatten()
+    }
+
+    fn io_apic_for(&self, gsi: u32) -> Option<(&IoApic, u8)> {
+        self.io_apics.iter().flatten()
+            .find(|io| io.handles(gsi))
+            .map(|io| (io, (gsi - io.gsi_base()) as u8))
+    }
+
+    fn is_claimed(&self, gsi: u32) -> bool {
+        let gsi = gsi as usize;
+        gsi >= MAX_GSIS || self.claimed[gsi / 64] & 1 << (gsi % 64) != 0
+    }
+
+    fn set_claimed(&mut self, gsi: u32, claimed: bool) {
+        let gsi = gsi as usize;
+        match claimed {
+            true => self.claimed[gsi / 64] |= 1 << (gsi % 64),
+            false => self.claimed[gsi / 64] &= !(1 << (gsi % 64)),
+        }
+    }
+
+    fn gsi_free(&self, gsi: u32) -> bool {
+        self.io_apic_for(gsi).is_some()
+            && !self.is_claimed(gsi)
+            && !self.isa.iter().flatten().any(|route| route.gsi == gsi)
+    }
+}
+
+static STATE: SpinLock<ApicState> = SpinLock::new(ApicState {
+    io_apics: [None; MAX_IO_APICS],
+    isa: [None; ISA_IRQS as usize],
+    claimed: [0; MAX_GSIS / 64],
+});
+
+// Local APIC plus I/O APICs, handling ISA IRQs the way the MADT says they're wired
+pub struct Apic;
+
+static APIC: Apic = Apic;
+
+impl InterruptController for Apic {
+    fn name(&self) -> &'static str {
+        match local_apic() {
+            Some(LocalApic::X2Apic) => "x2APIC + I/O APIC",
+            _ => "xAPIC + I/O APIC",
+        }
+    }
+
+    fn mask(&self, irq: u8) {
+        let state = STATE.lock();
+        if let Some((io, pin)) = state.isa_route(irq).and_then(|route| state.io_apic_for(route.gsi)) {
+            io.set_masked(pin, true);
+        }
+    }
+
+    fn unmask(&self, irq: u8) {
+        let state = STATE.lock();
+        if let Some((io, pin)) = state.isa_route(irq).and_then(|route| state.io_apic_for(route.gsi)) {
+            io.set_masked(pin, false);
+        }
+    }
+
+    fn eoi(&self, _irq: u8) {
+        if let Some(lapic) = local_apic() {
+            lapic.eoi();
+        }
+    }
+
+    fn route(&self, irq: u8, vector: u8) -> Result<(), RouteError> {
+        let state = STATE.lock();
+        let route = state.isa_route(irq).ok_or(RouteError::NoSuchIrq(irq))?;
+        let (io, pin) = state.io_apic_for(route.gsi).ok_or(RouteError::NoSuchIrq(irq))?;
+        let dest = this_cpu().map_err(RouteError::DestinationTooHigh)?;
+        io.write_entry(pin, RedirectionEntry {
+            vector,
+            active_low: route.active_low,
+            level_triggered: route.level_triggered,
+            masked: true,
+            dest,
+        });
+        Ok(())
+    }
+}
+
+extern "x86-interrupt" fn spurious_handler(_frame: InterruptStackFrame) {
+    // no EOI for spurious interrupts
+}
+
+// Take over from the 8259: bring up the local APIC and the I/O APICs from
+// the MADT, route every ISA IRQ to its usual vector, masked, and mask the
+// 8259 for good. Drivers unmask their lines through `irqchip` as before.
+pub fn init() -> Result<(), ApicError> {
+    let madt = Madt::find().ok_or(ApicError::NoMadt)?;
+
+    // find the I/O APICs first, without them the 8259 has to stay, and it
+    // only works as long as the local APIC is left the way firmware set it up
+    let mut io_apics = [None; MAX_IO_APICS];
+    let found = madt.entries().filter_map(|entry| match entry {
+        MadtEntry::IoApic { id, address, gsi_base } => Some((id, address, gsi_base)),
+        _ => None,
+    });
+    for (slot, (id, address, gsi_base)) in io_apics.iter_mut().zip(found) {
+        *slot = Some(IoApic::new(id, PAddr(address as u64), gsi_base).map_err(ApicError::Map)?);
+    }
+    if io_apics[0].is_none() {
+        return Err(ApicError::NoIoApic);
+    }
+
+    let lapic = init_local_apic(&madt)?;
+    *LOCAL_APIC.lock() = Some(lapic);
+    let _ = interrupts::register_irq(SPURIOUS_VECTOR, spurious_handler);
+
+    let mut state = STATE.lock();
+    for io in io_apics.iter().flatten() {
+        io.mask_all();
+    }
+    state.io_apics = io_apics;
+
+    // ISA IRQs are identity mapped to GSIs, edge triggered and active high,
+    // unless overridden. The usual one is the PIT moving from IRQ0 to GSI2,
+    // and then nothing else can have GSI2.
+    let mut overridden = [false; ISA_IRQS as usize];
+    for entry in madt.entries() {
+        if let MadtEntry::InterruptSourceOverride { bus: 0, source, gsi, flags } = entry {
+            if let Some(route) = state.isa.get_mut(source as usize) {
+                *route = Some(IsaRoute { gsi, active_low: flags.active_low, level_triggered: flags.level_triggered });
+                overridden[source as usize] = true;
+            }
+        }
+    }
+    for irq in 0..ISA_IRQS as usize {
+        let gsi = irq as u32;
+        let taken = state.isa.iter().enumerate().any(|(other, route)| {
+            overridden[other] && other != irq && matches!(route, Some(route) if route.gsi == gsi)
+        });
+        if !overridden[irq] && !taken {
+            state.isa[irq] = Some(IsaRoute { gsi, active_low: false, level_triggered: false });
+        }
+    }
+    drop(state);
+
+    set_pic1_mask(0xFF);
+    set_pic2_mask(0xFF);
+    for irq in 0..ISA_IRQS {
+        let _ = APIC.route(irq, ISA_VECTOR_BASE + irq);
+    }
+    irqchip::set_active(&APIC);
+    Ok(())
+}
+
+// Whether a device that can pick its I/O APIC pin, like the HPET, may have `gsi`
+pub fn gsi_free(gsi: u32) -> bool {
+    STATE.lock().gsi_free(gsi)
+}
+
+// Deliver `gsi` on `vector` for a device that isn't on the ISA bus, left
+// masked. Handlers EOI through the local APIC.
+pub fn claim_gsi(gsi: u32, vector: u8, flags: IntiFlags) -> Result<(), GsiError> {
+    let mut state = STATE.lock();
+    if state.io_apic_for(gsi).is_none() {
+        return Err(GsiError::NoSuchGsi(gsi));
+    }
+    if !state.gsi_free(gsi) {
+        return Err(GsiError::InUse(gsi));
+    }
+    let dest = this_cpu().map_err(GsiError::DestinationTooHigh)?;
+    let (io, pin) = state.io_apic_for(gsi).unwrap();
+    io.write_entry(pin, RedirectionEntry {
+        vector,
+        active_low: flags.active_low,
+        level_triggered: flags.level_triggered,
+        masked: true,
+        dest,
+    });
+    state.set_claimed(gsi, true);
+    Ok(())
+}
+
+pub fn release_gsi(gsi: u32) {
+    let mut state = STATE.lock();
+    if let Some((io, pin)) = state.io_apic_for(gsi) {
+        io.set_masked(pin, true);
+    }
+    if (gsi as usize) < MAX_GSIS {
+        state.set_claimed(gsi, false);
+    }
+}
+
+pub fn set_gsi_masked(gsi: u32, masked: bool) {
+    let state = STATE.lock();
+    if let Some((io, pin)) = state.io_apic_for(gsi) {
+        io.set_masked(pin, masked);
+    }
+}
@@ -0,0 +1,159 @@
+use core::fmt::{self, Write};
+use crate::sync::SpinLock;
+use crate::vga_buffer::{VGAColor, VGAColorCode, VGAWriter};
+
+const MAX_SINKS: usize = 4;
+
+// Anything that can show console output: VGA text mode, a serial port,
+// a framebuffer. Writes are expected to be complete when this returns.
+pub trait ConsoleSink: Send {
+    fn write_str(&mut self, s: &str);
+
+    // `write_str` for exception and NMI context: drop the text rather than
+    // wait for a lock the interrupted code may be holding
+    fn try_write_str(&mut self, s: &str) {
+        self.write_str(s);
+    }
+
+    // switch to/from highlighting output as an error, if the sink can
+    fn set_error(&mut self, _error: bool) {}
+}
+
+impl ConsoleSink for VGAWriter {
+    fn write_str(&mut self, s: &str) {
+        self.print(s);
+    }
+
+    fn set_error(&mut self, error: bool) {
+        let color = match error {
+            true => VGAColorCode::new(VGAColor::LightRed, VGAColor::Black),
+            false => VGAColorCode::new(VGAColor::White, VGAColor::Black),
+        };
+        self.set_color(color);
+    }
+}
+
+// Kernel console. VGA text mode is always there from the start,
+// other sinks are added as their drivers come up.
+pub struct Console {
+    vga: VGAWriter,
+    sinks: [Option<&'static mut dyn ConsoleSink>; MAX_SINKS],
+}
+
+impl Console {
+    const fn new() -> Self {
+        Console {
+            vga: VGAWriter::new(0, 0),
+            sinks: [None, None, None, None],
+        }
+    }
+
+    fn for_each_sink<F: FnMut(&mut dyn ConsoleSink)>(&mut self, mut func: F) {
+        func(&mut self.vga);
+        for sink in self.sinks.iter_mut().flatten() {
+            func(&mut **sink);
+        }
+    }
+
+    pub fn set_error(&mut self, error: bool) {
+        self.for_each_sink(|sink| sink.set_error(error));
+    }
+
+    // VGA cursor position, (column, row)
+    pub fn cursor(&self) -> (usize, usize) {
+        self.vga.position()
+    }
+
+    pub fn set_cursor(&mut self, x: usize, y: usize) {
+        self.vga.set_position(x, y);
+    }
+}
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.for_each_sink(|sink| sink.write_str(s));
+        Ok(())
+    }
+}
+
+// Writes through `try_write_str`, see `_try_print`
+struct TryWriter<'a>(&'a mut Console);
+
+impl Write for TryWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.for_each_sink(|sink| sink.try_write_str(s));
+        Ok(())
+    }
+}
+
+pub static CONSOLE: SpinLock<Console> = SpinLock::new(Console::new());
+
+// Returns false if there is no free slot left
+pub fn register_sink(sink: &'static mut dyn ConsoleSink) -> bool {
+    let mut console = CONSOLE.lock();
+    match console.sinks.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => {
+            *slot = Some(sink);
+            true
+        }
+        None => false,
+    }
+}
+
+// Only for the panic path: whatever was printing is never going to finish
+pub unsafe fn force_unlock() {
+    CONSOLE.force_unlock();
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    let _ = CONSOLE.lock().write_fmt(args);
+}
+
+#[doc(hidden)]
+pub fn _eprint(args: fmt::Arguments) {
+    let mut console = CONSOLE.lock();
+    console.set_error(true);
+    let _ = console.write_fmt(args);
+    console.set_error(false);
+}
+
+// For exception and NMI handlers, which can hit while this CPU holds the
+// console or a sink's lock. Returns false if the line was dropped.
+#[doc(hidden)]
+pub fn _try_print(args: fmt::Arguments) -> bool {
+    match CONSOLE.try_lock() {
+        Some(mut console) => {
+            let _ = TryWriter(&mut console).write_fmt(args);
+            true
+        }
+        None => false,
+    }
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::console::_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::console::_print(format_args!("{}\n", format_args!($($arg)*))));
+}
+
+#[macro_export]
+macro_rules! eprint {
+    ($($arg:tt)*) => ($crate::console::_eprint(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! eprintln {
+    () => ($crate::eprint!("\n"));
+    ($($arg:tt)*) => ($crate::console::_eprint(format_args!("{}\n", format_args!($($arg)*))));
+}
+
+#[macro_export]
+macro_rules! try_println {
+    ($($arg:tt)*) => ($crate::console::_try_print(format_args!("{}\n", format_args!($($arg)*))));
+}
@@ -0,0 +1,98 @@
+use core::mem::size_of;
+use x86::bits64::task::TaskStateSegment;
+use x86::dtables::{self, DescriptorTablePointer};
+use x86::segmentation::{self, SegmentSelector};
+use x86::task;
+use x86::Ring;
+use crate::memory::stack::alloc_kernel_stack;
+
+// https://wiki.osdev.org/Global_Descriptor_Table
+
+const ACCESS_PRESENT: u8 = 1 << 7;
+const ACCESS_RING3: u8 = 3 << 5;
+// code or data, as opposed to system segments like TSS
+const ACCESS_SEGMENT: u8 = 1 << 4;
+const ACCESS_EXECUTABLE: u8 = 1 << 3;
+const ACCESS_READABLE_WRITABLE: u8 = 1 << 1;
+const ACCESS_TSS_AVAILABLE: u8 = 0x9;
+
+const FLAG_64BIT_MODE: u8 = 1 << 1;
+
+// user data goes before user code, that's the order `sysret` expects
+const KERNEL_CODE_INDEX: u16 = 1;
+const KERNEL_DATA_INDEX: u16 = 2;
+const USER_DATA_INDEX: u16 = 3;
+const USER_CODE_INDEX: u16 = 4;
+// TSS descriptor is 16 bytes and takes two slots
+const TSS_INDEX: u16 = 5;
+const GDT_ENTRIES: usize = 7;
+
+pub const KERNEL_CODE_SELECTOR: SegmentSelector = SegmentSelector::new(KERNEL_CODE_INDEX, Ring::Ring0);
+pub const KERNEL_DATA_SELECTOR: SegmentSelector = SegmentSelector::new(KERNEL_DATA_INDEX, Ring::Ring0);
+pub const USER_DATA_SELECTOR: SegmentSelector = SegmentSelector::new(USER_DATA_INDEX, Ring::Ring3);
+pub const USER_CODE_SELECTOR: SegmentSelector = SegmentSelector::new(USER_CODE_INDEX, Ring::Ring3);
+pub const TSS_SELECTOR: SegmentSelector = SegmentSelector::new(TSS_INDEX, Ring::Ring0);
+
+// Interrupt Stack Table slots, as written into IDT entries (0 means no IST)
+pub const DOUBLE_FAULT_IST: u8 = 1;
+pub const NMI_IST: u8 = 2;
+pub const MACHINE_CHECK_IST: u8 = 3;
+const IST_STACKS: [u8; 3] = [DOUBLE_FAULT_IST, NMI_IST, MACHINE_CHECK_IST];
+const IST_STACK_PAGES: usize = 4;
+
+// base and limit are ignored for code and data segments in long mode
+const fn segment_descriptor(access: u8, flags: u8) -> u64 {
+    ((flags as u64 & 0xF) << 52) | (((access | ACCESS_SEGMENT | ACCESS_PRESENT) as u64) << 40)
+}
+
+// 64-bit system descriptor, low and high halves
+fn tss_descriptor(base: u64, limit: u64) -> (u64, u64) {
+    let low = (limit & 0xFFFF)
+        | ((base & 0xFF_FFFF) << 16)
+        | (((ACCESS_PRESENT | ACCESS_TSS_AVAILABLE) as u64) << 40)
+        | (((limit >> 16) & 0xF) << 48)
+        | (((base >> 24) & 0xFF) << 56);
+    (low, base >> 32)
+}
+
+static mut GDT: [u64; GDT_ENTRIES] = [
+    0,
+    segment_descriptor(ACCESS_EXECUTABLE | ACCESS_READABLE_WRITABLE, FLAG_64BIT_MODE),
+    segment_descriptor(ACCESS_READABLE_WRITABLE, 0),
+    segment_descriptor(ACCESS_RING3 | ACCESS_READABLE_WRITABLE, 0),
+    segment_descriptor(ACCESS_RING3 | ACCESS_EXECUTABLE | ACCESS_READABLE_WRITABLE, FLAG_64BIT_MODE),
+    // TSS, filled in by `init`
+    0,
+    0,
+];
+
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
+// Replace the bootstrap GDT from boot.S, needs the PMM and vmm to be up
+// for IST stacks
+pub unsafe fn init() {
+    for ist in IST_STACKS {
+        let stack = alloc_kernel_stack(IST_STACK_PAGES).expect("gdt: can't allocate IST stack");
+        TSS.set_ist(ist as usize - 1, stack.as_u64());
+    }
+    // no I/O permission bitmap
+    TSS.iomap_base = size_of::<TaskStateSegment>() as u16;
+
+    let (low, high) = tss_descriptor(
+        core::ptr::addr_of!(TSS) as u64,
+        size_of::<TaskStateSegment>() as u64 - 1
+    );
+    GDT[TSS_INDEX as usize] = low;
+    GDT[TSS_INDEX as usize + 1] = high;
+
+    let gdt_ptr = DescriptorTablePointer {
+        limit: (GDT_ENTRIES * size_of::<u64>() - 1) as u16,
+        base: core::ptr::addr_of!(GDT) as *const u64,
+    };
+    dtables::lgdt(&gdt_ptr);
+    x86::bits64::segmentation::load_cs(KERNEL_CODE_SELECTOR);
+    segmentation::load_ss(KERNEL_DATA_SELECTOR);
+    segmentation::load_ds(KERNEL_DATA_SELECTOR);
+    segmentation::load_es(KERNEL_DATA_SELECTOR);
+    task::load_tr(TSS_SELECTOR);
+}
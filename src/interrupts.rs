@@ -1,7 +1,8 @@
 use core::fmt::Debug;
 use core::marker::PhantomData;
-use x86::io::{inb, outb};
+use x86::dtables::{self, DescriptorTablePointer};
 use x86::segmentation;
+use crate::sync::SpinLock;
 
 const GATE_TYPE_INTERRUPT: u8 = 0xE;
 const GATE_TYPE_TRAP: u8 = 0xF;
@@ -34,7 +35,7 @@ pub struct IDTEntryOptions {
 }
 
 impl IDTEntryOptions {
-    pub fn new(gt: InterruptGateType, present: bool) -> Self {
+    pub const fn new(gt: InterruptGateType, present: bool) -> Self {
         let mut opt = IDTEntryOptions {ist: 0, type_attr: 0};
         opt.set_gate_type(gt);
         opt.set_present(present);
@@ -42,14 +43,14 @@ impl IDTEntryOptions {
     }
 
     pub fn set_ist_offset(&mut self, offset: u8) {
-        self.ist |= offset & 0b_00000111;
+        self.ist = (self.ist & !0b_00000111) | (offset & 0b_00000111);
     }
 
     pub fn get_ist_offset(&self) -> u8 {
         self.ist & 0b_00000111
     }
 
-    pub fn set_present(&mut self, present: bool) {
+    pub const fn set_present(&mut self, present: bool) {
         if present {
             self.type_attr |= 0b_10000000;
         }else {
@@ -69,7 +70,7 @@ impl IDTEntryOptions {
         (self.type_attr & 0b_01100000) >> 5
     }
 
-    pub fn set_gate_type(&mut self, gt: InterruptGateType) {
+    pub const fn set_gate_type(&mut self, gt: InterruptGateType) {
         self.type_attr &= 0b_11110000;
         match gt {
             InterruptGateType::Interrupt => { self.type_attr |= GATE_TYPE_INTERRUPT }
@@ -100,7 +101,7 @@ pub struct IDTEntry<F> {
 }
 
 impl<F> IDTEntry<F> {
-    pub fn missing() -> Self {
+    pub const fn missing() -> Self {
         let options = IDTEntryOptions::new(InterruptGateType::Interrupt, false);
         IDTEntry {
             offset_1: 0,
@@ -120,6 +121,19 @@ impl<F> IDTEntry<F> {
         self.selector = segmentation::cs().bits();
         self.options.set_present(true);
     }
+
+    pub fn is_present(&self) -> bool {
+        self.options.get_present()
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::missing();
+    }
+
+    // switch to TSS Interrupt Stack Table entry `index` (1-7) on entry, 0 disables IST
+    pub fn set_stack_index(&mut self, index: u8) {
+        self.options.set_ist_offset(index);
+    }
 }
 
 impl IDTEntry<IntHandler> {
@@ -191,7 +205,7 @@ pub struct InterruptDescriptorTable {
 }
 
 impl InterruptDescriptorTable {
-    pub fn new() -> Self {
+    pub const fn new() -> Self {
         InterruptDescriptorTable {
             divide_by_zero: IDTEntry::missing(),
             debug: IDTEntry::missing(),
@@ -240,6 +254,75 @@ impl InterruptDescriptorTable {
     }
 }
 
+// The one IDT the CPU uses. It lives for the whole run of the kernel,
+// so handlers can be added and removed after `load_idt`.
+pub static IDT: SpinLock<InterruptDescriptorTable> = SpinLock::new(InterruptDescriptorTable::new());
+
+// first vector of the `interrupts` block, after exceptions and legacy PIC IRQs
+pub const IRQ_VECTOR_BASE: u8 = 48;
+
+#[derive(Debug)]
+pub enum IrqError {
+    VectorOutOfRange(u8),
+    VectorInUse(u8),
+    NotRegistered(u8),
+    NoFreeVector,
+}
+
+impl InterruptDescriptorTable {
+    // Point `vector` at code that isn't a Rust handler, e.g. an assembly entry stub
+    pub fn set_raw_handler(&mut self, vector: u8, address: u64) {
+        let entries = unsafe { &mut *(self as *mut Self as *mut [IDTEntry<IntHandler>; 256]) };
+        entries[vector as usize].set_handler_address(address);
+    }
+
+    fn irq_entry(&mut self, vector: u8) -> Result<&mut IDTEntry<IntHandler>, IrqError> {
+        match vector.checked_sub(IRQ_VECTOR_BASE) {
+            Some(index) => Ok(&mut self.interrupts[index as usize]),
+            None => Err(IrqError::VectorOutOfRange(vector)),
+        }
+    }
+}
+
+pub fn load_idt() {
+    let idt = IDT.lock();
+    let idt_ptr = DescriptorTablePointer {
+        limit: (core::mem::size_of::<InterruptDescriptorTable>() - 1) as u16,
+        base: &*idt as *const InterruptDescriptorTable,
+    };
+    unsafe { dtables::lidt(&idt_ptr); }
+}
+
+// Install `handler` for a vector of the `interrupts` block,
+// refuses to replace a handler that is already there
+pub fn register_irq(vector: u8, handler: IntHandler) -> Result<(), IrqError> {
+    let mut idt = IDT.lock();
+    let entry = idt.irq_entry(vector)?;
+    if entry.is_present() {
+        return Err(IrqError::VectorInUse(vector));
+    }
+    entry.set_handler(handler);
+    Ok(())
+}
+
+// Install `handler` on the first free vector of the `interrupts` block
+pub fn allocate_irq(handler: IntHandler) -> Result<u8, IrqError> {
+    let mut idt = IDT.lock();
+    let index = idt.interrupts.iter().position(|entry| !entry.is_present()).ok_or(IrqError::NoFreeVector)?;
+    idt.interrupts[index].set_handler(handler);
+    Ok(IRQ_VECTOR_BASE + index as u8)
+}
+
+pub fn unregister_irq(vector: u8) -> Result<(), IrqError> {
+    let mut idt = IDT.lock();
+    let entry = idt.irq_entry(vector)?;
+    if !entry.is_present() {
+        return Err(IrqError::NotRegistered(vector));
+    }
+    entry.clear();
+    Ok(())
+}
+
 #[derive(Debug)]
 pub struct PageFaultInfo {
     present: bool,
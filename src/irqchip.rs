@@ -0,0 +1,56 @@
+use crate::pic8259::Pic8259;
+use crate::sync::SpinLock;
+
+// ISA IRQ n is delivered on vector ISA_VECTOR_BASE + n whichever controller
+// is active, matching the named IRQ entries of the IDT
+pub const ISA_VECTOR_BASE: u8 = 32;
+pub const ISA_IRQS: u8 = 16;
+
+#[derive(Debug)]
+pub enum RouteError {
+    NoSuchIrq(u8),
+    // the controller can't deliver this IRQ anywhere but its own vector
+    FixedVector { irq: u8, vector: u8 },
+    // the APIC id of the target CPU doesn't fit the controller's routing
+    DestinationTooHigh(u32),
+}
+
+// Whatever turns device IRQ lines into interrupt vectors. IRQ numbers are ISA
+// numbering, the controller takes care of any remapping it does internally.
+pub trait InterruptController: Sync {
+    fn name(&self) -> &'static str;
+    fn mask(&self, irq: u8);
+    fn unmask(&self, irq: u8);
+    fn eoi(&self, irq: u8);
+    // deliver `irq` on IDT `vector`, left masked
+    fn route(&self, irq: u8, vector: u8) -> Result<(), RouteError>;
+}
+
+static PIC: Pic8259 = Pic8259;
+static ACTIVE: SpinLock<&'static dyn InterruptController> = SpinLock::new(&PIC);
+
+// Hand all IRQ lines over to `controller`, the old one is expected to be shut up already
+pub fn set_active(controller: &'static dyn InterruptController) {
+    *ACTIVE.lock() = controller;
+}
+
+pub fn active_name() -> &'static str {
+    ACTIVE.lock().name()
+}
+
+pub fn mask(irq: u8) {
+    ACTIVE.lock().mask(irq);
+}
+
+pub fn unmask(irq: u8) {
+    ACTIVE.lock().unmask(irq);
+}
+
+// For interrupt handlers, once they're done with the device
+pub fn eoi(irq: u8) {
+    ACTIVE.lock().eoi(irq);
+}
+
+pub fn route(irq: u8, vector: u8) -> Result<(), RouteError> {
+    ACTIVE.lock().route(irq, vector)
+}
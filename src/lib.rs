@@ -8,59 +8,57 @@
 
 extern crate alloc;
 
+#[macro_use]
+mod console;
+#[macro_use]
+mod log;
 mod vga_buffer;
 mod interrupts;
+mod gdt;
 mod memory;
 mod pic8259;
+mod sync;
+mod trap;
+mod mce;
+mod serial;
+mod ps2;
+mod irqchip;
+mod acpi;
+mod apic;
+mod time;
+mod power;
+mod pci;
+mod virtio;
 
 use alloc::format;
-use core::fmt::Write;
 use core::panic::PanicInfo;
 use x86::halt;
 use x86::irq;
-use x86::io::{inb, outb};
-use x86::dtables;
-use crate::interrupts::{InterruptDescriptorTable, InterruptStackFrame, PageFaultInfo};
+use x86::io::outb;
+use crate::interrupts::IDT;
 use multiboot2;
 use memory::BootInfo;
-use pic8259::{pic1_end_of_intr, remap_pic, set_pic1_mask, set_pic2_mask};
-use crate::vga_buffer::VGAWriter;
+use pic8259::{remap_pic, set_pic1_mask, set_pic2_mask};
+
+const PANIC_LOG_RECORDS: usize = 16;
+// same 256 KiB the boot stack had
+const KERNEL_STACK_PAGES: usize = 64;
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     unsafe {
         irq::disable();
-        let mut writer = VGAWriter::new(0, 20);
-        writer.write_fmt(format_args!("{}", info)).unwrap();
+        // whoever held the console or the port isn't coming back
+        console::force_unlock();
+        serial::COM1.force_unlock();
+        eprintln!("{}", info);
+        println!("last log records:");
+        log::dump_recent(PANIC_LOG_RECORDS);
+        power::panic_exit();
         loop { halt(); }
     }
 }
 
-extern "x86-interrupt" fn kb_handler(_frame: InterruptStackFrame) {
-    let scan_code = unsafe {inb(0x60)};
-    let mut writer = VGAWriter::new(0, 23);
-    writer.write_fmt(format_args!("kb scan code: {:#02X}", scan_code));
-    pic1_end_of_intr();
-}
-
-extern "x86-interrupt" fn double_fault(frame: InterruptStackFrame, _err_code: u64) -> ! {
-    panic!("double fault: {:?}", frame);
-}
-
-extern "x86-interrupt" fn gp_fault(frame: InterruptStackFrame, _err_code: u64) {
-    panic!("gp fault: {:?}", frame);
-}
-
-extern "x86-interrupt" fn invalid_opcode_fault(frame: InterruptStackFrame) {
-    panic!("invalid opcode: {:?}", frame);
-}
-
-extern "x86-interrupt" fn page_fault(frame: InterruptStackFrame, err_code: u64) {
-    let info = PageFaultInfo::from_err_code(err_code);
-    let pf_addr = unsafe { x86::controlregs::cr2() } as *const ();
-    panic!("page fault at {:p}, accessing {:p}: {:?}", frame.rip, pf_addr, info);
-}
-
 // Memory layout:
 // 0x200000 - 0x201000          MB2 header and bootstrap assembly from boot.S
 // 0x201000 - 0x201800          GDT
@@ -89,26 +87,68 @@ pub unsafe extern "C" fn kmain(info: *mut BootInfo) -> ! {
     // so, do it early
     // NB: don't touch `info` after this function, it wont work
     memory::init_memory(info);
+    // the boot stack in .bss has nothing below it to fault on, move to one
+    // with a guard page before going any deeper
+    let stack = memory::stack::alloc_kernel_stack(KERNEL_STACK_PAGES).expect("can't allocate kernel stack");
+    memory::stack::switch_to(stack, kinit)
+}
+
+unsafe extern "C" fn kinit() -> ! {
+    let boot_info = memory::boot_info();
+    let cmdline = boot_info.command_line_tag()
+        .and_then(|tag| tag.command_line().ok())
+        .unwrap_or("");
+    log::init(cmdline);
+    power::init(cmdline);
+    serial::init(115200);
+    gdt::init();
 
     remap_pic();
-    set_pic1_mask(0b_1111_1101);
+    // drivers unmask their lines as they come up
+    set_pic1_mask(0b_1111_1111);
     set_pic2_mask(0b_1111_1111);
-    let mut idt = InterruptDescriptorTable::new();
-    idt.keyboard.set_handler(kb_handler);
-    idt.double_fault.set_handler(double_fault);
-    idt.general_protection_fault.set_handler(gp_fault);
-    idt.invalid_opcode.set_handler(invalid_opcode_fault);
-    idt.page_fault.set_handler(page_fault);
-    let idt_ptr = dtables::DescriptorTablePointer{ limit: 256 * 16 - 1, base: &idt };
-    dtables::lidt(&idt_ptr);
+    let mut idt = IDT.lock();
+    trap::install_exception_stubs(&mut idt);
+    idt.double_fault.set_stack_index(gdt::DOUBLE_FAULT_IST);
+    idt.non_maskable_interrupt.set_stack_index(gdt::NMI_IST);
+    idt.machine_check.set_stack_index(gdt::MACHINE_CHECK_IST);
+    drop(idt);
+    // patched in place from here on, load it before anything can fault
+    interrupts::load_idt();
+    match acpi::init() {
+        Ok(()) => {
+            if let Some(madt) = acpi::madt::Madt::find() {
+                info!("ACPI: {} CPUs", madt.processors().count());
+            }
+        }
+        Err(e) => warn!("no ACPI tables: {:?}", e),
+    }
+    if let Err(e) = apic::init() {
+        warn!("APIC unusable, staying with the 8259: {:?}", e);
+    }
+    info!("interrupt controller: {}", irqchip::active_name());
+    time::init(time::DEFAULT_TICK_HZ);
+    serial::enable_irqs();
+    match ps2::keyboard::init() {
+        Ok(set) => info!("PS/2 keyboard, scancode {:?}", set),
+        Err(e) => warn!("no PS/2 keyboard: {:?}", e),
+    }
+    match ps2::mouse::init() {
+        Ok(kind) => info!("PS/2 mouse, {:?}", kind),
+        Err(e) => warn!("no PS/2 mouse: {:?}", e),
+    }
+    pci::init();
+    virtio::init();
+    mce::init();
     irq::enable();
 
     // page fault
     // *(0xdeadbeef as *mut u64) = 0;
 
     // panic!("kmain: end of function");
-    let mut writer = VGAWriter::new(0, 24);
-    writer.print("kmain: halt");
+    power::finish();
+    info!("halt");
+    // with the tick stopped this sleeps until the next timer or device interrupt
     loop {
         halt();
     }
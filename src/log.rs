@@ -0,0 +1,320 @@
+use core::cell::UnsafeCell;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
+
+// Kernel log. Records that pass their module's level go to the console and
+// into a lock-free ring of the most recent ones, which the panic handler
+// dumps before halting.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+impl Level {
+    fn from_u8(level: u8) -> Self {
+        match level {
+            1 => Level::Error,
+            2 => Level::Warn,
+            3 => Level::Info,
+            4 => Level::Debug,
+            _ => Level::Trace,
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            "trace" => Some(Level::Trace),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+const MAX_FILTERS: usize = 16;
+const MAX_FILTER_NAME: usize = 32;
+const RING_SIZE: usize = 64;
+const MESSAGE_SIZE: usize = 112;
+
+// Level for one module and everything below it, `memory` also covers `memory::heap`
+#[derive(Clone, Copy)]
+struct ModuleFilter {
+    name: [u8; MAX_FILTER_NAME],
+    len: usize,
+    level: Level,
+}
+
+impl ModuleFilter {
+    fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.len]).unwrap_or("")
+    }
+
+    fn matches(&self, module: &str) -> bool {
+        let name = self.name();
+        module.starts_with(name) && (module.len() == name.len() || module[name.len()..].starts_with("::"))
+    }
+}
+
+const NO_FILTER: ModuleFilter = ModuleFilter { name: [0; MAX_FILTER_NAME], len: 0, level: Level::Info };
+
+static DEFAULT_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+// filters are only written by `init`, before anyone else is around to log
+static mut FILTERS: [ModuleFilter; MAX_FILTERS] = [NO_FILTER; MAX_FILTERS];
+static FILTER_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+// module_path!() without the crate name
+fn short_module(path: &'static str) -> &'static str {
+    path.split_once("::").map_or("", |(_, rest)| rest)
+}
+
+pub fn level_for(module: &str) -> Level {
+    let count = FILTER_COUNT.load(Ordering::Acquire);
+    let filters = unsafe { &FILTERS[..count] };
+    filters.iter()
+        .filter(|filter| filter.matches(module))
+        .max_by_key(|filter| filter.len)
+        .map_or(Level::from_u8(DEFAULT_LEVEL.load(Ordering::Relaxed)), |filter| filter.level)
+}
+
+pub fn enabled(level: Level, module_path: &'static str) -> bool {
+    level <= level_for(short_module(module_path))
+}
+
+// Take the `log=` option from the kernel command line, e.g.
+// `log=debug` or `log=memory:debug,interrupts:warn,info`
+pub fn init(cmdline: &str) {
+    let spec = match cmdline.split_whitespace().find_map(|opt| opt.strip_prefix("log=")) {
+        Some(spec) => spec,
+        None => return,
+    };
+    let mut count = 0;
+    for item in spec.split(',') {
+        match item.split_once(':') {
+            None => match Level::parse(item) {
+                Some(level) => DEFAULT_LEVEL.store(level as u8, Ordering::Relaxed),
+                None => crate::warn!("unknown log level '{}'", item),
+            },
+            Some((module, level)) => {
+                let level = match Level::parse(level) {
+                    Some(level) => level,
+                    None => {
+                        crate::warn!("unknown log level '{}' for {}", level, module);
+                        continue;
+                    }
+                };
+                if count == MAX_FILTERS || module.len() > MAX_FILTER_NAME {
+                    crate::warn!("ignoring log filter for {}", module);
+                    continue;
+                }
+                let mut filter = ModuleFilter { len: module.len(), level, ..NO_FILTER };
+                filter.name[..module.len()].copy_from_slice(module.as_bytes());
+                unsafe { FILTERS[count] = filter; }
+                count += 1;
+            }
+        }
+    }
+    FILTER_COUNT.store(count, Ordering::Release);
+}
+
+// nanoseconds since the Unix epoch once the RTC has been read, since boot
+// before that, 0 until the clock is running
+fn timestamp() -> u64 {
+    match crate::time::rtc::unix_nanos() {
+        0 => crate::time::clocksource::now_nanos(),
+        unix => unix,
+    }
+}
+
+// initial local APIC id of the CPU we're running on
+fn cpu_id() -> u32 {
+    x86::cpuid::cpuid!(1).ebx >> 24
+}
+
+#[derive(Clone, Copy)]
+struct Record {
+    level: Level,
+    timestamp: u64,
+    cpu: u32,
+    module: &'static str,
+    len: usize,
+    message: [u8; MESSAGE_SIZE],
+}
+
+impl Record {
+    fn message(&self) -> &str {
+        // truncation may have split a character
+        match core::str::from_utf8(&self.message[..self.len]) {
+            Ok(s) => s,
+            Err(e) => unsafe { core::str::from_utf8_unchecked(&self.message[..e.valid_up_to()]) },
+        }
+    }
+}
+
+impl fmt::Write for Record {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(MESSAGE_SIZE - self.len);
+        self.message[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+struct Header<'a>(&'a Record);
+
+impl<'a> fmt::Display for Header<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let r = self.0;
+        let secs = r.timestamp / crate::time::NANOS_PER_SEC;
+        let micros = r.timestamp % crate::time::NANOS_PER_SEC / 1000;
+        write!(f, "[{:>5}.{:06} cpu{} {:<5} {}] ", secs, micros, r.cpu, r.level.name(), r.module)
+    }
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", Header(self), self.message())
+    }
+}
+
+// `seq` is 0 while the record is being written, the claimed position + 1 once it's done
+struct Slot {
+    seq: AtomicU64,
+    record: UnsafeCell<Record>,
+}
+
+// only used to initialize `RING`
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_SLOT: Slot = Slot {
+    seq: AtomicU64::new(0),
+    record: UnsafeCell::new(Record {
+        level: Level::Info,
+        timestamp: 0,
+        cpu: 0,
+        module: "",
+        len: 0,
+        message: [0; MESSAGE_SIZE],
+    }),
+};
+
+// Writers claim a position with one atomic add and never wait on each other,
+// so logging works from any context, including while the kernel is panicking.
+// A reader that races a writer on the same slot sees the sequence change and
+// skips the record.
+struct Ring {
+    head: AtomicU64,
+    slots: [Slot; RING_SIZE],
+}
+
+unsafe impl Sync for Ring {}
+
+static RING: Ring = Ring {
+    head: AtomicU64::new(0),
+    slots: [EMPTY_SLOT; RING_SIZE],
+};
+
+impl Ring {
+    fn push(&self, record: &Record) {
+        let pos = self.head.fetch_add(1, Ordering::Relaxed);
+        let slot = &self.slots[pos as usize % RING_SIZE];
+        slot.seq.store(0, Ordering::Relaxed);
+        core::sync::atomic::fence(Ordering::Release);
+        unsafe { core::ptr::write_volatile(slot.record.get(), *record); }
+        slot.seq.store(pos + 1, Ordering::Release);
+    }
+
+    fn get(&self, pos: u64) -> Option<Record> {
+        let slot = &self.slots[pos as usize % RING_SIZE];
+        if slot.seq.load(Ordering::Acquire) != pos + 1 {
+            return None;
+        }
+        let record = unsafe { core::ptr::read_volatile(slot.record.get()) };
+        core::sync::atomic::fence(Ordering::Acquire);
+        match slot.seq.load(Ordering::Relaxed) == pos + 1 {
+            true => Some(record),
+            false => None,
+        }
+    }
+}
+
+#[doc(hidden)]
+pub fn _log(level: Level, module_path: &'static str, args: fmt::Arguments) {
+    let mut record = Record {
+        level,
+        timestamp: timestamp(),
+        cpu: cpu_id(),
+        module: short_module(module_path),
+        len: 0,
+        message: [0; MESSAGE_SIZE],
+    };
+    let _ = record.write_fmt(args);
+    RING.push(&record);
+    // the console gets the whole message, the ring may have cut it short
+    match level {
+        Level::Error => eprintln!("{}{}", Header(&record), args),
+        _ => println!("{}{}", Header(&record), args),
+    }
+}
+
+// Print up to `count` of the most recent records, oldest first
+pub fn dump_recent(count: usize) {
+    let head = RING.head.load(Ordering::Acquire);
+    let count = count.min(RING_SIZE) as u64;
+    for pos in head.saturating_sub(count)..head {
+        if let Some(record) = RING.get(pos) {
+            println!("{}", record);
+        }
+    }
+}
+
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => {{
+        let level = $level;
+        if $crate::log::enabled(level, module_path!()) {
+            $crate::log::_log(level, module_path!(), format_args!($($arg)*));
+        }
+    }};
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Error, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Warn, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Info, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Debug, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Trace, $($arg)*));
+}
@@ -0,0 +1,189 @@
+use core::fmt;
+use x86::controlregs::{self, Cr4};
+use x86::cpuid::CpuId;
+use x86::msr::{rdmsr, wrmsr, IA32_MCG_CAP, IA32_MCG_STATUS};
+
+// https://wiki.osdev.org/Machine_Check_Exception
+// Intel SDM vol. 3B, chapter 15
+
+// bank i registers are at IA32_MC0_CTL + 4 * i
+const IA32_MC0_CTL: u32 = 0x400;
+const MC_STATUS: u32 = 1;
+const MC_ADDR: u32 = 2;
+const MC_MISC: u32 = 3;
+
+const MCG_CAP_COUNT_MASK: u64 = 0xFF;
+const MCG_STATUS_RIPV: u64 = 1 << 0;
+const MCG_STATUS_EIPV: u64 = 1 << 1;
+const MCG_STATUS_MCIP: u64 = 1 << 2;
+
+const MCI_STATUS_VAL: u64 = 1 << 63;
+const MCI_STATUS_OVER: u64 = 1 << 62;
+const MCI_STATUS_UC: u64 = 1 << 61;
+const MCI_STATUS_EN: u64 = 1 << 60;
+const MCI_STATUS_MISCV: u64 = 1 << 59;
+const MCI_STATUS_ADDRV: u64 = 1 << 58;
+const MCI_STATUS_PCC: u64 = 1 << 57;
+
+fn bank_msr(bank: u32, reg: u32) -> u32 {
+    IA32_MC0_CTL + 4 * bank + reg
+}
+
+pub fn supported() -> bool {
+    CpuId::new().get_feature_info().map_or(false, |info| info.has_mca() && info.has_mce())
+}
+
+pub fn bank_count() -> u32 {
+    unsafe { (rdmsr(IA32_MCG_CAP) & MCG_CAP_COUNT_MASK) as u32 }
+}
+
+// Drop whatever the banks kept from before the reset and turn on #MC delivery
+pub fn init() {
+    if !supported() {
+        return;
+    }
+    MachineCheckReport::read().clear();
+    unsafe {
+        controlregs::cr4_write(controlregs::cr4() | Cr4::CR4_ENABLE_MACHINE_CHECK);
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BankStatus {
+    pub bank: u32,
+    pub status: u64,
+    pub addr: Option<u64>,
+    pub misc: Option<u64>,
+}
+
+impl BankStatus {
+    pub fn read(bank: u32) -> Option<Self> {
+        let status = unsafe { rdmsr(bank_msr(bank, MC_STATUS)) };
+        if status & MCI_STATUS_VAL == 0 {
+            return None;
+        }
+        Some(BankStatus {
+            bank,
+            status,
+            addr: match status & MCI_STATUS_ADDRV != 0 {
+                true => Some(unsafe { rdmsr(bank_msr(bank, MC_ADDR)) }),
+                false => None,
+            },
+            misc: match status & MCI_STATUS_MISCV != 0 {
+                true => Some(unsafe { rdmsr(bank_msr(bank, MC_MISC)) }),
+                false => None,
+            },
+        })
+    }
+
+    pub fn uncorrected(&self) -> bool {
+        self.status & MCI_STATUS_UC != 0
+    }
+
+    pub fn context_corrupt(&self) -> bool {
+        self.status & MCI_STATUS_PCC != 0
+    }
+
+    pub fn mca_error_code(&self) -> u16 {
+        self.status as u16
+    }
+
+    pub fn model_error_code(&self) -> u16 {
+        (self.status >> 16) as u16
+    }
+
+    // Simple and compound MCA error code classes, SDM table 15-8 and 15-9
+    pub fn error_class(&self) -> &'static str {
+        let code = self.mca_error_code();
+        match code {
+            0x0000 => "no error",
+            0x0001 => "unclassified",
+            0x0002 => "microcode ROM parity error",
+            0x0003 => "external error",
+            0x0004 => "FRC error",
+            0x0005 => "internal parity error",
+            0x0006 => "SMM handler code access violation",
+            0x0400..=0x07FF => "internal timer error",
+            _ if code & 0xEF80 == 0x0080 => "memory controller error",
+            _ if code & 0xEFF0 == 0x0010 => "TLB error",
+            _ if code & 0xEF00 == 0x0100 => "memory hierarchy error",
+            _ if code & 0xE800 == 0x0800 => "bus and interconnect error",
+            _ if code & 0xFF00 == 0x0C00 => "extended error",
+            _ => "unknown",
+        }
+    }
+
+    pub fn clear(&self) {
+        unsafe { wrmsr(bank_msr(self.bank, MC_STATUS), 0); }
+    }
+}
+
+impl fmt::Display for BankStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "bank {}: {} (mca {:#06x}, model {:#06x}) status {:#018x}",
+               self.bank, self.error_class(), self.mca_error_code(), self.model_error_code(), self.status)?;
+        for (bit, name) in [(MCI_STATUS_OVER, "overflow"), (MCI_STATUS_UC, "uncorrected"),
+                            (MCI_STATUS_EN, "enabled"), (MCI_STATUS_PCC, "context corrupt")] {
+            if self.status & bit != 0 {
+                write!(f, " {}", name)?;
+            }
+        }
+        if let Some(addr) = self.addr {
+            write!(f, " addr {:#x}", addr)?;
+        }
+        if let Some(misc) = self.misc {
+            write!(f, " misc {:#x}", misc)?;
+        }
+        Ok(())
+    }
+}
+
+// Global machine check state plus every bank holding a logged error
+pub struct MachineCheckReport {
+    pub mcg_status: u64,
+    pub banks: u32,
+}
+
+impl MachineCheckReport {
+    pub fn read() -> Self {
+        MachineCheckReport {
+            mcg_status: unsafe { rdmsr(IA32_MCG_STATUS) },
+            banks: bank_count(),
+        }
+    }
+
+    pub fn valid_banks(&self) -> impl Iterator<Item = BankStatus> {
+        (0..self.banks).filter_map(BankStatus::read)
+    }
+
+    // Restarting at the saved rip is fine and no bank reports lost state
+    pub fn recoverable(&self) -> bool {
+        self.mcg_status & MCG_STATUS_RIPV != 0
+            && self.valid_banks().all(|bank| !bank.uncorrected() && !bank.context_corrupt())
+    }
+
+    // Acknowledge the errors so the next machine check doesn't shut the CPU down
+    pub fn clear(&self) {
+        for bank in self.valid_banks() {
+            bank.clear();
+        }
+        // only 0 is architecturally safe to write here, anything else may #GP
+        unsafe { wrmsr(IA32_MCG_STATUS, 0); }
+    }
+}
+
+impl fmt::Display for MachineCheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "machine check: mcg_status {:#x}", self.mcg_status)?;
+        if self.mcg_status & MCG_STATUS_RIPV != 0 {
+            write!(f, " ripv")?;
+        }
+        if self.mcg_status & MCG_STATUS_EIPV != 0 {
+            write!(f, " eipv")?;
+        }
+        for bank in self.valid_banks() {
+            write!(f, "\n{}", bank)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,278 @@
+use core::mem::{size_of, size_of_val};
+use core::ops::Range;
+use x86::bits64::paging::{PAddr, BASE_PAGE_SIZE};
+use crate::sync::SpinLock;
+use super::pmm::{self, LOW_ZONE_END};
+
+pub const MAX_ORDER: usize = 10;
+const ORDERS: usize = MAX_ORDER + 1;
+const PAGE_SIZE: u64 = BASE_PAGE_SIZE as u64;
+const BITS: usize = u64::BITS as usize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Zone {
+    // below 16 MiB, reachable by legacy ISA DMA
+    Dma,
+    Normal,
+}
+
+impl Zone {
+    fn range(self) -> Range<u64> {
+        match self {
+            Zone::Dma => 0..LOW_ZONE_END,
+            Zone::Normal => LOW_ZONE_END..u64::MAX,
+        }
+    }
+
+    fn of(addr: PAddr) -> Zone {
+        match addr.as_u64() < LOW_ZONE_END {
+            true => Zone::Dma,
+            false => Zone::Normal,
+        }
+    }
+}
+
+const fn block_size(order: usize) -> u64 {
+    PAGE_SIZE << order
+}
+
+const fn map_words(end: u64, order: usize) -> usize {
+    let blocks = end.div_ceil(block_size(order)) as usize;
+    blocks.div_ceil(BITS)
+}
+
+// Number of bytes of free map needed for physical memory up to `end`
+pub fn free_map_size(end: u64) -> usize {
+    (0..ORDERS).map(|order| map_words(end, order)).sum::<usize>() * size_of::<u64>()
+}
+
+// One bit per naturally aligned block of every order, set while the block
+// sits free in a zone. It lives in physical memory right behind the PMM
+// bitmap, so neither allocating nor freeing ever needs the heap.
+struct FreeMap {
+    end: u64,
+    words: &'static mut [u64],
+    // first word of each order's bitmap
+    offsets: [usize; ORDERS],
+}
+
+impl FreeMap {
+    fn new(end: u64, words: &'static mut [u64]) -> Self {
+        assert!(size_of_val(words) >= free_map_size(end));
+        words.fill(0);
+        let mut offsets = [0; ORDERS];
+        for order in 1..ORDERS {
+            offsets[order] = offsets[order - 1] + map_words(end, order - 1);
+        }
+        FreeMap { end, words, offsets }
+    }
+
+    fn bit(&self, order: usize, block: u64) -> (usize, u64) {
+        let index = (block / block_size(order)) as usize;
+        (self.offsets[order] + index / BITS, 1 << (index % BITS))
+    }
+
+    fn is_free(&self, order: usize, block: u64) -> bool {
+        if block >= self.end {
+            return false;
+        }
+        let (word, mask) = self.bit(order, block);
+        self.words[word] & mask != 0
+    }
+
+    fn set_free(&mut self, order: usize, block: u64) {
+        let (word, mask) = self.bit(order, block);
+        self.words[word] |= mask;
+    }
+
+    fn set_used(&mut self, order: usize, block: u64) {
+        let (word, mask) = self.bit(order, block);
+        self.words[word] &= !mask;
+    }
+
+    // Lowest free block of `order` inside `range`
+    fn find(&self, order: usize, range: &Range<u64>) -> Option<u64> {
+        let first = range.start.div_ceil(block_size(order)) as usize;
+        let last = (range.end.min(self.end) / block_size(order)) as usize;
+        let mut index = first;
+        while index < last {
+            let word = self.words[self.offsets[order] + index / BITS] >> (index % BITS);
+            if word == 0 {
+                index = (index / BITS + 1) * BITS;
+                continue;
+            }
+            index += word.trailing_zeros() as usize;
+            return (index < last).then_some(index as u64 * block_size(order));
+        }
+        None
+    }
+}
+
+// Free blocks of one zone are the zone's bits in the free map, with a count
+// per order so empty orders are skipped without scanning.
+// Blocks are borrowed from the PMM bitmap, so neither allocator can hand out
+// a frame the other one owns. A block that merges all the way up to
+// MAX_ORDER is returned to the PMM.
+struct BuddyZone {
+    zone: Zone,
+    free: [usize; ORDERS],
+    // pages currently taken from the PMM, free or not
+    owned_pages: usize,
+}
+
+impl BuddyZone {
+    const fn new(zone: Zone) -> Self {
+        BuddyZone {
+            zone,
+            free: [0; ORDERS],
+            owned_pages: 0,
+        }
+    }
+
+    fn insert(&mut self, map: &mut FreeMap, order: usize, block: u64) {
+        map.set_free(order, block);
+        self.free[order] += 1;
+    }
+
+    fn remove(&mut self, map: &mut FreeMap, order: usize, block: u64) {
+        map.set_used(order, block);
+        self.free[order] -= 1;
+    }
+
+    // Take a naturally aligned block of the largest order the PMM can give,
+    // but at least `order`
+    fn refill(&mut self, map: &mut FreeMap, order: usize) -> bool {
+        for k in (order..ORDERS).rev() {
+            if let Some(block) = pmm::alloc_contiguous_in(1 << k, block_size(k), self.zone.range()) {
+                self.insert(map, k, block.as_u64());
+                self.owned_pages += 1 << k;
+                return true;
+            }
+        }
+        false
+    }
+
+    fn alloc(&mut self, map: &mut FreeMap, order: usize) -> Option<PAddr> {
+        let found = match (order..ORDERS).find(|&k| self.free[k] != 0) {
+            Some(k) => k,
+            None => {
+                if !self.refill(map, order) {
+                    return None;
+                }
+                (order..ORDERS).find(|&k| self.free[k] != 0)?
+            }
+        };
+        let block = map.find(found, &self.zone.range())?;
+        self.remove(map, found, block);
+        // split, keeping the lower half and freeing the upper one
+        for k in (order..found).rev() {
+            self.insert(map, k, block + block_size(k));
+        }
+        Some(PAddr(block))
+    }
+
+    fn free(&mut self, map: &mut FreeMap, addr: PAddr, order: usize) {
+        let mut block = addr.as_u64();
+        let mut order = order;
+        while order < MAX_ORDER {
+            let buddy = block ^ block_size(order);
+            if !map.is_free(order, buddy) {
+                break;
+            }
+            self.remove(map, order, buddy);
+            block = block.min(buddy);
+            order += 1;
+        }
+        if order == MAX_ORDER {
+            pmm::free_contiguous(PAddr(block), 1 << MAX_ORDER);
+            self.owned_pages -= 1 << MAX_ORDER;
+        } else {
+            self.insert(map, order, block);
+        }
+    }
+
+    // Give every free block back to the PMM
+    fn trim(&mut self, map: &mut FreeMap) -> usize {
+        let mut released = 0;
+        for order in 0..ORDERS {
+            while let Some(block) = map.find(order, &self.zone.range()) {
+                self.remove(map, order, block);
+                pmm::free_contiguous(PAddr(block), 1 << order);
+                released += 1 << order;
+            }
+        }
+        self.owned_pages -= released;
+        released
+    }
+
+    fn free_pages(&self) -> usize {
+        self.free.iter().enumerate().map(|(order, blocks)| blocks << order).sum()
+    }
+}
+
+struct BuddyAllocator {
+    map: Option<FreeMap>,
+    dma: BuddyZone,
+    normal: BuddyZone,
+}
+
+impl BuddyAllocator {
+    fn zone(&mut self, zone: Zone) -> Option<(&mut FreeMap, &mut BuddyZone)> {
+        let map = self.map.as_mut()?;
+        match zone {
+            Zone::Dma => Some((map, &mut self.dma)),
+            Zone::Normal => Some((map, &mut self.normal)),
+        }
+    }
+}
+
+static BUDDY: SpinLock<BuddyAllocator> = SpinLock::new(BuddyAllocator {
+    map: None,
+    dma: BuddyZone::new(Zone::Dma),
+    normal: BuddyZone::new(Zone::Normal),
+});
+
+// `map` covers physical memory up to `end`, see `free_map_size`
+pub unsafe fn init(end: u64, map: &'static mut [u64]) {
+    BUDDY.lock().map = Some(FreeMap::new(end, map));
+}
+
+// Smallest order whose block holds `pages` pages
+pub fn order_for(pages: usize) -> usize {
+    pages.max(1).next_power_of_two().trailing_zeros() as usize
+}
+
+// 2^order physically contiguous pages, aligned to their own size
+pub fn alloc_pages(order: usize, zone: Zone) -> Option<PAddr> {
+    assert!(order <= MAX_ORDER, "buddy: order {} is too large", order);
+    let mut buddy = BUDDY.lock();
+    let (map, zone) = buddy.zone(zone)?;
+    zone.alloc(map, order)
+}
+
+pub fn free_pages(addr: PAddr, order: usize) {
+    assert!(order <= MAX_ORDER, "buddy: order {} is too large", order);
+    assert!(addr.as_u64() % block_size(order) == 0, "buddy: {:#x} is not an order {} block", addr, order);
+    let mut buddy = BUDDY.lock();
+    let (map, zone) = buddy.zone(Zone::of(addr)).expect("buddy: not initialized");
+    zone.free(map, addr, order)
+}
+
+// Return all cached free blocks to the PMM, returns number of pages released
+pub fn trim() -> usize {
+    let mut buddy = BUDDY.lock();
+    let buddy = &mut *buddy;
+    match buddy.map.as_mut() {
+        Some(map) => buddy.dma.trim(map) + buddy.normal.trim(map),
+        None => 0,
+    }
+}
+
+// (owned, free) pages of a zone
+pub fn zone_stats(zone: Zone) -> (usize, usize) {
+    let mut buddy = BUDDY.lock();
+    match buddy.zone(zone) {
+        Some((_, zone)) => (zone.owned_pages, zone.free_pages()),
+        None => (0, 0),
+    }
+}
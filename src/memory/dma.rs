@@ -0,0 +1,76 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86::bits64::paging::{PAddr, PTFlags, VAddr, BASE_PAGE_SIZE};
+use super::buddy::{self, Zone, MAX_ORDER};
+use super::vmm::{MapError, VirtualMemoryManager};
+use super::DMA_START;
+
+const PAGE_SIZE: u64 = BASE_PAGE_SIZE as u64;
+
+// next free address in the DMA window, never reused
+static NEXT_DMA: AtomicU64 = AtomicU64::new(DMA_START);
+
+// Physically contiguous memory for devices to read and write, mapped for
+// the CPU too. Pages come from the buddy allocator and go back to it when
+// the buffer is dropped.
+pub struct DmaBuffer {
+    paddr: PAddr,
+    vaddr: VAddr,
+    order: usize,
+}
+
+impl DmaBuffer {
+    // At least `size` zeroed bytes, aligned to their own power of two size.
+    // Devices that can only address 16 MiB want `Zone::Dma`.
+    pub fn new(size: usize, zone: Zone) -> Result<Self, MapError> {
+        let pages = (size.max(1) as u64).div_ceil(PAGE_SIZE) as usize;
+        let order = buddy::order_for(pages);
+        if order > MAX_ORDER {
+            return Err(MapError::OutOfFrames);
+        }
+        let paddr = buddy::alloc_pages(order, zone).ok_or(MapError::OutOfFrames)?;
+        let count = 1 << order;
+        let vaddr = NEXT_DMA.fetch_add(count as u64 * PAGE_SIZE, Ordering::Relaxed);
+        let mut vmm = VirtualMemoryManager::active();
+        if let Err(e) = unsafe { vmm.map_range(VAddr(vaddr), paddr, count, PTFlags::RW) } {
+            buddy::free_pages(paddr, order);
+            return Err(e);
+        }
+        unsafe { core::ptr::write_bytes(vaddr as *mut u8, 0, count * PAGE_SIZE as usize); }
+        Ok(DmaBuffer { paddr, vaddr: VAddr(vaddr), order })
+    }
+
+    pub fn paddr(&self) -> PAddr {
+        self.paddr
+    }
+
+    pub fn vaddr(&self) -> VAddr {
+        self.vaddr
+    }
+
+    pub fn len(&self) -> usize {
+        (PAGE_SIZE << self.order) as usize
+    }
+
+    pub fn as_ptr<T>(&self) -> *mut T {
+        self.vaddr.as_u64() as *mut T
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.as_ptr(), self.len()) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.as_ptr(), self.len()) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        let mut vmm = VirtualMemoryManager::active();
+        // pages can only go back once nothing maps them anymore
+        match unsafe { vmm.unmap_range(self.vaddr, 1 << self.order) } {
+            Ok(()) => buddy::free_pages(self.paddr, self.order),
+            Err(e) => warn!("dma: leaking {:#x}, unmap failed: {:?}", self.paddr, e),
+        }
+    }
+}
@@ -1,22 +1,308 @@
 use core::alloc::{GlobalAlloc, Layout};
+use core::mem::{align_of, size_of};
+use core::ptr::{null_mut, NonNull};
+use x86::bits64::paging::{PTFlags, VAddr, BASE_PAGE_SIZE};
+use crate::sync::SpinLock;
+use super::slab::{self, size_class};
+use super::vmm::{PageSize, VirtualMemoryManager};
+use super::{align_up, pmm, KERNEL_HEAP_MAX_SIZE, KERNEL_HEAP_START};
+
+// heap is grown by at least this much at a time
+const HEAP_GROW_MIN: usize = 16 * BASE_PAGE_SIZE;
+
+// every block has to fit a free list node once it's released
+const BLOCK_ALIGN: usize = align_of::<FreeBlock>();
+const MIN_BLOCK: usize = size_of::<FreeBlock>();
+
+// Header written into the first bytes of every free block.
+// Free list is kept sorted by address so neighbours can be merged.
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+impl FreeBlock {
+    fn start(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    fn end(&self) -> usize {
+        self.start() + self.size
+    }
+}
+
+// Actual block size used for a layout: the size is rounded up so that
+// whatever is left around an allocation can always hold a `FreeBlock`.
+fn block_size(layout: &Layout) -> usize {
+    align_up(layout.size().max(MIN_BLOCK) as u64, BLOCK_ALIGN as u64) as usize
+}
+
+fn block_align(layout: &Layout) -> usize {
+    layout.align().max(BLOCK_ALIGN)
+}
+
+// First-fit free list allocator over a reserved virtual range.
+// Pages are mapped in from the PMM only when the free list runs dry.
+pub struct Heap {
+    head: *mut FreeBlock,
+    start: usize,
+    // end of the mapped part of the heap
+    top: usize,
+    limit: usize,
+}
+
+unsafe impl Send for Heap {}
+
+impl Heap {
+    pub const fn new(start: usize, max_size: usize) -> Self {
+        Heap {
+            head: null_mut(),
+            start,
+            top: start,
+            limit: start + max_size,
+        }
+    }
+
+    pub fn mapped_size(&self) -> usize {
+        self.top - self.start
+    }
+
+    pub fn free_size(&self) -> usize {
+        let mut total = 0;
+        let mut cur = self.head;
+        while !cur.is_null() {
+            unsafe {
+                total += (*cur).size;
+                cur = (*cur).next;
+            }
+        }
+        total
+    }
+
+    // Put `start..start + size` back into the free list, merging it with
+    // the blocks right before and after it.
+    unsafe fn insert_free(&mut self, start: usize, size: usize) {
+        let mut prev: *mut FreeBlock = null_mut();
+        let mut cur = self.head;
+        while !cur.is_null() && (*cur).start() < start {
+            prev = cur;
+            cur = (*cur).next;
+        }
+        assert!(cur.is_null() || start + size <= (*cur).start(), "heap: double free at {:#x}", start);
+        assert!(prev.is_null() || (*prev).end() <= start, "heap: double free at {:#x}", start);
+
+        let block = start as *mut FreeBlock;
+        block.write(FreeBlock { size, next: cur });
+        if !cur.is_null() && (*block).end() == (*cur).start() {
+            (*block).size += (*cur).size;
+            (*block).next = (*cur).next;
+        }
+        if prev.is_null() {
+            self.head = block;
+        } else if (*prev).end() == start {
+            (*prev).size += (*block).size;
+            (*prev).next = (*block).next;
+        } else {
+            (*prev).next = block;
+        }
+    }
+
+    // Where an allocation would land inside `block`, if it fits at all.
+    // Both the gap before it and the remainder after it have to be either
+    // empty or big enough to stay on the free list.
+    fn fit(block: &FreeBlock, size: usize, align: usize) -> Option<usize> {
+        let mut alloc_start = align_up(block.start() as u64, align as u64) as usize;
+        if alloc_start != block.start() && alloc_start - block.start() < MIN_BLOCK {
+            alloc_start = align_up((block.start() + MIN_BLOCK) as u64, align as u64) as usize;
+        }
+        let alloc_end = alloc_start.checked_add(size)?;
+        if alloc_end > block.end() {
+            return None;
+        }
+        let tail = block.end() - alloc_end;
+        if tail != 0 && tail < MIN_BLOCK {
+            return None;
+        }
+        Some(alloc_start)
+    }
+
+    unsafe fn take_first_fit(&mut self, size: usize, align: usize) -> Option<usize> {
+        let mut prev: *mut FreeBlock = null_mut();
+        let mut cur = self.head;
+        while !cur.is_null() {
+            if let Some(alloc_start) = Self::fit(&*cur, size, align) {
+                let (block_start, block_end, next) = ((*cur).start(), (*cur).end(), (*cur).next);
+                if prev.is_null() {
+                    self.head = next;
+                } else {
+                    (*prev).next = next;
+                }
+                if alloc_start > block_start {
+                    self.insert_free(block_start, alloc_start - block_start);
+                }
+                if alloc_start + size < block_end {
+                    self.insert_free(alloc_start + size, block_end - alloc_start - size);
+                }
+                return Some(alloc_start);
+            }
+            prev = cur;
+            cur = (*cur).next;
+        }
+        None
+    }
+
+    // Map at least `min_size` more bytes at the top of the heap
+    unsafe fn grow(&mut self, min_size: usize) -> bool {
+        let size = align_up(min_size.max(HEAP_GROW_MIN) as u64, BASE_PAGE_SIZE as u64) as usize;
+        let size = size.min(self.limit - self.top);
+        let mut vmm = VirtualMemoryManager::active();
+        let mut mapped = 0;
+        while mapped < size {
+            let frame = match pmm::alloc_frame() {
+                Some(frame) => frame,
+                None => break,
+            };
+            if vmm.map(VAddr((self.top + mapped) as u64), frame, PageSize::Size4K, PTFlags::RW).is_err() {
+                pmm::free_frame(frame);
+                break;
+            }
+            mapped += BASE_PAGE_SIZE;
+        }
+        if mapped == 0 {
+            return false;
+        }
+        let old_top = self.top;
+        self.top += mapped;
+        self.insert_free(old_top, mapped);
+        mapped >= min_size
+    }
+
+    pub unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let size = block_size(&layout);
+        let align = block_align(&layout);
+        loop {
+            if let Some(addr) = self.take_first_fit(size, align) {
+                return addr as *mut u8;
+            }
+            // worst case the new pages need padding for alignment
+            if !self.grow(size + align + MIN_BLOCK) {
+                return null_mut();
+            }
+        }
+    }
+
+    pub unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        self.insert_free(ptr as usize, block_size(&layout));
+    }
+
+    // Try to resize a block without moving it: shrinking gives the tail
+    // back, growing eats into a free block that directly follows.
+    pub unsafe fn resize_in_place(&mut self, ptr: *mut u8, layout: Layout, new_size: usize) -> bool {
+        let start = ptr as usize;
+        let old = block_size(&layout);
+        let new = block_size(&Layout::from_size_align_unchecked(new_size, layout.align()));
+        if new == old {
+            return true;
+        }
+        if new < old {
+            if old - new < MIN_BLOCK {
+                return false;
+            }
+            self.insert_free(start + new, old - new);
+            return true;
+        }
+
+        let end = start + old;
+        let mut prev: *mut FreeBlock = null_mut();
+        let mut cur = self.head;
+        while !cur.is_null() && (*cur).start() < end {
+            prev = cur;
+            cur = (*cur).next;
+        }
+        if cur.is_null() || (*cur).start() != end {
+            return false;
+        }
+        let needed = new - old;
+        let available = (*cur).size;
+        if available < needed || (available != needed && available - needed < MIN_BLOCK) {
+            return false;
+        }
+        let next = (*cur).next;
+        if prev.is_null() {
+            self.head = next;
+        } else {
+            (*prev).next = next;
+        }
+        if available > needed {
+            self.insert_free(end + needed, available - needed);
+        }
+        true
+    }
+}
+
+pub(super) static HEAP: SpinLock<Heap> = SpinLock::new(Heap::new(KERNEL_HEAP_START as usize, KERNEL_HEAP_MAX_SIZE));
+
+pub fn heap_stats() -> (usize, usize) {
+    let heap = HEAP.lock();
+    (heap.mapped_size(), heap.free_size())
+}
 
 struct KernelHeapAllocator;
 
+// Small layouts are served by the slab size classes, everything else
+// goes straight to the free list.
+unsafe fn try_alloc(layout: Layout) -> *mut u8 {
+    match size_class(&layout) {
+        Some(cache) => cache.alloc().map_or(null_mut(), |ptr| ptr.as_ptr()),
+        None => HEAP.lock().alloc(layout),
+    }
+}
+
 unsafe impl GlobalAlloc for KernelHeapAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        todo!()
+        let ptr = try_alloc(layout);
+        if !ptr.is_null() {
+            return ptr;
+        }
+        // out of memory: let the caches give back what they can and retry
+        if slab::reclaim_all() == 0 {
+            return null_mut();
+        }
+        try_alloc(layout)
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        todo!()
+        match size_class(&layout) {
+            Some(cache) => cache.free(NonNull::new_unchecked(ptr)),
+            None => HEAP.lock().dealloc(ptr, layout),
+        }
     }
 
     unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
-        todo!()
+        let ptr = self.alloc(layout);
+        if !ptr.is_null() {
+            core::ptr::write_bytes(ptr, 0, layout.size());
+        }
+        ptr
     }
 
     unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
-        todo!()
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        match (size_class(&layout), size_class(&new_layout)) {
+            (Some(old), Some(new)) if core::ptr::eq(old, new) => return ptr,
+            (None, None) => {
+                if HEAP.lock().resize_in_place(ptr, layout, new_size) {
+                    return ptr;
+                }
+            }
+            _ => {}
+        }
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            core::ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
     }
 }
 
@@ -0,0 +1,28 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86::bits64::paging::{PAddr, PTFlags, VAddr, BASE_PAGE_SIZE};
+use super::vmm::{MapError, VirtualMemoryManager};
+use super::{align_down, align_up, MMIO_START};
+
+const PAGE_SIZE: u64 = BASE_PAGE_SIZE as u64;
+
+// next free address in the MMIO window, never reused
+static NEXT_MMIO: AtomicU64 = AtomicU64::new(MMIO_START);
+
+// Map `size` bytes of physical address space starting at `paddr` somewhere
+// in the MMIO window. `paddr` doesn't need to be page aligned, the returned
+// address points at it, not at the start of its page.
+pub fn map_physical(paddr: PAddr, size: usize, flags: PTFlags) -> Result<VAddr, MapError> {
+    let start = align_down(paddr.as_u64(), PAGE_SIZE);
+    let end = align_up(paddr.as_u64() + size.max(1) as u64, PAGE_SIZE);
+    let vaddr = NEXT_MMIO.fetch_add(end - start, Ordering::Relaxed);
+    let mut vmm = VirtualMemoryManager::active();
+    unsafe {
+        vmm.map_range(VAddr(vaddr), PAddr(start), ((end - start) / PAGE_SIZE) as usize, flags)?;
+    }
+    Ok(VAddr(vaddr + paddr.as_u64() - start))
+}
+
+// Device registers, uncached
+pub fn map_mmio(paddr: PAddr, size: usize) -> Result<VAddr, MapError> {
+    map_physical(paddr, size, PTFlags::RW | PTFlags::PCD | PTFlags::PWT)
+}
@@ -9,9 +9,23 @@ use x86::bits64::paging::{LARGE_PAGE_SIZE, PD, PDEntry, PDFlags, PDPT, pdpt_inde
 use x86::dtables::DescriptorTablePointer;
 pub mod vmm;
 pub mod pmm;
-mod heap;
+pub mod heap;
+pub mod slab;
+pub mod buddy;
+pub mod stack;
+pub mod mmio;
+pub mod dma;
 
-const HIGHER_HALF: u64 = 0xFFFF800000000000;
+pub const HIGHER_HALF: u64 = 0xFFFF800000000000;
+// kernel heap gets its own PML4 slot, mapped on demand
+const KERNEL_HEAP_START: u64 = 0xFFFF880000000000;
+const KERNEL_HEAP_MAX_SIZE: usize = 1 << 30;
+// kernel stacks with guard pages in between
+const KERNEL_STACKS_START: u64 = 0xFFFF900000000000;
+// device registers and firmware tables, mapped on request
+const MMIO_START: u64 = 0xFFFFA00000000000;
+// buffers shared with devices
+const DMA_START: u64 = 0xFFFFB00000000000;
 
 fn sign_extend_48(addr: u64) -> u64 {
     if addr > 0x00007FFFFFFFFFFF {
@@ -21,7 +35,11 @@ fn sign_extend_48(addr: u64) -> u64 {
     }
 }
 
-fn align_up(addr: u64, align: u64) -> u64 {
+const fn align_down(addr: u64, align: u64) -> u64 {
+    addr & !(align - 1)
+}
+
+const fn align_up(addr: u64, align: u64) -> u64 {
     let align_mask = align - 1;
     if addr & align_mask == 0 {
         addr
@@ -47,6 +65,17 @@ pub struct BootInfo {
     pub kv_end: VAddr,
 }
 
+// relocated MB2 info, stays mapped and reserved for good
+static mut MB2_INFO: VAddr = VAddr(0);
+
+// Bootloader info, only valid after `init_memory`
+pub fn boot_info() -> multiboot2::BootInformation {
+    unsafe {
+        assert!(MB2_INFO.as_u64() != 0, "boot_info: memory not initialized");
+        multiboot2::load(MB2_INFO.into()).unwrap()
+    }
+}
+
 unsafe fn relocate_mb2_at_addr(info: *mut BootInfo, addr: VAddr) {
     // read MB2 header wherever it is currently
     let boot_info = multiboot2::load((*info).mb2.into()).unwrap();
@@ -59,11 +88,24 @@ unsafe fn relocate_mb2_at_addr(info: *mut BootInfo, addr: VAddr) {
 pub unsafe fn init_memory(info: *mut BootInfo) {
     // move MB2 header to fixed location
     relocate_mb2_at_addr(info, (*info).kv_end);
+    MB2_INFO = (*info).mb2;
     let boot_info = multiboot2::load((*info).mb2.into()).unwrap();
+    let mmap = boot_info.memory_map_tag().expect("no memory map from bootloader");
     let pml4_pa = PAddr(0x100000);
     let pdpt_pa = PAddr(0x101000);
     let pd_pa = PAddr(0x102000);
 
+    // frame bitmap goes to the first page after relocated MB2
+    let mb2_pa = (*info).kp_end;
+    let mb2_end = mb2_pa.as_u64() + boot_info.total_size() as u64;
+    let zone = pmm::zone_from_mmap(mmap);
+    let bitmap_pa = align_up(mb2_end, BASE_PAGE_SIZE as u64);
+    let bitmap_size = pmm::PMM::bitmap_size(&zone);
+    let bitmap_end = align_up(bitmap_pa + bitmap_size as u64, BASE_PAGE_SIZE as u64);
+    // buddy free map right behind it
+    let free_map_size = buddy::free_map_size(zone.end);
+    let free_map_end = align_up(bitmap_end + free_map_size as u64, BASE_PAGE_SIZE as u64);
+
     core::ptr::write(pml4_pa.as_u64() as *mut PML4, zeroed());
     let pml4 = &mut *(pml4_pa.as_u64() as *mut PML4);
 
@@ -72,10 +114,10 @@ pub unsafe fn init_memory(info: *mut BootInfo) {
         pdpt_pa,
         PML4Flags::P | PML4Flags::RW
     );
-    // recursive paging
+    // recursive paging, writable so vmm can edit tables through it
     pml4[511] = PML4Entry::new(
         pml4_pa,
-        PML4Flags::P
+        PML4Flags::P | PML4Flags::RW
     );
 
     core::ptr::write(pdpt_pa.as_u64() as *mut PDPT, zeroed());
@@ -90,12 +132,35 @@ pub unsafe fn init_memory(info: *mut BootInfo) {
     core::ptr::write(pd_pa.as_u64() as *mut PD, zeroed());
     let pd = &mut *(pd_pa.as_u64() as *mut PD);
 
-    for i in 0..2 {
-        pd[i] = PDEntry::new(
+    // at least 4 MiB, more if the bitmaps don't fit
+    let large_pages = (align_up(free_map_end, LARGE_PAGE_SIZE as u64) / LARGE_PAGE_SIZE as u64).max(2) as usize;
+    assert!(large_pages <= pd.len(), "frame bitmaps don't fit into first GiB");
+    for (i, entry) in pd.iter_mut().enumerate().take(large_pages) {
+        *entry = PDEntry::new(
             PAddr((i * LARGE_PAGE_SIZE) as u64),
             PDFlags::P | PDFlags::RW | PDFlags::PS
         )
     }
 
     x86::controlregs::cr3_write(pml4_pa.into());
+
+    let bitmap = core::slice::from_raw_parts_mut(
+        (HIGHER_HALF + bitmap_pa) as *mut u64,
+        bitmap_size / size_of::<u64>()
+    );
+    pmm::init(mmap, bitmap, &[
+        // bootstrap page tables above
+        pml4_pa.as_u64()..pd_pa.as_u64() + BASE_PAGE_SIZE as u64,
+        // bootstrap GDT, still loaded
+        (*info).gdt.as_u64()..(*info).gdt.as_u64() + BASE_PAGE_SIZE as u64,
+        // kernel image and stack
+        (*info).kp_start.as_u64()..(*info).kp_end.as_u64(),
+        // relocated MB2 info, frame bitmap and buddy free map
+        mb2_pa.as_u64()..free_map_end,
+    ]);
+    let free_map = core::slice::from_raw_parts_mut(
+        (HIGHER_HALF + bitmap_end) as *mut u64,
+        free_map_size / size_of::<u64>()
+    );
+    buddy::init(zone.end, free_map);
 }
@@ -1,16 +1,235 @@
-use alloc::vec::Vec;
 use core::ops::Range;
+use multiboot2::{MemoryAreaType, MemoryMapTag};
+use x86::bits64::paging::{PAddr, BASE_PAGE_SIZE};
+use crate::sync::SpinLock;
+use super::{align_down, align_up};
 
+const FRAME_SIZE: u64 = BASE_PAGE_SIZE as u64;
+const BITS: usize = u64::BITS as usize;
+// below this is kept for ISA DMA as long as there is anything else left
+pub const LOW_ZONE_END: u64 = 16 * 1024 * 1024;
+
+static FRAMES: SpinLock<Option<PMM>> = SpinLock::new(None);
+
+// Bitmap frame allocator, one bit per 4 KiB frame, set bit means the frame
+// is in use. The bitmap itself lives in physical memory right after the
+// relocated mb2 block, so the allocator works before the heap exists.
 pub struct PMM {
     zone: Range<u64>,
-    pages: Vec<u64>
+    pages: &'static mut [u64],
+    free: usize,
+    // word index to start searching from
+    next: usize,
 }
 
 impl PMM {
-    pub fn new(zone: Range<u64>) -> Self {
+    // Everything starts out as used, `add_region` hands memory to the allocator.
+    pub fn new(zone: Range<u64>, pages: &'static mut [u64]) -> Self {
+        assert!(pages.len() * BITS * FRAME_SIZE as usize >= (zone.end - zone.start) as usize);
+        pages.fill(u64::MAX);
         Self {
             zone,
-            pages: Vec::new()
+            pages,
+            free: 0,
+            next: 0,
+        }
+    }
+
+    // Number of bitmap bytes needed to cover `zone`
+    pub fn bitmap_size(zone: &Range<u64>) -> usize {
+        let frames = ((zone.end - zone.start) / FRAME_SIZE) as usize;
+        (frames + BITS - 1) / BITS * core::mem::size_of::<u64>()
+    }
+
+    fn frame_count(&self) -> usize {
+        ((self.zone.end - self.zone.start) / FRAME_SIZE) as usize
+    }
+
+    fn index_of(&self, addr: u64) -> usize {
+        ((addr - self.zone.start) / FRAME_SIZE) as usize
+    }
+
+    fn addr_of(&self, index: usize) -> u64 {
+        self.zone.start + index as u64 * FRAME_SIZE
+    }
+
+    // single frames come from above the low zone first
+    fn first_normal_word(&self) -> usize {
+        let start = LOW_ZONE_END.clamp(self.zone.start, self.zone.end);
+        self.index_of(start) / BITS
+    }
+
+    fn is_used(&self, index: usize) -> bool {
+        self.pages[index / BITS] & (1 << (index % BITS)) != 0
+    }
+
+    fn set_used(&mut self, index: usize) {
+        if !self.is_used(index) {
+            self.pages[index / BITS] |= 1 << (index % BITS);
+            self.free -= 1;
         }
     }
-}
\ No newline at end of file
+
+    fn set_free(&mut self, index: usize) {
+        if self.is_used(index) {
+            self.pages[index / BITS] &= !(1 << (index % BITS));
+            self.free += 1;
+        }
+    }
+
+    // Clip a physical range to whole frames inside the zone, as bitmap indices.
+    // Partial frames at the edges are dropped when `inner`, kept otherwise.
+    fn frame_range(&self, range: &Range<u64>, inner: bool) -> Range<usize> {
+        let (start, end) = match inner {
+            true => (align_up(range.start, FRAME_SIZE), align_down(range.end, FRAME_SIZE)),
+            false => (align_down(range.start, FRAME_SIZE), align_up(range.end, FRAME_SIZE)),
+        };
+        let start = start.clamp(self.zone.start, self.zone.end);
+        let end = end.clamp(self.zone.start, self.zone.end);
+        if start >= end {
+            return 0..0;
+        }
+        self.index_of(start)..self.index_of(end)
+    }
+
+    pub fn add_region(&mut self, range: Range<u64>) {
+        for i in self.frame_range(&range, true) {
+            self.set_free(i);
+        }
+        self.next = self.first_normal_word();
+    }
+
+    pub fn reserve_region(&mut self, range: Range<u64>) {
+        for i in self.frame_range(&range, false) {
+            self.set_used(i);
+        }
+    }
+
+    pub fn free_frames(&self) -> usize {
+        self.free
+    }
+
+    pub fn is_frame_free(&self, frame: PAddr) -> bool {
+        let addr = frame.as_u64();
+        self.zone.contains(&addr) && !self.is_used(self.index_of(addr))
+    }
+
+    pub fn alloc_frame(&mut self) -> Option<PAddr> {
+        if self.free == 0 {
+            return None;
+        }
+        let words = self.pages.len();
+        for w in (self.next..words).chain(0..self.next) {
+            let word = self.pages[w];
+            if word == u64::MAX {
+                continue;
+            }
+            let index = w * BITS + (!word).trailing_zeros() as usize;
+            if index >= self.frame_count() {
+                continue;
+            }
+            self.set_used(index);
+            self.next = w;
+            return Some(PAddr(self.addr_of(index)));
+        }
+        None
+    }
+
+    pub fn free_frame(&mut self, frame: PAddr) {
+        let addr = frame.as_u64();
+        assert!(addr % FRAME_SIZE == 0, "pmm: freeing unaligned frame {:#x}", addr);
+        assert!(self.zone.contains(&addr), "pmm: freeing frame {:#x} outside of {:x?}", addr, self.zone);
+        let index = self.index_of(addr);
+        assert!(self.is_used(index), "pmm: double free of frame {:#x}", addr);
+        self.set_free(index);
+        if index / BITS < self.next && index / BITS >= self.first_normal_word() {
+            self.next = index / BITS;
+        }
+    }
+
+    // First-fit search for `count` free frames whose start address is
+    // aligned to `align` bytes.
+    pub fn alloc_contiguous(&mut self, count: usize, align: u64) -> Option<PAddr> {
+        self.alloc_contiguous_in(count, align, self.zone.clone())
+    }
+
+    // Same as `alloc_contiguous`, but the whole block has to be inside `range`
+    pub fn alloc_contiguous_in(&mut self, count: usize, align: u64, range: Range<u64>) -> Option<PAddr> {
+        assert!(align.is_power_of_two() && align >= FRAME_SIZE, "pmm: bad alignment {:#x}", align);
+        if count == 0 || count > self.free {
+            return None;
+        }
+        let range = self.frame_range(&range, true);
+        let total = range.end;
+        let mut index = self.index_of(align_up(self.addr_of(range.start), align));
+        while index + count <= total {
+            match (index..index + count).rev().find(|&i| self.is_used(i)) {
+                // skip past the used frame to the next aligned candidate
+                Some(used) => index = self.index_of(align_up(self.addr_of(used + 1), align)),
+                None => {
+                    for i in index..index + count {
+                        self.set_used(i);
+                    }
+                    return Some(PAddr(self.addr_of(index)));
+                }
+            }
+        }
+        None
+    }
+
+    pub fn free_contiguous(&mut self, start: PAddr, count: usize) {
+        for i in 0..count {
+            self.free_frame(PAddr(start.as_u64() + i as u64 * FRAME_SIZE));
+        }
+    }
+}
+
+// Physical range the allocator has to cover: up to the end of the highest
+// available area in the memory map.
+pub fn zone_from_mmap(mmap: &MemoryMapTag) -> Range<u64> {
+    let end = mmap.memory_areas()
+        .filter(|area| area.typ() == MemoryAreaType::Available)
+        .map(|area| align_down(area.end_address(), FRAME_SIZE))
+        .max()
+        .unwrap_or(0);
+    0..end
+}
+
+pub unsafe fn init(mmap: &MemoryMapTag, bitmap: &'static mut [u64], reserved: &[Range<u64>]) {
+    let mut pmm = PMM::new(zone_from_mmap(mmap), bitmap);
+    for area in mmap.memory_areas() {
+        if area.typ() == MemoryAreaType::Available {
+            pmm.add_region(area.start_address()..area.end_address());
+        }
+    }
+    // null frame stays reserved, so a zero PAddr is never a valid allocation
+    pmm.reserve_region(0..FRAME_SIZE);
+    for range in reserved {
+        pmm.reserve_region(range.clone());
+    }
+    *FRAMES.lock() = Some(pmm);
+}
+
+pub fn alloc_frame() -> Option<PAddr> {
+    FRAMES.lock().as_mut()?.alloc_frame()
+}
+
+pub fn free_frame(frame: PAddr) {
+    FRAMES.lock().as_mut().expect("pmm: not initialized").free_frame(frame)
+}
+
+pub fn alloc_contiguous(count: usize, align: u64) -> Option<PAddr> {
+    FRAMES.lock().as_mut()?.alloc_contiguous(count, align)
+}
+
+pub fn alloc_contiguous_in(count: usize, align: u64, range: Range<u64>) -> Option<PAddr> {
+    FRAMES.lock().as_mut()?.alloc_contiguous_in(count, align, range)
+}
+
+pub fn free_contiguous(start: PAddr, count: usize) {
+    FRAMES.lock().as_mut().expect("pmm: not initialized").free_contiguous(start, count)
+}
+
+pub fn free_frames() -> usize {
+    FRAMES.lock().as_ref().map_or(0, |pmm| pmm.free_frames())
+}
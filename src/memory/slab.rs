@@ -0,0 +1,337 @@
+use core::alloc::Layout;
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of};
+use core::ptr::{null_mut, NonNull};
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86::bits64::paging::BASE_PAGE_SIZE;
+use crate::sync::SpinLock;
+use super::align_up;
+use super::heap::HEAP;
+
+// slabs with nothing allocated kept around per cache, the rest go back to the heap
+const MAX_EMPTY_SLABS: usize = 2;
+// a slab is sized to hold at least this many objects
+const MIN_OBJECTS_PER_SLAB: usize = 8;
+const MAX_CACHES: usize = 64;
+
+// Lives at the start of every slab, objects follow. Slabs are aligned to
+// their own size, so masking an object address gives its header.
+struct SlabHeader {
+    prev: *mut SlabHeader,
+    next: *mut SlabHeader,
+    free: *mut FreeObject,
+    in_use: usize,
+}
+
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+struct SlabList {
+    head: *mut SlabHeader,
+    len: usize,
+}
+
+impl SlabList {
+    const fn new() -> Self {
+        SlabList { head: null_mut(), len: 0 }
+    }
+
+    unsafe fn push(&mut self, slab: *mut SlabHeader) {
+        (*slab).prev = null_mut();
+        (*slab).next = self.head;
+        if !self.head.is_null() {
+            (*self.head).prev = slab;
+        }
+        self.head = slab;
+        self.len += 1;
+    }
+
+    unsafe fn remove(&mut self, slab: *mut SlabHeader) {
+        if (*slab).prev.is_null() {
+            self.head = (*slab).next;
+        } else {
+            (*(*slab).prev).next = (*slab).next;
+        }
+        if !(*slab).next.is_null() {
+            (*(*slab).next).prev = (*slab).prev;
+        }
+        self.len -= 1;
+    }
+
+    unsafe fn pop(&mut self) -> Option<*mut SlabHeader> {
+        let slab = self.head;
+        if slab.is_null() {
+            return None;
+        }
+        self.remove(slab);
+        Some(slab)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SlabStats {
+    pub name: &'static str,
+    pub object_size: usize,
+    pub slab_size: usize,
+    pub slabs: usize,
+    pub objects_in_use: usize,
+    pub objects_total: usize,
+    pub allocs: u64,
+    pub frees: u64,
+    pub reclaimed_slabs: u64,
+}
+
+struct Slabs {
+    partial: SlabList,
+    full: SlabList,
+    empty: SlabList,
+    objects_in_use: usize,
+    allocs: u64,
+    frees: u64,
+    reclaimed_slabs: u64,
+}
+
+unsafe impl Send for Slabs {}
+
+// Untyped cache of `object_size` byte objects
+pub struct RawSlabCache {
+    name: &'static str,
+    object_size: usize,
+    slab_size: usize,
+    first_object: usize,
+    capacity: usize,
+    // called before empty slabs are released, so the owner can drop
+    // whatever objects it keeps around as a cache of its own
+    reclaim_hook: Option<fn()>,
+    registered: AtomicBool,
+    slabs: SpinLock<Slabs>,
+}
+
+impl RawSlabCache {
+    pub const fn new(name: &'static str, layout: Layout, reclaim_hook: Option<fn()>) -> Self {
+        let align = if layout.align() > align_of::<FreeObject>() { layout.align() } else { align_of::<FreeObject>() };
+        let size = if layout.size() > size_of::<FreeObject>() { layout.size() } else { size_of::<FreeObject>() };
+        let object_size = align_up(size as u64, align as u64) as usize;
+        let first_object = align_up(size_of::<SlabHeader>() as u64, align as u64) as usize;
+        let mut slab_size = BASE_PAGE_SIZE;
+        while slab_size < first_object + object_size * MIN_OBJECTS_PER_SLAB && slab_size < 16 * BASE_PAGE_SIZE {
+            slab_size *= 2;
+        }
+        assert!(first_object + object_size <= slab_size, "slab: object too large for a slab cache");
+        RawSlabCache {
+            name,
+            object_size,
+            slab_size,
+            first_object,
+            capacity: (slab_size - first_object) / object_size,
+            reclaim_hook,
+            registered: AtomicBool::new(false),
+            slabs: SpinLock::new(Slabs {
+                partial: SlabList::new(),
+                full: SlabList::new(),
+                empty: SlabList::new(),
+                objects_in_use: 0,
+                allocs: 0,
+                frees: 0,
+                reclaimed_slabs: 0,
+            }),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn object_size(&self) -> usize {
+        self.object_size
+    }
+
+    fn slab_layout(&self) -> Layout {
+        unsafe { Layout::from_size_align_unchecked(self.slab_size, self.slab_size) }
+    }
+
+    fn slab_of(&self, ptr: *mut u8) -> *mut SlabHeader {
+        (ptr as usize & !(self.slab_size - 1)) as *mut SlabHeader
+    }
+
+    // Carve a fresh slab out of the heap and thread all objects onto its free list
+    unsafe fn new_slab(&self) -> Option<*mut SlabHeader> {
+        let base = HEAP.lock().alloc(self.slab_layout());
+        if base.is_null() {
+            return None;
+        }
+        let slab = base as *mut SlabHeader;
+        let mut free: *mut FreeObject = null_mut();
+        for i in (0..self.capacity).rev() {
+            let obj = base.add(self.first_object + i * self.object_size) as *mut FreeObject;
+            (*obj).next = free;
+            free = obj;
+        }
+        slab.write(SlabHeader { prev: null_mut(), next: null_mut(), free, in_use: 0 });
+        Some(slab)
+    }
+
+    unsafe fn release_slab(&self, slab: *mut SlabHeader) {
+        HEAP.lock().dealloc(slab as *mut u8, self.slab_layout());
+    }
+
+    pub fn alloc(&'static self) -> Option<NonNull<u8>> {
+        if !self.registered.swap(true, Ordering::Relaxed) {
+            register(self);
+        }
+        let mut slabs = self.slabs.lock();
+        unsafe {
+            let slab = match slabs.partial.pop().or_else(|| slabs.empty.pop()) {
+                Some(slab) => slab,
+                None => self.new_slab()?,
+            };
+            let obj = (*slab).free;
+            (*slab).free = (*obj).next;
+            (*slab).in_use += 1;
+            if (*slab).in_use == self.capacity {
+                slabs.full.push(slab);
+            } else {
+                slabs.partial.push(slab);
+            }
+            slabs.objects_in_use += 1;
+            slabs.allocs += 1;
+            NonNull::new(obj as *mut u8)
+        }
+    }
+
+    pub unsafe fn free(&self, ptr: NonNull<u8>) {
+        let obj = ptr.as_ptr() as *mut FreeObject;
+        let slab = self.slab_of(ptr.as_ptr());
+        let mut slabs = self.slabs.lock();
+        if (*slab).in_use == self.capacity {
+            slabs.full.remove(slab);
+        } else {
+            slabs.partial.remove(slab);
+        }
+        (*obj).next = (*slab).free;
+        (*slab).free = obj;
+        (*slab).in_use -= 1;
+        slabs.objects_in_use -= 1;
+        slabs.frees += 1;
+        if (*slab).in_use > 0 {
+            slabs.partial.push(slab);
+        } else if slabs.empty.len < MAX_EMPTY_SLABS {
+            slabs.empty.push(slab);
+        } else {
+            slabs.reclaimed_slabs += 1;
+            self.release_slab(slab);
+        }
+    }
+
+    // Run the owner's hook, then give every empty slab back to the heap.
+    // Returns the number of bytes released.
+    pub fn reclaim(&self) -> usize {
+        if let Some(hook) = self.reclaim_hook {
+            hook();
+        }
+        let mut slabs = self.slabs.lock();
+        let mut released = 0;
+        unsafe {
+            while let Some(slab) = slabs.empty.pop() {
+                self.release_slab(slab);
+                slabs.reclaimed_slabs += 1;
+                released += self.slab_size;
+            }
+        }
+        released
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        let slabs = self.slabs.lock();
+        let count = slabs.partial.len + slabs.full.len + slabs.empty.len;
+        SlabStats {
+            name: self.name,
+            object_size: self.object_size,
+            slab_size: self.slab_size,
+            slabs: count,
+            objects_in_use: slabs.objects_in_use,
+            objects_total: count * self.capacity,
+            allocs: slabs.allocs,
+            frees: slabs.frees,
+            reclaimed_slabs: slabs.reclaimed_slabs,
+        }
+    }
+}
+
+// Cache of `T` sized objects. Memory comes back uninitialized.
+pub struct SlabCache<T> {
+    raw: RawSlabCache,
+    phantom: PhantomData<T>,
+}
+
+impl<T> SlabCache<T> {
+    pub const fn new(name: &'static str) -> Self {
+        SlabCache {
+            raw: RawSlabCache::new(name, Layout::new::<T>(), None),
+            phantom: PhantomData,
+        }
+    }
+
+    pub const fn with_reclaim_hook(name: &'static str, hook: fn()) -> Self {
+        SlabCache {
+            raw: RawSlabCache::new(name, Layout::new::<T>(), Some(hook)),
+            phantom: PhantomData,
+        }
+    }
+
+    pub fn alloc(&'static self) -> Option<NonNull<T>> {
+        self.raw.alloc().map(|ptr| ptr.cast())
+    }
+
+    pub unsafe fn free(&self, ptr: NonNull<T>) {
+        self.raw.free(ptr.cast())
+    }
+
+    pub fn reclaim(&self) -> usize {
+        self.raw.reclaim()
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        self.raw.stats()
+    }
+}
+
+static CACHES: SpinLock<[Option<&'static RawSlabCache>; MAX_CACHES]> = SpinLock::new([None; MAX_CACHES]);
+
+fn register(cache: &'static RawSlabCache) {
+    let mut caches = CACHES.lock();
+    if let Some(slot) = caches.iter_mut().find(|slot| slot.is_none()) {
+        *slot = Some(cache);
+    }
+}
+
+pub fn for_each_cache<F: FnMut(SlabStats)>(mut func: F) {
+    let caches = *CACHES.lock();
+    for cache in caches.iter().flatten() {
+        func(cache.stats());
+    }
+}
+
+// Squeeze every registered cache, returns the number of bytes released
+pub fn reclaim_all() -> usize {
+    let caches = *CACHES.lock();
+    caches.iter().flatten().map(|cache| cache.reclaim()).sum()
+}
+
+// General purpose size classes behind `KernelHeapAllocator`
+static SIZE_CLASSES: [RawSlabCache; 7] = [
+    RawSlabCache::new("kmalloc-16", unsafe { Layout::from_size_align_unchecked(16, 16) }, None),
+    RawSlabCache::new("kmalloc-32", unsafe { Layout::from_size_align_unchecked(32, 32) }, None),
+    RawSlabCache::new("kmalloc-64", unsafe { Layout::from_size_align_unchecked(64, 64) }, None),
+    RawSlabCache::new("kmalloc-128", unsafe { Layout::from_size_align_unchecked(128, 128) }, None),
+    RawSlabCache::new("kmalloc-256", unsafe { Layout::from_size_align_unchecked(256, 256) }, None),
+    RawSlabCache::new("kmalloc-512", unsafe { Layout::from_size_align_unchecked(512, 512) }, None),
+    RawSlabCache::new("kmalloc-1024", unsafe { Layout::from_size_align_unchecked(1024, 1024) }, None),
+];
+
+// Size class serving `layout`, objects are aligned to their size
+pub(super) fn size_class(layout: &Layout) -> Option<&'static RawSlabCache> {
+    let size = layout.size().max(layout.align()).max(16).next_power_of_two();
+    SIZE_CLASSES.iter().find(|cache| cache.object_size() == size)
+}
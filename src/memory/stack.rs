@@ -0,0 +1,54 @@
+use core::arch::asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86::bits64::paging::{PTFlags, VAddr, BASE_PAGE_SIZE};
+use super::vmm::{MapError, PageSize, VirtualMemoryManager};
+use super::{pmm, KERNEL_STACKS_START};
+
+const PAGE_SIZE: u64 = BASE_PAGE_SIZE as u64;
+
+// next free address in the stack region, never reused
+static NEXT_STACK: AtomicU64 = AtomicU64::new(KERNEL_STACKS_START);
+
+// Kernel stack of `pages` pages with an unmapped guard page right below it,
+// so running off the end faults instead of silently eating other memory.
+// Returns the initial stack pointer, i.e. the end of the stack.
+pub fn alloc_kernel_stack(pages: usize) -> Result<VAddr, MapError> {
+    let size = (pages as u64 + 1) * PAGE_SIZE;
+    let guard = NEXT_STACK.fetch_add(size, Ordering::Relaxed);
+    let bottom = guard + PAGE_SIZE;
+    let mut vmm = VirtualMemoryManager::active();
+    for i in 0..pages as u64 {
+        if let Err(e) = map_page(&mut vmm, VAddr(bottom + i * PAGE_SIZE)) {
+            // give back what's mapped so far, the addresses stay burnt
+            for j in 0..i {
+                if let Ok(mapping) = unsafe { vmm.unmap(VAddr(bottom + j * PAGE_SIZE)) } {
+                    pmm::free_frame(mapping.paddr);
+                }
+            }
+            return Err(e);
+        }
+    }
+    Ok(VAddr(bottom + pages as u64 * PAGE_SIZE))
+}
+
+fn map_page(vmm: &mut VirtualMemoryManager, page: VAddr) -> Result<(), MapError> {
+    let frame = pmm::alloc_frame().ok_or(MapError::OutOfFrames)?;
+    let result = unsafe { vmm.map(page, frame, PageSize::Size4K, PTFlags::RW) };
+    if result.is_err() {
+        pmm::free_frame(frame);
+    }
+    result
+}
+
+// Continue in `entry` on the stack ending at `stack`, leaving the current
+// one behind for good
+pub unsafe fn switch_to(stack: VAddr, entry: unsafe extern "C" fn() -> !) -> ! {
+    asm!(
+        "mov rsp, {stack}",
+        "xor ebp, ebp",
+        "call {entry}",
+        stack = in(reg) stack.as_u64(),
+        entry = in(reg) entry,
+        options(noreturn)
+    )
+}
@@ -1,5 +1,259 @@
-use x86::bits64::paging::PML4;
+use x86::bits64::paging::{PAddr, VAddr, PML4, PDPT, PD, PT, PTFlags};
+use x86::bits64::paging::{pml4_index, pdpt_index, pd_index, pt_index};
+use x86::bits64::paging::{BASE_PAGE_SIZE, LARGE_PAGE_SIZE, HUGE_PAGE_SIZE};
+use x86::cpuid::CpuId;
+use x86::tlb;
+use super::pmm;
+
+// pml4[511] points back at the PML4, so every page table of the active
+// address space is reachable at a fixed virtual address:
+// PML4 at 0xFFFFFFFFFFFFF000, PDPTs from 0xFFFFFFFFFFE00000,
+// PDs from 0xFFFFFFFFC0000000 and PTs from 0xFFFFFF8000000000
+const RECURSIVE_PML4: u64 = 0xFFFF_FFFF_FFFF_F000;
+const RECURSIVE_PDPT: u64 = 0xFFFF_FFFF_FFE0_0000;
+const RECURSIVE_PD: u64 = 0xFFFF_FFFF_C000_0000;
+const RECURSIVE_PT: u64 = 0xFFFF_FF80_0000_0000;
+
+// bits shared by entries of all levels
+const ENTRY_PRESENT: u64 = 1 << 0;
+const ENTRY_WRITABLE: u64 = 1 << 1;
+const ENTRY_USER: u64 = 1 << 2;
+// 2 MiB / 1 GiB page in PD / PDPT entries
+const ENTRY_PAGE_SIZE: u64 = 1 << 7;
+// PAT bit moves from 7 to 12 in PD / PDPT entries mapping a page
+const ENTRY_PAT_4K: u64 = 1 << 7;
+const ENTRY_PAT_LARGE: u64 = 1 << 12;
+const ENTRY_ADDR_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+const ENTRY_FLAGS_MASK: u64 = !ENTRY_ADDR_MASK;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageSize {
+    Size4K,
+    Size2M,
+    Size1G,
+}
+
+impl PageSize {
+    pub fn bytes(self) -> u64 {
+        match self {
+            PageSize::Size4K => BASE_PAGE_SIZE as u64,
+            PageSize::Size2M => LARGE_PAGE_SIZE as u64,
+            PageSize::Size1G => HUGE_PAGE_SIZE as u64,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum MapError {
+    OutOfFrames,
+    AlreadyMapped(PAddr),
+    NotMapped,
+    Unaligned,
+    // a bigger page already covers part of the requested range
+    HugePageInTheWay,
+    // no 1 GiB page support reported by CPUID
+    Unsupported,
+}
+
+fn pdpt_addr(vaddr: VAddr) -> VAddr {
+    VAddr(RECURSIVE_PDPT | (pml4_index(vaddr) as u64) << 12)
+}
+
+fn pd_addr(vaddr: VAddr) -> VAddr {
+    VAddr(RECURSIVE_PD | (pml4_index(vaddr) as u64) << 21 | (pdpt_index(vaddr) as u64) << 12)
+}
+
+fn pt_addr(vaddr: VAddr) -> VAddr {
+    VAddr(RECURSIVE_PT | (pml4_index(vaddr) as u64) << 30 | (pdpt_index(vaddr) as u64) << 21 | (pd_index(vaddr) as u64) << 12)
+}
+
+// Make `entry` point to a page table, allocating and zeroing a frame for it
+// if it isn't present. `table` is where that table shows up through the
+// recursive slot.
+unsafe fn ensure_table(entry: &mut u64, table: VAddr, user: bool) -> Result<(), MapError> {
+    if *entry & ENTRY_PRESENT == 0 {
+        let frame = pmm::alloc_frame().ok_or(MapError::OutOfFrames)?;
+        *entry = frame.as_u64() | ENTRY_PRESENT | ENTRY_WRITABLE;
+        tlb::flush(table.as_usize());
+        core::ptr::write_bytes(table.as_mut_ptr::<u8>(), 0, BASE_PAGE_SIZE);
+    } else if *entry & ENTRY_PAGE_SIZE != 0 {
+        return Err(MapError::HugePageInTheWay);
+    }
+    if user {
+        *entry |= ENTRY_USER;
+    }
+    Ok(())
+}
+
+// Convert 4 KiB style flags into the layout of a PD / PDPT page entry
+fn large_page_bits(flags: PTFlags) -> u64 {
+    let bits = flags.bits() & !ENTRY_PAT_4K;
+    match flags.bits() & ENTRY_PAT_4K != 0 {
+        true => bits | ENTRY_PAT_LARGE | ENTRY_PAGE_SIZE,
+        false => bits | ENTRY_PAGE_SIZE,
+    }
+}
+
+fn large_page_flags(entry: u64) -> PTFlags {
+    let bits = entry & ENTRY_FLAGS_MASK & !(ENTRY_PAGE_SIZE | ENTRY_PAT_LARGE);
+    match entry & ENTRY_PAT_LARGE != 0 {
+        true => PTFlags::from_bits_truncate(bits | ENTRY_PAT_4K),
+        false => PTFlags::from_bits_truncate(bits),
+    }
+}
+
+fn huge_pages_supported() -> bool {
+    CpuId::new()
+        .get_extended_processor_and_feature_identifiers()
+        .map_or(false, |info| info.has_1gib_pages())
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Mapping {
+    // start of the page, not of `vaddr`
+    pub paddr: PAddr,
+    pub size: PageSize,
+    pub flags: PTFlags,
+}
 
 pub struct VirtualMemoryManager {
     pml4: *mut PML4
 }
+
+impl VirtualMemoryManager {
+    // Address space currently loaded in CR3
+    pub const fn active() -> Self {
+        VirtualMemoryManager {
+            pml4: RECURSIVE_PML4 as *mut PML4
+        }
+    }
+
+    // Raw entry mapping `vaddr`, or the first non-present one on the way there
+    unsafe fn walk(&mut self, vaddr: VAddr) -> (&mut u64, PageSize) {
+        let pml4 = &mut *self.pml4;
+        if !pml4[pml4_index(vaddr)].is_present() {
+            return (&mut pml4[pml4_index(vaddr)].0, PageSize::Size1G);
+        }
+        let pdpt = &mut *pdpt_addr(vaddr).as_mut_ptr::<PDPT>();
+        let entry = &mut pdpt[pdpt_index(vaddr)].0;
+        if *entry & ENTRY_PRESENT == 0 || *entry & ENTRY_PAGE_SIZE != 0 {
+            return (entry, PageSize::Size1G);
+        }
+        let pd = &mut *pd_addr(vaddr).as_mut_ptr::<PD>();
+        let entry = &mut pd[pd_index(vaddr)].0;
+        if *entry & ENTRY_PRESENT == 0 || *entry & ENTRY_PAGE_SIZE != 0 {
+            return (entry, PageSize::Size2M);
+        }
+        let pt = &mut *pt_addr(vaddr).as_mut_ptr::<PT>();
+        (&mut pt[pt_index(vaddr)].0, PageSize::Size4K)
+    }
+
+    pub unsafe fn map(&mut self, vaddr: VAddr, paddr: PAddr, size: PageSize, flags: PTFlags) -> Result<(), MapError> {
+        if vaddr.as_u64() % size.bytes() != 0 || paddr.as_u64() % size.bytes() != 0 {
+            return Err(MapError::Unaligned);
+        }
+        if size == PageSize::Size1G && !huge_pages_supported() {
+            return Err(MapError::Unsupported);
+        }
+        let user = flags.contains(PTFlags::US);
+        let pml4 = &mut *self.pml4;
+        ensure_table(&mut pml4[pml4_index(vaddr)].0, pdpt_addr(vaddr), user)?;
+        let pdpt = &mut *pdpt_addr(vaddr).as_mut_ptr::<PDPT>();
+        let entry = &mut pdpt[pdpt_index(vaddr)].0;
+        let entry = match size {
+            PageSize::Size1G => entry,
+            _ => {
+                ensure_table(entry, pd_addr(vaddr), user)?;
+                let pd = &mut *pd_addr(vaddr).as_mut_ptr::<PD>();
+                let entry = &mut pd[pd_index(vaddr)].0;
+                match size {
+                    PageSize::Size2M => entry,
+                    _ => {
+                        ensure_table(entry, pt_addr(vaddr), user)?;
+                        let pt = &mut *pt_addr(vaddr).as_mut_ptr::<PT>();
+                        &mut pt[pt_index(vaddr)].0
+                    }
+                }
+            }
+        };
+        // for 2 MiB / 1 GiB this also catches a table of smaller pages
+        if *entry & ENTRY_PRESENT != 0 {
+            return Err(MapError::AlreadyMapped(PAddr(*entry & ENTRY_ADDR_MASK)));
+        }
+        *entry = paddr.as_u64() | ENTRY_PRESENT | match size {
+            PageSize::Size4K => flags.bits(),
+            _ => large_page_bits(flags),
+        };
+        tlb::flush(vaddr.as_usize());
+        Ok(())
+    }
+
+    // Map `count` consecutive 4 KiB pages
+    pub unsafe fn map_range(&mut self, vaddr: VAddr, paddr: PAddr, count: usize, flags: PTFlags) -> Result<(), MapError> {
+        for i in 0..count as u64 {
+            let offset = i * BASE_PAGE_SIZE as u64;
+            self.map(vaddr + offset, paddr + offset, PageSize::Size4K, flags)?;
+        }
+        Ok(())
+    }
+
+    // Remove the page containing `vaddr`, returns what was mapped there.
+    // The frame itself still belongs to the caller.
+    pub unsafe fn unmap(&mut self, vaddr: VAddr) -> Result<Mapping, MapError> {
+        let (entry, size) = self.walk(vaddr);
+        if *entry & ENTRY_PRESENT == 0 {
+            return Err(MapError::NotMapped);
+        }
+        let mapping = Self::decode(*entry, size);
+        *entry = 0;
+        // invlpg drops the whole TLB entry, whatever size the page is
+        tlb::flush(vaddr.as_usize());
+        Ok(mapping)
+    }
+
+    pub unsafe fn unmap_range(&mut self, vaddr: VAddr, count: usize) -> Result<(), MapError> {
+        for i in 0..count as u64 {
+            self.unmap(vaddr + i * BASE_PAGE_SIZE as u64)?;
+        }
+        Ok(())
+    }
+
+    fn decode(entry: u64, size: PageSize) -> Mapping {
+        Mapping {
+            paddr: PAddr(entry & ENTRY_ADDR_MASK & !(size.bytes() - 1)),
+            size,
+            flags: match size {
+                PageSize::Size4K => PTFlags::from_bits_truncate(entry),
+                _ => large_page_flags(entry),
+            },
+        }
+    }
+
+    pub fn lookup(&mut self, vaddr: VAddr) -> Option<Mapping> {
+        let (entry, size) = unsafe { self.walk(vaddr) };
+        match *entry & ENTRY_PRESENT != 0 {
+            true => Some(Self::decode(*entry, size)),
+            false => None,
+        }
+    }
+
+    pub fn translate(&mut self, vaddr: VAddr) -> Option<PAddr> {
+        let mapping = self.lookup(vaddr)?;
+        Some(mapping.paddr + (vaddr.as_u64() & (mapping.size.bytes() - 1)))
+    }
+
+    // Replace the flags of the page containing `vaddr`
+    pub unsafe fn protect(&mut self, vaddr: VAddr, flags: PTFlags) -> Result<(), MapError> {
+        let (entry, size) = self.walk(vaddr);
+        if *entry & ENTRY_PRESENT == 0 {
+            return Err(MapError::NotMapped);
+        }
+        let addr = *entry & ENTRY_ADDR_MASK & !(size.bytes() - 1);
+        *entry = addr | ENTRY_PRESENT | match size {
+            PageSize::Size4K => flags.bits(),
+            _ => large_page_bits(flags),
+        };
+        tlb::flush(vaddr.as_usize());
+        Ok(())
+    }
+}
@@ -0,0 +1,152 @@
+use x86::bits64::paging::PAddr;
+use x86::io::{inb, inl, inw, outb, outl, outw};
+use crate::acpi::mcfg::Mcfg;
+use crate::memory::mmio;
+use crate::sync::SpinLock;
+use super::PciAddress;
+
+// https://wiki.osdev.org/PCI#Configuration_Space_Access_Mechanism_.231
+// https://wiki.osdev.org/PCI_Express#Enhanced_Configuration_Mechanism
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+const CONFIG_ENABLE: u32 = 1 << 31;
+
+// port I/O only reaches the first 256 bytes of a function, ECAM all 4096
+pub const LEGACY_CONFIG_SIZE: u16 = 256;
+pub const ECAM_CONFIG_SIZE: u16 = 4096;
+// one bus worth of ECAM: 32 devices of 8 functions of 4KiB
+const ECAM_BUS_SIZE: usize = 1 << 20;
+
+const MAX_ECAM_REGIONS: usize = 4;
+const MAX_BUSES: usize = 256;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Width {
+    Byte,
+    Word,
+    Dword,
+}
+
+// One MCFG entry. Buses get mapped the first time they're touched, so
+// buses with nothing on them cost nothing.
+#[derive(Clone, Copy)]
+struct EcamRegion {
+    segment: u16,
+    start_bus: u8,
+    end_bus: u8,
+    base: u64,
+    // virtual address of each bus, 0 if not mapped yet
+    buses: [u64; MAX_BUSES],
+}
+
+static LEGACY: SpinLock<()> = SpinLock::new(());
+static ECAM: SpinLock<[Option<EcamRegion>; MAX_ECAM_REGIONS]> = SpinLock::new([None; MAX_ECAM_REGIONS]);
+
+// Pick up the ECAM regions from the MCFG, returns how many there are
+pub fn init() -> usize {
+    let mcfg = match Mcfg::find() {
+        Some(mcfg) => mcfg,
+        None => return 0,
+    };
+    let mut ecam = ECAM.lock();
+    let mut count = 0;
+    for (slot, entry) in ecam.iter_mut().zip(mcfg.entries()) {
+        *slot = Some(EcamRegion {
+            segment: entry.segment,
+            start_bus: entry.start_bus,
+            end_bus: entry.end_bus,
+            base: entry.base_address,
+            buses: [0; MAX_BUSES],
+        });
+        count += 1;
+    }
+    count
+}
+
+// Segments and their bus ranges, from the MCFG
+pub fn ecam_segments() -> [Option<(u16, u8, u8)>; MAX_ECAM_REGIONS] {
+    let ecam = ECAM.lock();
+    let mut segments = [None; MAX_ECAM_REGIONS];
+    for (segment, region) in segments.iter_mut().zip(ecam.iter()) {
+        *segment = region.as_ref().map(|region| (region.segment, region.start_bus, region.end_bus));
+    }
+    segments
+}
+
+// Virtual address of a function's ECAM space, mapping its bus if needed
+fn ecam_address(address: PciAddress) -> Option<u64> {
+    let mut ecam = ECAM.lock();
+    let region = ecam.iter_mut().flatten().find(|region| {
+        region.segment == address.segment && (region.start_bus..=region.end_bus).contains(&address.bus)
+    })?;
+    let bus = address.bus as usize;
+    if region.buses[bus] == 0 {
+        let paddr = region.base + ((bus - region.start_bus as usize) * ECAM_BUS_SIZE) as u64;
+        region.buses[bus] = mmio::map_mmio(PAddr(paddr), ECAM_BUS_SIZE).ok()?.as_u64();
+    }
+    Some(region.buses[bus] + ((address.device as u64) << 15 | (address.function as u64) << 12))
+}
+
+fn legacy_address(address: PciAddress, offset: u16) -> u32 {
+    CONFIG_ENABLE
+        | (address.bus as u32) << 16
+        | (address.device as u32) << 11
+        | (address.function as u32) << 8
+        | (offset as u32 & 0xFC)
+}
+
+// Read `width` at `offset`, which has to be aligned to it. Functions that
+// aren't there read as all ones.
+pub fn read(address: PciAddress, offset: u16, width: Width) -> u32 {
+    if let Some(base) = ecam_address(address) {
+        let ptr = base + offset as u64;
+        return unsafe {
+            match width {
+                Width::Byte => core::ptr::read_volatile(ptr as *const u8) as u32,
+                Width::Word => core::ptr::read_volatile(ptr as *const u16) as u32,
+                Width::Dword => core::ptr::read_volatile(ptr as *const u32),
+            }
+        };
+    }
+    if address.segment != 0 || offset >= LEGACY_CONFIG_SIZE {
+        return u32::MAX;
+    }
+    let _legacy = LEGACY.lock();
+    let port = CONFIG_DATA + (offset & 3);
+    unsafe {
+        outl(CONFIG_ADDRESS, legacy_address(address, offset));
+        match width {
+            Width::Byte => inb(port) as u32,
+            Width::Word => inw(port) as u32,
+            Width::Dword => inl(port),
+        }
+    }
+}
+
+pub fn write(address: PciAddress, offset: u16, width: Width, value: u32) {
+    if let Some(base) = ecam_address(address) {
+        let ptr = base + offset as u64;
+        unsafe {
+            match width {
+                Width::Byte => core::ptr::write_volatile(ptr as *mut u8, value as u8),
+                Width::Word => core::ptr::write_volatile(ptr as *mut u16, value as u16),
+                Width::Dword => core::ptr::write_volatile(ptr as *mut u32, value),
+            }
+        }
+        return;
+    }
+    if address.segment != 0 || offset >= LEGACY_CONFIG_SIZE {
+        return;
+    }
+    let _legacy = LEGACY.lock();
+    let port = CONFIG_DATA + (offset & 3);
+    unsafe {
+        outl(CONFIG_ADDRESS, legacy_address(address, offset));
+        match width {
+            Width::Byte => outb(port, value as u8),
+            Width::Word => outw(port, value as u16),
+            Width::Dword => outl(port, value),
+        }
+    }
+}
@@ -0,0 +1,265 @@
+use core::fmt;
+use super::config::{self, Width};
+use super::PciAddress;
+
+// https://wiki.osdev.org/PCI#Configuration_Space
+
+pub const REG_VENDOR_ID: u16 = 0x00;
+pub const REG_DEVICE_ID: u16 = 0x02;
+pub const REG_COMMAND: u16 = 0x04;
+pub const REG_STATUS: u16 = 0x06;
+pub const REG_REVISION: u16 = 0x08;
+pub const REG_PROG_IF: u16 = 0x09;
+pub const REG_SUBCLASS: u16 = 0x0A;
+pub const REG_CLASS: u16 = 0x0B;
+pub const REG_HEADER_TYPE: u16 = 0x0E;
+pub const REG_BAR0: u16 = 0x10;
+pub const REG_SECONDARY_BUS: u16 = 0x19;
+pub const REG_SUBSYSTEM_VENDOR_ID: u16 = 0x2C;
+pub const REG_SUBSYSTEM_ID: u16 = 0x2E;
+pub const REG_CAPABILITIES: u16 = 0x34;
+pub const REG_INTERRUPT_LINE: u16 = 0x3C;
+pub const REG_INTERRUPT_PIN: u16 = 0x3D;
+
+pub const COMMAND_IO: u16 = 1 << 0;
+pub const COMMAND_MEMORY: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+pub const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+pub const HEADER_TYPE_MASK: u8 = 0x7F;
+pub const HEADER_MULTIFUNCTION: u8 = 1 << 7;
+pub const HEADER_GENERAL: u8 = 0x00;
+pub const HEADER_PCI_BRIDGE: u8 = 0x01;
+
+const BAR_IO: u32 = 1 << 0;
+const BAR_TYPE_MASK: u32 = 0b11 << 1;
+const BAR_TYPE_64BIT: u32 = 0b10 << 1;
+const BAR_PREFETCHABLE: u32 = 1 << 3;
+const BAR_IO_ADDRESS_MASK: u32 = !0b11;
+const BAR_MEMORY_ADDRESS_MASK: u32 = !0b1111;
+
+pub const CAP_POWER_MANAGEMENT: u8 = 0x01;
+pub const CAP_MSI: u8 = 0x05;
+pub const CAP_VENDOR: u8 = 0x09;
+pub const CAP_PCIE: u8 = 0x10;
+pub const CAP_MSIX: u8 = 0x11;
+// a broken list could point back at itself
+const MAX_CAPABILITIES: usize = 48;
+
+pub const VENDOR_NONE: u16 = 0xFFFF;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Bar {
+    Memory { address: u64, size: u64, prefetchable: bool, is_64bit: bool },
+    Io { port: u32, size: u32 },
+}
+
+impl Bar {
+    pub fn size(&self) -> u64 {
+        match *self {
+            Bar::Memory { size, .. } => size,
+            Bar::Io { size, .. } => size as u64,
+        }
+    }
+}
+
+// Offsets of the capabilities drivers care about, 0 for missing ones
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Capabilities {
+    pub power_management: u8,
+    pub msi: u8,
+    pub msix: u8,
+    pub pcie: u8,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: u8,
+    // general devices only
+    pub subsystem_vendor_id: u16,
+    pub subsystem_id: u16,
+    pub interrupt_line: u8,
+    // 1 to 4 for INTA# to INTD#, 0 for none
+    pub interrupt_pin: u8,
+    pub bars: [Option<Bar>; 6],
+    pub capabilities: Capabilities,
+}
+
+pub fn read_u8(address: PciAddress, offset: u16) -> u8 {
+    config::read(address, offset, Width::Byte) as u8
+}
+
+pub fn read_u16(address: PciAddress, offset: u16) -> u16 {
+    config::read(address, offset, Width::Word) as u16
+}
+
+pub fn read_u32(address: PciAddress, offset: u16) -> u32 {
+    config::read(address, offset, Width::Dword)
+}
+
+pub fn write_u8(address: PciAddress, offset: u16, value: u8) {
+    config::write(address, offset, Width::Byte, value as u32)
+}
+
+pub fn write_u16(address: PciAddress, offset: u16, value: u16) {
+    config::write(address, offset, Width::Word, value as u32)
+}
+
+pub fn write_u32(address: PciAddress, offset: u16, value: u32) {
+    config::write(address, offset, Width::Dword, value)
+}
+
+// Size a BAR by writing all ones and seeing which address bits stick.
+// Decoding is switched off meanwhile so the half written BAR can't claim
+// anyone else's addresses. Returns the BAR and how many slots it takes.
+fn probe_bar(address: PciAddress, index: usize, count: usize) -> (Option<Bar>, usize) {
+    let reg = REG_BAR0 + index as u16 * 4;
+    let low = read_u32(address, reg);
+    let command = read_u16(address, REG_COMMAND);
+    write_u16(address, REG_COMMAND, command & !(COMMAND_IO | COMMAND_MEMORY));
+
+    let result = if low & BAR_IO != 0 {
+        write_u32(address, reg, u32::MAX);
+        let mask = read_u32(address, reg) & BAR_IO_ADDRESS_MASK & 0xFFFF;
+        write_u32(address, reg, low);
+        let bar = (mask != 0).then_some(Bar::Io { port: low & BAR_IO_ADDRESS_MASK, size: (!mask & 0xFFFF) + 1 });
+        (bar, 1)
+    } else {
+        let is_64bit = low & BAR_TYPE_MASK == BAR_TYPE_64BIT && index + 1 < count;
+        let high = if is_64bit { read_u32(address, reg + 4) } else { 0 };
+        write_u32(address, reg, u32::MAX);
+        let mut mask = (read_u32(address, reg) & BAR_MEMORY_ADDRESS_MASK) as u64;
+        write_u32(address, reg, low);
+        if is_64bit {
+            write_u32(address, reg + 4, u32::MAX);
+            mask |= (read_u32(address, reg + 4) as u64) << 32;
+            write_u32(address, reg + 4, high);
+        }
+        // a BAR that isn't implemented keeps reading 0
+        let implemented = mask != 0;
+        if !is_64bit {
+            mask |= 0xFFFF_FFFF_0000_0000;
+        }
+        let bar = implemented.then(|| Bar::Memory {
+            address: (low & BAR_MEMORY_ADDRESS_MASK) as u64 | (high as u64) << 32,
+            size: (!mask).wrapping_add(1),
+            prefetchable: low & BAR_PREFETCHABLE != 0,
+            is_64bit,
+        });
+        (bar, if is_64bit { 2 } else { 1 })
+    };
+    write_u16(address, REG_COMMAND, command);
+    result
+}
+
+// Every capability as (id, offset)
+pub fn capabilities(address: PciAddress) -> impl Iterator<Item = (u8, u8)> {
+    let mut next = match read_u16(address, REG_STATUS) & STATUS_CAPABILITIES {
+        0 => 0,
+        _ => read_u8(address, REG_CAPABILITIES) & !0b11,
+    };
+    let mut seen = 0;
+    core::iter::from_fn(move || {
+        if next == 0 || seen == MAX_CAPABILITIES {
+            return None;
+        }
+        seen += 1;
+        let offset = next;
+        let id = read_u8(address, offset as u16);
+        next = read_u8(address, offset as u16 + 1) & !0b11;
+        Some((id, offset))
+    })
+}
+
+impl PciDevice {
+    // Decode the header of a function that's known to be there
+    pub fn read(address: PciAddress) -> Self {
+        let header_type = read_u8(address, REG_HEADER_TYPE);
+        let mut device = PciDevice {
+            address,
+            vendor_id: read_u16(address, REG_VENDOR_ID),
+            device_id: read_u16(address, REG_DEVICE_ID),
+            class: read_u8(address, REG_CLASS),
+            subclass: read_u8(address, REG_SUBCLASS),
+            prog_if: read_u8(address, REG_PROG_IF),
+            revision: read_u8(address, REG_REVISION),
+            header_type,
+            subsystem_vendor_id: 0,
+            subsystem_id: 0,
+            interrupt_line: read_u8(address, REG_INTERRUPT_LINE),
+            interrupt_pin: read_u8(address, REG_INTERRUPT_PIN),
+            bars: [None; 6],
+            capabilities: Capabilities::default(),
+        };
+        let bar_count = match header_type & HEADER_TYPE_MASK {
+            HEADER_GENERAL => {
+                device.subsystem_vendor_id = read_u16(address, REG_SUBSYSTEM_VENDOR_ID);
+                device.subsystem_id = read_u16(address, REG_SUBSYSTEM_ID);
+                6
+            }
+            HEADER_PCI_BRIDGE => 2,
+            _ => 0,
+        };
+        let mut index = 0;
+        while index < bar_count {
+            let (bar, slots) = probe_bar(address, index, bar_count);
+            device.bars[index] = bar;
+            index += slots;
+        }
+        for (id, offset) in capabilities(address) {
+            match id {
+                CAP_POWER_MANAGEMENT => device.capabilities.power_management = offset,
+                CAP_MSI => device.capabilities.msi = offset,
+                CAP_MSIX => device.capabilities.msix = offset,
+                CAP_PCIE => device.capabilities.pcie = offset,
+                _ => {}
+            }
+        }
+        device
+    }
+
+    pub fn is_multifunction(&self) -> bool {
+        self.header_type & HEADER_MULTIFUNCTION != 0
+    }
+
+    pub fn is_bridge(&self) -> bool {
+        self.header_type & HEADER_TYPE_MASK == HEADER_PCI_BRIDGE
+    }
+
+    pub fn secondary_bus(&self) -> u8 {
+        read_u8(self.address, REG_SECONDARY_BUS)
+    }
+
+    // Turn on decoding of the BARs, and DMA if the device needs it
+    pub fn enable(&self, bus_master: bool) {
+        let mut command = read_u16(self.address, REG_COMMAND) | COMMAND_IO | COMMAND_MEMORY;
+        if bus_master {
+            command |= COMMAND_BUS_MASTER;
+        }
+        write_u16(self.address, REG_COMMAND, command);
+    }
+
+    pub fn set_intx_disabled(&self, disabled: bool) {
+        let command = read_u16(self.address, REG_COMMAND);
+        write_u16(self.address, REG_COMMAND, match disabled {
+            true => command | COMMAND_INTX_DISABLE,
+            false => command & !COMMAND_INTX_DISABLE,
+        });
+    }
+}
+
+impl fmt::Display for PciDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {:04x}:{:04x} class {:02x}.{:02x}.{:02x}",
+            self.address, self.vendor_id, self.device_id, self.class, self.subclass, self.prog_if)
+    }
+}
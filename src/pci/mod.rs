@@ -0,0 +1,202 @@
+use alloc::vec::Vec;
+use core::fmt;
+use crate::sync::SpinLock;
+use device::{PciDevice, VENDOR_NONE};
+pub mod config;
+pub mod device;
+pub mod msi;
+
+// https://wiki.osdev.org/PCI
+
+pub const DEVICES_PER_BUS: u8 = 32;
+pub const FUNCTIONS_PER_DEVICE: u8 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciAddress {
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciAddress {
+    pub const fn new(segment: u16, bus: u8, device: u8, function: u8) -> Self {
+        PciAddress { segment, bus, device, function }
+    }
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04x}:{:02x}:{:02x}.{}", self.segment, self.bus, self.device, self.function)
+    }
+}
+
+// What a driver binds to; `None` fields match anything
+#[derive(Debug, Clone, Copy)]
+pub struct PciMatch {
+    pub vendor_id: Option<u16>,
+    pub device_id: Option<u16>,
+    pub class: Option<u8>,
+    pub subclass: Option<u8>,
+    pub prog_if: Option<u8>,
+}
+
+impl PciMatch {
+    pub const fn device(vendor_id: u16, device_id: u16) -> Self {
+        PciMatch { vendor_id: Some(vendor_id), device_id: Some(device_id), class: None, subclass: None, prog_if: None }
+    }
+
+    pub const fn vendor(vendor_id: u16) -> Self {
+        PciMatch { vendor_id: Some(vendor_id), device_id: None, class: None, subclass: None, prog_if: None }
+    }
+
+    pub const fn class(class: u8, subclass: u8) -> Self {
+        PciMatch { vendor_id: None, device_id: None, class: Some(class), subclass: Some(subclass), prog_if: None }
+    }
+
+    pub fn matches(&self, device: &PciDevice) -> bool {
+        self.vendor_id.map_or(true, |id| id == device.vendor_id)
+            && self.device_id.map_or(true, |id| id == device.device_id)
+            && self.class.map_or(true, |class| class == device.class)
+            && self.subclass.map_or(true, |subclass| subclass == device.subclass)
+            && self.prog_if.map_or(true, |prog_if| prog_if == device.prog_if)
+    }
+}
+
+pub struct PciDriver {
+    pub name: &'static str,
+    pub matches: &'static [PciMatch],
+    // returns whether the driver took the device
+    pub probe: fn(&PciDevice) -> bool,
+}
+
+struct Entry {
+    device: PciDevice,
+    driver: Option<&'static str>,
+}
+
+struct Registry {
+    devices: Vec<Entry>,
+    drivers: Vec<&'static PciDriver>,
+}
+
+static REGISTRY: SpinLock<Registry> = SpinLock::new(Registry { devices: Vec::new(), drivers: Vec::new() });
+
+fn exists(address: PciAddress) -> bool {
+    device::read_u16(address, device::REG_VENDOR_ID) != VENDOR_NONE
+}
+
+// Depth first from `bus`, following bridges to the buses behind them.
+// `visited` stops misconfigured bridges from sending the scan in circles.
+fn scan_bus(segment: u16, bus: u8, visited: &mut [bool; 256], found: &mut Vec<PciDevice>) {
+    if visited[bus as usize] {
+        return;
+    }
+    visited[bus as usize] = true;
+    for slot in 0..DEVICES_PER_BUS {
+        let address = PciAddress::new(segment, bus, slot, 0);
+        if !exists(address) {
+            continue;
+        }
+        let first = PciDevice::read(address);
+        let functions = if first.is_multifunction() { FUNCTIONS_PER_DEVICE } else { 1 };
+        for function in 0..functions {
+            let address = PciAddress::new(segment, bus, slot, function);
+            if function != 0 && !exists(address) {
+                continue;
+            }
+            let device = if function == 0 { first } else { PciDevice::read(address) };
+            if device.is_bridge() {
+                scan_bus(segment, device.secondary_bus(), visited, found);
+            }
+            found.push(device);
+        }
+    }
+}
+
+// Every function on a segment. Each function of a multifunction host bridge
+// at 00.0 is a host controller of its own, with the bus of that number.
+fn scan_segment(segment: u16, start_bus: u8, found: &mut Vec<PciDevice>) {
+    let mut visited = [false; 256];
+    let host = PciAddress::new(segment, start_bus, 0, 0);
+    if !exists(host) {
+        return;
+    }
+    let buses = match PciDevice::read(host).is_multifunction() {
+        true => FUNCTIONS_PER_DEVICE,
+        false => 1,
+    };
+    for function in 0..buses {
+        if !exists(PciAddress::new(segment, start_bus, 0, function)) {
+            continue;
+        }
+        if let Some(bus) = start_bus.checked_add(function) {
+            scan_bus(segment, bus, &mut visited, found);
+        }
+    }
+}
+
+// Offer every matching unbound device to `driver`. Probes run without the
+// registry locked, they're free to look things up in it.
+fn bind(driver: &'static PciDriver) {
+    let candidates: Vec<PciDevice> = REGISTRY.lock().devices.iter()
+        .filter(|entry| entry.driver.is_none())
+        .filter(|entry| driver.matches.iter().any(|m| m.matches(&entry.device)))
+        .map(|entry| entry.device)
+        .collect();
+    for device in candidates {
+        if !(driver.probe)(&device) {
+            continue;
+        }
+        info!("{} bound to {}", driver.name, device.address);
+        let mut registry = REGISTRY.lock();
+        if let Some(entry) = registry.devices.iter_mut().find(|entry| entry.device.address == device.address) {
+            entry.driver = Some(driver.name);
+        }
+    }
+}
+
+pub fn register_driver(driver: &'static PciDriver) {
+    REGISTRY.lock().drivers.push(driver);
+    bind(driver);
+}
+
+// Every function on a bus that matches `m`
+pub fn find(m: &PciMatch) -> Vec<PciDevice> {
+    REGISTRY.lock().devices.iter().map(|entry| entry.device).filter(|device| m.matches(device)).collect()
+}
+
+pub fn get(address: PciAddress) -> Option<PciDevice> {
+    REGISTRY.lock().devices.iter().map(|entry| entry.device).find(|device| device.address == address)
+}
+
+pub fn devices() -> Vec<PciDevice> {
+    REGISTRY.lock().devices.iter().map(|entry| entry.device).collect()
+}
+
+// Enumerate everything through ECAM where the MCFG lists it, and segment 0
+// through the legacy ports otherwise, then hand it to registered drivers
+pub fn init() {
+    let regions = config::init();
+    let mut found = Vec::new();
+    match regions {
+        0 => scan_segment(0, 0, &mut found),
+        _ => {
+            for (segment, start_bus, _) in config::ecam_segments().into_iter().flatten() {
+                scan_segment(segment, start_bus, &mut found);
+            }
+        }
+    }
+    info!("PCI: {} functions, {}", found.len(), if regions == 0 { "port I/O" } else { "ECAM" });
+    for device in &found {
+        debug!("PCI {}", device);
+    }
+    let drivers = {
+        let mut registry = REGISTRY.lock();
+        registry.devices = found.into_iter().map(|device| Entry { device, driver: None }).collect();
+        registry.drivers.clone()
+    };
+    for driver in drivers {
+        bind(driver);
+    }
+}
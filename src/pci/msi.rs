@@ -0,0 +1,301 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use x86::bits64::paging::{PAddr, VAddr};
+use crate::apic;
+use crate::interrupts::{self, IntHandler, IrqError};
+use crate::memory::mmio;
+use crate::memory::vmm::MapError;
+use crate::sync::SpinLock;
+use super::device::{self, Bar, PciDevice, COMMAND_INTX_DISABLE, REG_COMMAND};
+use super::PciAddress;
+
+// https://wiki.osdev.org/PCI#Message_Signaled_Interrupts
+// PCI Local Bus specification 3.0, section 6.8
+// Intel SDM vol. 3A, section 10.11
+
+// message address: fixed delivery to one local APIC in physical mode
+const MSI_ADDRESS_BASE: u32 = 0xFEE0_0000;
+const MSI_ADDRESS_DEST_SHIFT: u32 = 12;
+
+// MSI capability
+const MSI_CONTROL: u16 = 0x02;
+const MSI_ADDRESS_LOW: u16 = 0x04;
+const MSI_ADDRESS_HIGH: u16 = 0x08;
+const MSI_CONTROL_ENABLE: u16 = 1 << 0;
+// multiple message enable, we only ever use one
+const MSI_CONTROL_MME_MASK: u16 = 0b111 << 4;
+const MSI_CONTROL_64BIT: u16 = 1 << 7;
+const MSI_CONTROL_PER_VECTOR_MASK: u16 = 1 << 8;
+
+// MSI-X capability
+const MSIX_CONTROL: u16 = 0x02;
+const MSIX_TABLE: u16 = 0x04;
+const MSIX_CONTROL_TABLE_SIZE_MASK: u16 = 0x7FF;
+const MSIX_CONTROL_FUNCTION_MASK: u16 = 1 << 14;
+const MSIX_CONTROL_ENABLE: u16 = 1 << 15;
+const MSIX_BIR_MASK: u32 = 0b111;
+
+// MSI-X table entries
+const MSIX_ENTRY_SIZE: u64 = 16;
+const MSIX_ENTRY_ADDRESS_LOW: u64 = 0x0;
+const MSIX_ENTRY_ADDRESS_HIGH: u64 = 0x4;
+const MSIX_ENTRY_DATA: u64 = 0x8;
+const MSIX_ENTRY_CONTROL: u64 = 0xC;
+const MSIX_ENTRY_MASKED: u32 = 1 << 0;
+
+#[derive(Debug)]
+pub enum MsiError {
+    NotSupported,
+    // MSI or the MSI-X entry is on already, free it first
+    AlreadyEnabled,
+    // nothing was requested there, so there's nothing to free
+    NotRequested,
+    NoLocalApic,
+    // the message address only has room for 8-bit APIC ids
+    DestinationTooHigh(u32),
+    NoSuchEntry(u16),
+    BadTableBar(u8),
+    Map(MapError),
+    Irq(IrqError),
+}
+
+// A device's MSI-X table, mapped the first time it's needed
+struct MsixTable {
+    address: PciAddress,
+    base: VAddr,
+    entries: u16,
+    // vector each entry was given, so only those we handed out go back
+    vectors: Vec<Option<u8>>,
+}
+
+static MSIX_TABLES: SpinLock<Vec<MsixTable>> = SpinLock::new(Vec::new());
+// functions with MSI on and the vector they got
+static MSI_VECTORS: SpinLock<Vec<(PciAddress, u8)>> = SpinLock::new(Vec::new());
+
+fn message(vector: u8, apic_id: u8) -> (u32, u32) {
+    (MSI_ADDRESS_BASE | (apic_id as u32) << MSI_ADDRESS_DEST_SHIFT, vector as u32)
+}
+
+// This CPU's local APIC, where interrupts go unless asked otherwise
+fn this_cpu() -> Result<u8, MsiError> {
+    let id = apic::local_apic().ok_or(MsiError::NoLocalApic)?.id();
+    u8::try_from(id).map_err(|_| MsiError::DestinationTooHigh(id))
+}
+
+// For handlers of message signalled interrupts, which always come through
+// the local APIC whatever the active `irqchip` is
+pub fn eoi() {
+    if let Some(lapic) = apic::local_apic() {
+        lapic.eoi();
+    }
+}
+
+// MSI registers past the address move up a dword for 64-bit capable functions
+fn msi_data_offset(control: u16) -> u16 {
+    if control & MSI_CONTROL_64BIT != 0 { 0x0C } else { 0x08 }
+}
+
+fn msi_mask_offset(control: u16) -> u16 {
+    msi_data_offset(control) + 4
+}
+
+// Deliver the function's single MSI message on a fresh vector running
+// `handler`, on the local APIC `apic_id`
+pub fn request_msi_on(dev: &PciDevice, handler: IntHandler, apic_id: u8) -> Result<u8, MsiError> {
+    let cap = dev.capabilities.msi as u16;
+    if cap == 0 {
+        return Err(MsiError::NotSupported);
+    }
+    let address = dev.address;
+    let mut vectors = MSI_VECTORS.lock();
+    let control = device::read_u16(address, cap + MSI_CONTROL);
+    if control & MSI_CONTROL_ENABLE != 0 || vectors.iter().any(|&(owner, _)| owner == address) {
+        return Err(MsiError::AlreadyEnabled);
+    }
+    let vector = interrupts::allocate_irq(handler).map_err(MsiError::Irq)?;
+    vectors.push((address, vector));
+    let (msg_address, data) = message(vector, apic_id);
+    device::write_u16(address, cap + MSI_CONTROL, control & !(MSI_CONTROL_ENABLE | MSI_CONTROL_MME_MASK));
+    device::write_u32(address, cap + MSI_ADDRESS_LOW, msg_address);
+    if control & MSI_CONTROL_64BIT != 0 {
+        device::write_u32(address, cap + MSI_ADDRESS_HIGH, 0);
+    }
+    device::write_u16(address, cap + msi_data_offset(control), data as u16);
+    if control & MSI_CONTROL_PER_VECTOR_MASK != 0 {
+        device::write_u32(address, cap + msi_mask_offset(control), 0);
+    }
+    // messages are memory writes, the device has to be allowed to make them
+    dev.enable(true);
+    disable_intx(address);
+    device::write_u16(address, cap + MSI_CONTROL, (control & !MSI_CONTROL_MME_MASK) | MSI_CONTROL_ENABLE);
+    Ok(vector)
+}
+
+pub fn request_msi(dev: &PciDevice, handler: IntHandler) -> Result<u8, MsiError> {
+    request_msi_on(dev, handler, this_cpu()?)
+}
+
+// Only for functions with per-vector masking, the rest can't be masked at the source
+pub fn mask_msi(dev: &PciDevice, masked: bool) -> Result<(), MsiError> {
+    let cap = dev.capabilities.msi as u16;
+    let control = match cap {
+        0 => return Err(MsiError::NotSupported),
+        _ => device::read_u16(dev.address, cap + MSI_CONTROL),
+    };
+    if control & MSI_CONTROL_PER_VECTOR_MASK == 0 {
+        return Err(MsiError::NotSupported);
+    }
+    device::write_u32(dev.address, cap + msi_mask_offset(control), masked as u32);
+    Ok(())
+}
+
+pub fn free_msi(dev: &PciDevice) -> Result<(), MsiError> {
+    let cap = dev.capabilities.msi as u16;
+    if cap == 0 {
+        return Err(MsiError::NotSupported);
+    }
+    let mut vectors = MSI_VECTORS.lock();
+    let index = vectors.iter().position(|&(owner, _)| owner == dev.address).ok_or(MsiError::NotRequested)?;
+    let (_, vector) = vectors.swap_remove(index);
+    let control = device::read_u16(dev.address, cap + MSI_CONTROL);
+    device::write_u16(dev.address, cap + MSI_CONTROL, control & !MSI_CONTROL_ENABLE);
+    device::write_u16(dev.address, cap + msi_data_offset(control), 0);
+    interrupts::unregister_irq(vector).map_err(MsiError::Irq)
+}
+
+fn disable_intx(address: PciAddress) {
+    let command = device::read_u16(address, REG_COMMAND);
+    device::write_u16(address, REG_COMMAND, command | COMMAND_INTX_DISABLE);
+}
+
+// Run `f` on the function's MSI-X table. The first time around the table
+// gets mapped and MSI-X switched on with every entry masked.
+fn with_msix_table<T, F>(dev: &PciDevice, f: F) -> Result<T, MsiError>
+    where F: FnOnce(&mut MsixTable) -> Result<T, MsiError>
+{
+    let cap = dev.capabilities.msix as u16;
+    if cap == 0 {
+        return Err(MsiError::NotSupported);
+    }
+    let mut tables = MSIX_TABLES.lock();
+    if let Some(table) = tables.iter_mut().find(|table| table.address == dev.address) {
+        return f(table);
+    }
+    let address = dev.address;
+    let control = device::read_u16(address, cap + MSIX_CONTROL);
+    let entries = (control & MSIX_CONTROL_TABLE_SIZE_MASK) + 1;
+    let location = device::read_u32(address, cap + MSIX_TABLE);
+    let bir = (location & MSIX_BIR_MASK) as u8;
+    let bar_address = match dev.bars.get(bir as usize).copied().flatten() {
+        Some(Bar::Memory { address, .. }) => address,
+        _ => return Err(MsiError::BadTableBar(bir)),
+    };
+    let paddr = PAddr(bar_address + (location & !MSIX_BIR_MASK) as u64);
+    let base = mmio::map_mmio(paddr, entries as usize * MSIX_ENTRY_SIZE as usize).map_err(MsiError::Map)?;
+    let mut table = MsixTable { address, base, entries, vectors: vec![None; entries as usize] };
+
+    // the device needs to answer memory accesses to its table, and to be
+    // allowed to write messages
+    dev.enable(true);
+    device::write_u16(address, cap + MSIX_CONTROL, control | MSIX_CONTROL_ENABLE | MSIX_CONTROL_FUNCTION_MASK);
+    for entry in 0..entries {
+        table.write(entry, MSIX_ENTRY_CONTROL, MSIX_ENTRY_MASKED);
+    }
+    disable_intx(address);
+    device::write_u16(address, cap + MSIX_CONTROL, (control | MSIX_CONTROL_ENABLE) & !MSIX_CONTROL_FUNCTION_MASK);
+    let result = f(&mut table);
+    tables.push(table);
+    result
+}
+
+impl MsixTable {
+    fn entry(&self, entry: u16, reg: u64) -> *mut u32 {
+        (self.base.as_u64() + entry as u64 * MSIX_ENTRY_SIZE + reg) as *mut u32
+    }
+
+    fn read(&self, entry: u16, reg: u64) -> u32 {
+        unsafe { core::ptr::read_volatile(self.entry(entry, reg)) }
+    }
+
+    fn write(&self, entry: u16, reg: u64, value: u32) {
+        unsafe { core::ptr::write_volatile(self.entry(entry, reg), value) }
+    }
+
+    fn check(&self, entry: u16) -> Result<(), MsiError> {
+        match entry < self.entries {
+            true => Ok(()),
+            false => Err(MsiError::NoSuchEntry(entry)),
+        }
+    }
+}
+
+// Whether MSI-X is switched on, by us or anyone else
+pub fn msix_enabled(dev: &PciDevice) -> bool {
+    let cap = dev.capabilities.msix as u16;
+    cap != 0 && device::read_u16(dev.address, cap + MSIX_CONTROL) & MSIX_CONTROL_ENABLE != 0
+}
+
+pub fn msix_entries(dev: &PciDevice) -> Result<u16, MsiError> {
+    with_msix_table(dev, |table| Ok(table.entries))
+}
+
+// Point MSI-X table entry `entry` at a fresh vector running `handler`, on
+// the local APIC `apic_id`. The entry is left unmasked.
+pub fn request_msix_on(dev: &PciDevice, entry: u16, handler: IntHandler, apic_id: u8) -> Result<u8, MsiError> {
+    with_msix_table(dev, |table| {
+        table.check(entry)?;
+        if table.vectors[entry as usize].is_some() {
+            return Err(MsiError::AlreadyEnabled);
+        }
+        let vector = interrupts::allocate_irq(handler).map_err(MsiError::Irq)?;
+        table.vectors[entry as usize] = Some(vector);
+        let (address, data) = message(vector, apic_id);
+        table.write(entry, MSIX_ENTRY_CONTROL, MSIX_ENTRY_MASKED);
+        table.write(entry, MSIX_ENTRY_ADDRESS_LOW, address);
+        table.write(entry, MSIX_ENTRY_ADDRESS_HIGH, 0);
+        table.write(entry, MSIX_ENTRY_DATA, data);
+        table.write(entry, MSIX_ENTRY_CONTROL, 0);
+        Ok(vector)
+    })
+}
+
+// Deliver MSI-X entry `n` of `dev` to `handler` on this CPU, returns the vector
+pub fn request_msix(dev: &PciDevice, n: u16, handler: IntHandler) -> Result<u8, MsiError> {
+    request_msix_on(dev, n, handler, this_cpu()?)
+}
+
+pub fn mask_msix(dev: &PciDevice, entry: u16, masked: bool) -> Result<(), MsiError> {
+    with_msix_table(dev, |table| {
+        table.check(entry)?;
+        let control = table.read(entry, MSIX_ENTRY_CONTROL);
+        table.write(entry, MSIX_ENTRY_CONTROL, match masked {
+            true => control | MSIX_ENTRY_MASKED,
+            false => control & !MSIX_ENTRY_MASKED,
+        });
+        Ok(())
+    })
+}
+
+// Send an entry's interrupts to another local APIC, keeping its vector
+pub fn set_msix_target(dev: &PciDevice, entry: u16, apic_id: u8) -> Result<(), MsiError> {
+    with_msix_table(dev, |table| {
+        table.check(entry)?;
+        let control = table.read(entry, MSIX_ENTRY_CONTROL);
+        let (address, _) = message(0, apic_id);
+        table.write(entry, MSIX_ENTRY_CONTROL, control | MSIX_ENTRY_MASKED);
+        table.write(entry, MSIX_ENTRY_ADDRESS_LOW, address);
+        table.write(entry, MSIX_ENTRY_CONTROL, control);
+        Ok(())
+    })
+}
+
+// Mask the entry and give its vector back
+pub fn free_msix(dev: &PciDevice, entry: u16) -> Result<(), MsiError> {
+    with_msix_table(dev, |table| {
+        table.check(entry)?;
+        let vector = table.vectors[entry as usize].take().ok_or(MsiError::NotRequested)?;
+        table.write(entry, MSIX_ENTRY_CONTROL, MSIX_ENTRY_MASKED);
+        table.write(entry, MSIX_ENTRY_DATA, 0);
+        interrupts::unregister_irq(vector).map_err(MsiError::Irq)
+    })
+}
@@ -1,4 +1,5 @@
 use x86::io::{inb, outb};
+use crate::irqchip::{InterruptController, RouteError, ISA_IRQS, ISA_VECTOR_BASE};
 
 // https://wiki.osdev.org/PIC
 
@@ -82,3 +83,43 @@ pub fn pic2_end_of_intr() {
         outb(PIC2_CMD, 0x20);
     }
 }
+
+const CASCADE_IRQ: u8 = 2;
+
+// The pair of 8259s as remapped by `remap_pic`
+pub struct Pic8259;
+
+impl InterruptController for Pic8259 {
+    fn name(&self) -> &'static str {
+        "8259 PIC"
+    }
+
+    fn mask(&self, irq: u8) {
+        set_pic_irq_line(irq);
+    }
+
+    fn unmask(&self, irq: u8) {
+        // slave lines go nowhere unless the master lets the cascade through
+        if irq >= 8 {
+            clear_pic_iqr_line(CASCADE_IRQ);
+        }
+        clear_pic_iqr_line(irq);
+    }
+
+    fn eoi(&self, irq: u8) {
+        if irq >= 8 {
+            pic2_end_of_intr();
+        }
+        pic1_end_of_intr();
+    }
+
+    fn route(&self, irq: u8, vector: u8) -> Result<(), RouteError> {
+        if irq >= ISA_IRQS {
+            return Err(RouteError::NoSuchIrq(irq));
+        }
+        match vector == ISA_VECTOR_BASE + irq {
+            true => Ok(()),
+            false => Err(RouteError::FixedVector { irq, vector }),
+        }
+    }
+}
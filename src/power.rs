@@ -0,0 +1,274 @@
+use core::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+use x86::bits64::paging::PAddr;
+use x86::dtables::{self, DescriptorTablePointer};
+use x86::io::{inw, outb, outl, outw};
+use crate::acpi::dsdt::Dsdt;
+use crate::acpi::fadt::{Fadt, FLAG_RESET_REG_SUPPORTED};
+use crate::acpi::{GenericAddress, ADDRESS_SPACE_IO, ADDRESS_SPACE_MEMORY};
+use crate::memory::mmio;
+use crate::ps2;
+
+// https://wiki.osdev.org/Shutdown
+// https://wiki.osdev.org/Reboot
+// ACPI 6.5 specification, sections 4.8.3 and 7.4.2
+
+// PM1 control register
+const PM1_SCI_ENABLE: u16 = 1 << 0;
+const PM1_SLEEP_TYPE_SHIFT: u16 = 10;
+const PM1_SLEEP_TYPE_MASK: u16 = 0b111 << PM1_SLEEP_TYPE_SHIFT;
+const PM1_SLEEP_ENABLE: u16 = 1 << 13;
+
+// AML bytes around the `Name (_S5, Package () { SLP_TYPa, SLP_TYPb, ... })`
+const AML_NAME_OP: u8 = 0x08;
+const AML_ROOT_PREFIX: u8 = 0x5C;
+const AML_PACKAGE_OP: u8 = 0x12;
+const AML_ZERO_OP: u8 = 0x00;
+const AML_ONE_OP: u8 = 0x01;
+const AML_BYTE_PREFIX: u8 = 0x0A;
+
+const ADDRESS_SPACE_PCI_CONFIG: u8 = 2;
+const PCI_CONFIG_ADDRESS: u16 = 0xCF8;
+const PCI_CONFIG_DATA: u16 = 0xCFC;
+const PCI_CONFIG_ENABLE: u32 = 1 << 31;
+
+const ACPI_ENABLE_SPINS: usize = 1_000_000;
+// how long a reset or power off request gets to take effect
+const RESET_SPINS: usize = 10_000_000;
+
+// QEMU's `-device isa-debug-exit,iobase=0xf4,iosize=0x04`; writing `code`
+// makes QEMU exit with `(code << 1) | 1`
+pub const QEMU_DEBUG_EXIT_PORT: u16 = 0xF4;
+pub const QEMU_EXIT_SUCCESS: u32 = 0x10;
+pub const QEMU_EXIT_FAILURE: u32 = 0x11;
+
+#[derive(Debug)]
+pub enum PowerError {
+    NoFadt,
+    HardwareReduced,
+    NoPm1Control,
+    NoS5,
+    AcpiEnableTimeout,
+    // the sleep request went out and nothing happened
+    StillRunning,
+}
+
+// What kmain does once it's done, from `finish=` on the kernel command line
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum Finish {
+    Idle = 0,
+    Shutdown = 1,
+    Reboot = 2,
+    QemuExit = 3,
+}
+
+static FINISH: AtomicU8 = AtomicU8::new(Finish::Idle as u8);
+static FINISH_EXIT_CODE: AtomicU32 = AtomicU32::new(QEMU_EXIT_SUCCESS);
+
+// Find SLP_TYPa and SLP_TYPb of the \_S5 object without an AML interpreter,
+// relying on firmware declaring it the usual, simple way
+fn parse_s5(aml: &[u8]) -> Option<(u8, u8)> {
+    let name = aml.windows(4).position(|w| w == b"_S5_")?;
+    let declared = match name {
+        0 => false,
+        1 => aml[0] == AML_NAME_OP,
+        _ => aml[name - 1] == AML_NAME_OP || (aml[name - 1] == AML_ROOT_PREFIX && aml[name - 2] == AML_NAME_OP),
+    };
+    if !declared || *aml.get(name + 4)? != AML_PACKAGE_OP {
+        return None;
+    }
+    // PkgLength says in its top two bits how many more bytes it has
+    let pkg_length = name + 5;
+    let elements = pkg_length + 1 + (*aml.get(pkg_length)? >> 6) as usize;
+    let mut i = elements + 1;
+    let mut element = || {
+        let value = match *aml.get(i)? {
+            AML_ZERO_OP => 0,
+            AML_ONE_OP => 1,
+            AML_BYTE_PREFIX => {
+                i += 1;
+                *aml.get(i)?
+            }
+            other => other,
+        };
+        i += 1;
+        Some(value)
+    };
+    let a = element()?;
+    let b = element()?;
+    Some((a, b))
+}
+
+// Legacy I/O port of a PM1 block, the 64-bit field if the FADT has it
+fn pm1_port(x_block: GenericAddress, block: u32) -> Option<u16> {
+    let address = match x_block.address_space == ADDRESS_SPACE_IO && x_block.address != 0 {
+        true => x_block.address,
+        false => block as u64,
+    };
+    Some(address as u16).filter(|&port| port != 0)
+}
+
+// Firmware starts out in legacy mode on some machines, and sleep
+// requests are ignored until the OS asks SMM to hand over
+fn enable_acpi(fadt: &Fadt, pm1a: u16) -> Result<(), PowerError> {
+    if unsafe { inw(pm1a) } & PM1_SCI_ENABLE != 0 || fadt.smi_command == 0 || fadt.acpi_enable == 0 {
+        return Ok(());
+    }
+    unsafe { outb(fadt.smi_command as u16, fadt.acpi_enable); }
+    for _ in 0..ACPI_ENABLE_SPINS {
+        if unsafe { inw(pm1a) } & PM1_SCI_ENABLE != 0 {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err(PowerError::AcpiEnableTimeout)
+}
+
+fn write_sleep_type(port: u16, sleep_type: u8) {
+    unsafe {
+        let value = inw(port) & !PM1_SLEEP_TYPE_MASK;
+        outw(port, value | (sleep_type as u16) << PM1_SLEEP_TYPE_SHIFT | PM1_SLEEP_ENABLE);
+    }
+}
+
+// Enter S5 (soft off). Only returns if that didn't work.
+pub fn shutdown() -> Result<(), PowerError> {
+    let fadt = Fadt::find().ok_or(PowerError::NoFadt)?;
+    if fadt.is_hardware_reduced() {
+        return Err(PowerError::HardwareReduced);
+    }
+    let pm1a = pm1_port(fadt.x_pm1a_control_block, fadt.pm1a_control_block).ok_or(PowerError::NoPm1Control)?;
+    let pm1b = pm1_port(fadt.x_pm1b_control_block, fadt.pm1b_control_block);
+    let (sleep_a, sleep_b) = Dsdt::find().and_then(|dsdt| parse_s5(dsdt.aml())).ok_or(PowerError::NoS5)?;
+    enable_acpi(&fadt, pm1a)?;
+    info!("powering off");
+    unsafe { x86::irq::disable(); }
+    write_sleep_type(pm1a, sleep_a);
+    if let Some(pm1b) = pm1b {
+        write_sleep_type(pm1b, sleep_b);
+    }
+    wait_for_reset();
+    Err(PowerError::StillRunning)
+}
+
+fn write_reset_register(reg: GenericAddress, value: u8) {
+    match reg.address_space {
+        ADDRESS_SPACE_IO => unsafe { outb(reg.address as u16, value) },
+        ADDRESS_SPACE_MEMORY => {
+            if let Ok(vaddr) = mmio::map_mmio(PAddr(reg.address), 1) {
+                unsafe { core::ptr::write_volatile(vaddr.as_mut_ptr::<u8>(), value); }
+            }
+        }
+        // bus 0: device in bits 32..48, function in 16..32, register offset in 0..16
+        ADDRESS_SPACE_PCI_CONFIG => {
+            let device = (reg.address >> 32) as u32 & 0x1F;
+            let function = (reg.address >> 16) as u32 & 0x7;
+            let offset = reg.address as u32 & 0xFF;
+            unsafe {
+                outl(PCI_CONFIG_ADDRESS, PCI_CONFIG_ENABLE | device << 11 | function << 8 | (offset & !3));
+                outb(PCI_CONFIG_DATA + (offset & 3) as u16, value);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn wait_for_reset() {
+    for _ in 0..RESET_SPINS {
+        core::hint::spin_loop();
+    }
+}
+
+// An IDT with nothing in it turns the next interrupt into a triple fault
+fn triple_fault() -> ! {
+    let idt: DescriptorTablePointer<u64> = DescriptorTablePointer { limit: 0, base: core::ptr::null() };
+    unsafe {
+        dtables::lidt(&idt);
+        core::arch::asm!("int3");
+        loop {
+            x86::halt();
+        }
+    }
+}
+
+// Reset the machine: the FADT reset register, then the 8042's reset line,
+// then a triple fault, which works everywhere
+pub fn reboot() -> ! {
+    info!("rebooting");
+    unsafe { x86::irq::disable(); }
+    if let Some(fadt) = Fadt::find().filter(|fadt| fadt.flags & FLAG_RESET_REG_SUPPORTED != 0) {
+        write_reset_register(fadt.reset_register, fadt.reset_value);
+        wait_for_reset();
+    }
+    if ps2::pulse_reset().is_ok() {
+        wait_for_reset();
+    }
+    triple_fault()
+}
+
+// Make QEMU exit with `(code << 1) | 1`. Returns when not running under
+// QEMU with the isa-debug-exit device.
+pub fn qemu_exit(code: u32) {
+    unsafe { outl(QEMU_DEBUG_EXIT_PORT, code); }
+}
+
+// Take `finish=shutdown`, `finish=reboot` or `finish=qemu-exit[:code]` from
+// the kernel command line
+pub fn init(cmdline: &str) {
+    let spec = match cmdline.split_whitespace().find_map(|opt| opt.strip_prefix("finish=")) {
+        Some(spec) => spec,
+        None => return,
+    };
+    let (action, code) = match spec.split_once(':') {
+        Some((action, code)) => (action, Some(code)),
+        None => (spec, None),
+    };
+    let finish = match action {
+        "idle" => Finish::Idle,
+        "shutdown" => Finish::Shutdown,
+        "reboot" => Finish::Reboot,
+        "qemu-exit" => Finish::QemuExit,
+        _ => {
+            warn!("unknown finish action '{}'", spec);
+            return;
+        }
+    };
+    if let Some(code) = code {
+        match code.parse() {
+            Ok(code) => FINISH_EXIT_CODE.store(code, Ordering::Relaxed),
+            Err(_) => warn!("bad exit code '{}'", code),
+        }
+    }
+    FINISH.store(finish as u8, Ordering::Relaxed);
+}
+
+pub fn finish_action() -> Finish {
+    match FINISH.load(Ordering::Relaxed) {
+        1 => Finish::Shutdown,
+        2 => Finish::Reboot,
+        3 => Finish::QemuExit,
+        _ => Finish::Idle,
+    }
+}
+
+// Called once kmain has nothing left to do. Returns for `Finish::Idle`, or
+// if the chosen way out didn't work.
+pub fn finish() {
+    match finish_action() {
+        Finish::Idle => {}
+        Finish::Shutdown => {
+            if let Err(e) = shutdown() {
+                warn!("power off failed: {:?}", e);
+            }
+        }
+        Finish::Reboot => reboot(),
+        Finish::QemuExit => qemu_exit(FINISH_EXIT_CODE.load(Ordering::Relaxed)),
+    }
+}
+
+// For the panic handler: automated QEMU runs should end, and as a failure
+pub fn panic_exit() {
+    if finish_action() == Finish::QemuExit {
+        qemu_exit(QEMU_EXIT_FAILURE);
+    }
+}
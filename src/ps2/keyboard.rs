@@ -0,0 +1,514 @@
+use x86::io::inb;
+use crate::interrupts::{InterruptStackFrame, IDT};
+use crate::irqchip;
+use crate::sync::SpinLock;
+use super::keymap::{KeyCode, Layout, Modifiers};
+use super::{EventQueue, Port, Ps2Error, DEVICE_ACK, DEVICE_RESEND, EVENT_QUEUE_SIZE};
+
+// https://wiki.osdev.org/PS/2_Keyboard
+
+const KEYBOARD_IRQ: u8 = 1;
+const DATA: u16 = 0x60;
+
+const CMD_SET_LEDS: u8 = 0xED;
+const CMD_SCANCODE_SET: u8 = 0xF0;
+const CMD_ENABLE_SCANNING: u8 = 0xF4;
+
+const LED_SCROLL_LOCK: u8 = 1 << 0;
+const LED_NUM_LOCK: u8 = 1 << 1;
+const LED_CAPS_LOCK: u8 = 1 << 2;
+
+const PREFIX_EXTENDED: u8 = 0xE0;
+const PREFIX_PAUSE: u8 = 0xE1;
+const SET2_PREFIX_RELEASE: u8 = 0xF0;
+const SET1_RELEASE: u8 = 0x80;
+// Pause sends E1 and these many more bytes, press only, no release
+const SET1_PAUSE_TAIL: u8 = 5;
+const SET2_PAUSE_TAIL: u8 = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScancodeSet {
+    Set1,
+    Set2,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct KeyEvent {
+    pub key: KeyCode,
+    pub pressed: bool,
+    // state after this event was applied
+    pub modifiers: Modifiers,
+    // what the key types with the current layout, presses only
+    pub ch: Option<char>,
+}
+
+fn set1_key(code: u8) -> Option<KeyCode> {
+    use KeyCode::*;
+    Some(match code {
+        0x01 => Escape,
+        0x02 => Num1, 0x03 => Num2, 0x04 => Num3, 0x05 => Num4, 0x06 => Num5,
+        0x07 => Num6, 0x08 => Num7, 0x09 => Num8, 0x0A => Num9, 0x0B => Num0,
+        0x0C => Minus,
+        0x0D => Equals,
+        0x0E => Backspace,
+        0x0F => Tab,
+        0x10 => Q, 0x11 => W, 0x12 => E, 0x13 => R, 0x14 => T,
+        0x15 => Y, 0x16 => U, 0x17 => I, 0x18 => O, 0x19 => P,
+        0x1A => LeftBracket,
+        0x1B => RightBracket,
+        0x1C => Enter,
+        0x1D => LeftCtrl,
+        0x1E => A, 0x1F => S, 0x20 => D, 0x21 => F, 0x22 => G,
+        0x23 => H, 0x24 => J, 0x25 => K, 0x26 => L,
+        0x27 => Semicolon,
+        0x28 => Quote,
+        0x29 => Backtick,
+        0x2A => LeftShift,
+        0x2B => Backslash,
+        0x2C => Z, 0x2D => X, 0x2E => C, 0x2F => V, 0x30 => B, 0x31 => N, 0x32 => M,
+        0x33 => Comma,
+        0x34 => Period,
+        0x35 => Slash,
+        0x36 => RightShift,
+        0x37 => KeypadMultiply,
+        0x38 => LeftAlt,
+        0x39 => Space,
+        0x3A => CapsLock,
+        0x3B => F1, 0x3C => F2, 0x3D => F3, 0x3E => F4, 0x3F => F5,
+        0x40 => F6, 0x41 => F7, 0x42 => F8, 0x43 => F9, 0x44 => F10,
+        0x45 => NumLock,
+        0x46 => ScrollLock,
+        0x47 => Keypad7, 0x48 => Keypad8, 0x49 => Keypad9,
+        0x4A => KeypadMinus,
+        0x4B => Keypad4, 0x4C => Keypad5, 0x4D => Keypad6,
+        0x4E => KeypadPlus,
+        0x4F => Keypad1, 0x50 => Keypad2, 0x51 => Keypad3,
+        0x52 => Keypad0,
+        0x53 => KeypadPeriod,
+        0x56 => NonUsBackslash,
+        0x57 => F11,
+        0x58 => F12,
+        _ => return None,
+    })
+}
+
+// after E0; the fake shift codes sent around print screen and the
+// navigation keys aren't listed, so they get dropped
+fn set1_extended_key(code: u8) -> Option<KeyCode> {
+    use KeyCode::*;
+    Some(match code {
+        0x1C => KeypadEnter,
+        0x1D => RightCtrl,
+        0x35 => KeypadDivide,
+        0x37 => PrintScreen,
+        0x38 => RightAlt,
+        0x47 => Home,
+        0x48 => ArrowUp,
+        0x49 => PageUp,
+        0x4B => ArrowLeft,
+        0x4D => ArrowRight,
+        0x4F => End,
+        0x50 => ArrowDown,
+        0x51 => PageDown,
+        0x52 => Insert,
+        0x53 => Delete,
+        0x5B => LeftGui,
+        0x5C => RightGui,
+        0x5D => Menu,
+        _ => return None,
+    })
+}
+
+fn set2_key(code: u8) -> Option<KeyCode> {
+    use KeyCode::*;
+    Some(match code {
+        0x01 => F9,
+        0x03 => F5,
+        0x04 => F3,
+        0x05 => F1,
+        0x06 => F2,
+        0x07 => F12,
+        0x09 => F10,
+        0x0A => F8,
+        0x0B => F6,
+        0x0C => F4,
+        0x0D => Tab,
+        0x0E => Backtick,
+        0x11 => LeftAlt,
+        0x12 => LeftShift,
+        0x14 => LeftCtrl,
+        0x15 => Q,
+        0x16 => Num1,
+        0x1A => Z,
+        0x1B => S,
+        0x1C => A,
+        0x1D => W,
+        0x1E => Num2,
+        0x21 => C,
+        0x22 => X,
+        0x23 => D,
+        0x24 => E,
+        0x25 => Num4,
+        0x26 => Num3,
+        0x29 => Space,
+        0x2A => V,
+        0x2B => F,
+        0x2C => T,
+        0x2D => R,
+        0x2E => Num5,
+        0x31 => N,
+        0x32 => B,
+        0x33 => H,
+        0x34 => G,
+        0x35 => Y,
+        0x36 => Num6,
+        0x3A => M,
+        0x3B => J,
+        0x3C => U,
+        0x3D => Num7,
+        0x3E => Num8,
+        0x41 => Comma,
+        0x42 => K,
+        0x43 => I,
+        0x44 => O,
+        0x45 => Num0,
+        0x46 => Num9,
+        0x49 => Period,
+        0x4A => Slash,
+        0x4B => L,
+        0x4C => Semicolon,
+        0x4D => P,
+        0x4E => Minus,
+        0x52 => Quote,
+        0x54 => LeftBracket,
+        0x55 => Equals,
+        0x58 => CapsLock,
+        0x59 => RightShift,
+        0x5A => Enter,
+        0x5B => RightBracket,
+        0x5D => Backslash,
+        0x61 => NonUsBackslash,
+        0x66 => Backspace,
+        0x69 => Keypad1,
+        0x6B => Keypad4,
+        0x6C => Keypad7,
+        0x70 => Keypad0,
+        0x71 => KeypadPeriod,
+        0x72 => Keypad2,
+        0x73 => Keypad5,
+        0x74 => Keypad6,
+        0x75 => Keypad8,
+        0x76 => Escape,
+        0x77 => NumLock,
+        0x78 => F11,
+        0x79 => KeypadPlus,
+        0x7A => Keypad3,
+        0x7B => KeypadMinus,
+        0x7C => KeypadMultiply,
+        0x7D => Keypad9,
+        0x7E => ScrollLock,
+        0x83 => F7,
+        _ => return None,
+    })
+}
+
+fn set2_extended_key(code: u8) -> Option<KeyCode> {
+    use KeyCode::*;
+    Some(match code {
+        0x11 => RightAlt,
+        0x14 => RightCtrl,
+        0x1F => LeftGui,
+        0x27 => RightGui,
+        0x2F => Menu,
+        0x4A => KeypadDivide,
+        0x5A => KeypadEnter,
+        0x69 => End,
+        0x6B => ArrowLeft,
+        0x6C => Home,
+        0x70 => Insert,
+        0x71 => Delete,
+        0x72 => ArrowDown,
+        0x74 => ArrowRight,
+        0x75 => ArrowUp,
+        0x7A => PageDown,
+        0x7C => PrintScreen,
+        0x7D => PageUp,
+        _ => return None,
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DecodeState {
+    Start,
+    Extended,
+    // set 2 only, after F0 and E0 F0
+    Release,
+    ExtendedRelease,
+    // bytes of the pause sequence still to come
+    Pause(u8),
+}
+
+// Turns scancode bytes into key presses and releases
+struct Decoder {
+    set: ScancodeSet,
+    state: DecodeState,
+}
+
+impl Decoder {
+    const fn new(set: ScancodeSet) -> Self {
+        Decoder { set, state: DecodeState::Start }
+    }
+
+    fn feed(&mut self, b: u8) -> Option<(KeyCode, bool)> {
+        match self.set {
+            ScancodeSet::Set1 => self.feed_set1(b),
+            ScancodeSet::Set2 => self.feed_set2(b),
+        }
+    }
+
+    fn feed_set1(&mut self, b: u8) -> Option<(KeyCode, bool)> {
+        match (self.state, b) {
+            (DecodeState::Pause(1), _) => {
+                self.state = DecodeState::Start;
+                Some((KeyCode::Pause, true))
+            }
+            (DecodeState::Pause(n), _) => {
+                self.state = DecodeState::Pause(n - 1);
+                None
+            }
+            (DecodeState::Start, PREFIX_EXTENDED) => {
+                self.state = DecodeState::Extended;
+                None
+            }
+            (DecodeState::Start, PREFIX_PAUSE) => {
+                self.state = DecodeState::Pause(SET1_PAUSE_TAIL);
+                None
+            }
+            (state, b) => {
+                self.state = DecodeState::Start;
+                let code = b & !SET1_RELEASE;
+                let key = match state {
+                    DecodeState::Extended => set1_extended_key(code),
+                    _ => set1_key(code),
+                }?;
+                Some((key, b & SET1_RELEASE == 0))
+            }
+        }
+    }
+
+    fn feed_set2(&mut self, b: u8) -> Option<(KeyCode, bool)> {
+        match (self.state, b) {
+            (DecodeState::Pause(1), _) => {
+                self.state = DecodeState::Start;
+                Some((KeyCode::Pause, true))
+            }
+            (DecodeState::Pause(n), _) => {
+                self.state = DecodeState::Pause(n - 1);
+                None
+            }
+            (DecodeState::Start, PREFIX_EXTENDED) => {
+                self.state = DecodeState::Extended;
+                None
+            }
+            (DecodeState::Start, PREFIX_PAUSE) => {
+                self.state = DecodeState::Pause(SET2_PAUSE_TAIL);
+                None
+            }
+            (DecodeState::Start, SET2_PREFIX_RELEASE) => {
+                self.state = DecodeState::Release;
+                None
+            }
+            (DecodeState::Extended, SET2_PREFIX_RELEASE) => {
+                self.state = DecodeState::ExtendedRelease;
+                None
+            }
+            (state, b) => {
+                self.state = DecodeState::Start;
+                match state {
+                    DecodeState::Extended => Some((set2_extended_key(b)?, true)),
+                    DecodeState::ExtendedRelease => Some((set2_extended_key(b)?, false)),
+                    DecodeState::Release => Some((set2_key(b)?, false)),
+                    _ => Some((set2_key(b)?, true)),
+                }
+            }
+        }
+    }
+}
+
+pub struct Keyboard {
+    decoder: Decoder,
+    modifiers: Modifiers,
+    layout: Layout,
+    // LED byte waiting for the keyboard to acknowledge the set LEDs command
+    pending_leds: Option<u8>,
+    // lock keys currently held down, so typematic repeat doesn't toggle them again
+    locks_held: u8,
+    events: EventQueue<KeyEvent, EVENT_QUEUE_SIZE>,
+}
+
+impl Keyboard {
+    const fn new() -> Self {
+        Keyboard {
+            decoder: Decoder::new(ScancodeSet::Set2),
+            modifiers: Modifiers {
+                left_shift: false,
+                right_shift: false,
+                left_ctrl: false,
+                right_ctrl: false,
+                left_alt: false,
+                alt_gr: false,
+                left_gui: false,
+                right_gui: false,
+                caps_lock: false,
+                num_lock: false,
+                scroll_lock: false,
+            },
+            layout: Layout::Us,
+            pending_leds: None,
+            locks_held: 0,
+            events: EventQueue::new(),
+        }
+    }
+
+    fn leds(&self) -> u8 {
+        let mut leds = 0;
+        if self.modifiers.scroll_lock {
+            leds |= LED_SCROLL_LOCK;
+        }
+        if self.modifiers.num_lock {
+            leds |= LED_NUM_LOCK;
+        }
+        if self.modifiers.caps_lock {
+            leds |= LED_CAPS_LOCK;
+        }
+        leds
+    }
+
+    // Called from the interrupt handler, so the LED byte only goes out
+    // once the ACK for the command comes in as another interrupt
+    fn update_leds(&mut self) {
+        if super::post(Port::First, CMD_SET_LEDS).is_ok() {
+            self.pending_leds = Some(self.leds());
+        }
+    }
+
+    fn apply(&mut self, key: KeyCode, pressed: bool) {
+        let lock = match key {
+            KeyCode::ScrollLock => LED_SCROLL_LOCK,
+            KeyCode::NumLock => LED_NUM_LOCK,
+            KeyCode::CapsLock => LED_CAPS_LOCK,
+            _ => 0,
+        };
+        if lock != 0 {
+            let repeat = pressed && self.locks_held & lock != 0;
+            match pressed {
+                true => self.locks_held |= lock,
+                false => self.locks_held &= !lock,
+            }
+            if !pressed || repeat {
+                return;
+            }
+        }
+        let m = &mut self.modifiers;
+        match key {
+            KeyCode::LeftShift => m.left_shift = pressed,
+            KeyCode::RightShift => m.right_shift = pressed,
+            KeyCode::LeftCtrl => m.left_ctrl = pressed,
+            KeyCode::RightCtrl => m.right_ctrl = pressed,
+            KeyCode::LeftAlt => m.left_alt = pressed,
+            KeyCode::RightAlt => m.alt_gr = pressed,
+            KeyCode::LeftGui => m.left_gui = pressed,
+            KeyCode::RightGui => m.right_gui = pressed,
+            KeyCode::CapsLock => m.caps_lock = !m.caps_lock,
+            KeyCode::NumLock => m.num_lock = !m.num_lock,
+            KeyCode::ScrollLock => m.scroll_lock = !m.scroll_lock,
+            _ => {}
+        }
+        if lock != 0 {
+            self.update_leds();
+        }
+    }
+
+    fn handle_byte(&mut self, b: u8) {
+        match (b, self.pending_leds) {
+            (DEVICE_ACK, Some(leds)) => {
+                self.pending_leds = None;
+                let _ = super::post(Port::First, leds);
+                return;
+            }
+            (DEVICE_ACK, None) | (DEVICE_RESEND, _) => return,
+            _ => {}
+        }
+        if let Some((key, pressed)) = self.decoder.feed(b) {
+            self.apply(key, pressed);
+            let ch = match pressed {
+                true => self.layout.translate(key, &self.modifiers),
+                false => None,
+            };
+            self.events.push(KeyEvent { key, pressed, modifiers: self.modifiers, ch });
+        }
+    }
+}
+
+pub static KEYBOARD: SpinLock<Keyboard> = SpinLock::new(Keyboard::new());
+
+extern "x86-interrupt" fn keyboard_handler(_frame: InterruptStackFrame) {
+    let b = unsafe { inb(DATA) };
+    KEYBOARD.lock().handle_byte(b);
+    irqchip::eoi(KEYBOARD_IRQ);
+}
+
+// Try to get the keyboard into scancode set 2, otherwise let the controller
+// translate whatever it sends into set 1
+fn select_scancode_set() -> ScancodeSet {
+    let set2 = super::send(Port::First, CMD_SCANCODE_SET)
+        .and_then(|_| super::send(Port::First, 2));
+    match set2 {
+        Ok(()) => ScancodeSet::Set2,
+        Err(_) => match super::enable_translation(true) {
+            Ok(()) => ScancodeSet::Set1,
+            Err(_) => ScancodeSet::Set2,
+        },
+    }
+}
+
+// Set up the controller and the keyboard on its first port, then start taking IRQ1
+pub fn init() -> Result<ScancodeSet, Ps2Error> {
+    super::init_controller()?;
+    super::enable_port(Port::First)?;
+    super::reset_device(Port::First)?;
+    let set = select_scancode_set();
+    // all locks start off
+    super::send(Port::First, CMD_SET_LEDS)?;
+    super::send(Port::First, 0)?;
+    super::send(Port::First, CMD_ENABLE_SCANNING)?;
+    KEYBOARD.lock().decoder = Decoder::new(set);
+
+    IDT.lock().keyboard.set_handler(keyboard_handler);
+    super::enable_irq(Port::First)?;
+    irqchip::unmask(KEYBOARD_IRQ);
+    Ok(set)
+}
+
+pub fn set_layout(layout: Layout) {
+    KEYBOARD.lock().layout = layout;
+}
+
+pub fn modifiers() -> Modifiers {
+    KEYBOARD.lock().modifiers
+}
+
+pub fn read_event() -> Option<KeyEvent> {
+    KEYBOARD.lock().events.pop()
+}
+
+// Next typed character, skipping releases and keys that don't type anything
+pub fn read_char() -> Option<char> {
+    let mut keyboard = KEYBOARD.lock();
+    while let Some(event) = keyboard.events.pop() {
+        if let Some(c) = event.ch {
+            return Some(c);
+        }
+    }
+    None
+}
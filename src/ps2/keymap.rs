@@ -0,0 +1,294 @@
+// Physical keys, named after what they say on a US keyboard. The two keys an
+// ISO keyboard has on top of that are `NonUsBackslash`, between left shift
+// and Z, and the one next to enter, which shares its scancode with `Backslash`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyCode {
+    Escape,
+    F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12,
+    Backtick,
+    Num1, Num2, Num3, Num4, Num5, Num6, Num7, Num8, Num9, Num0,
+    Minus,
+    Equals,
+    Backspace,
+    Tab,
+    Q, W, E, R, T, Y, U, I, O, P,
+    LeftBracket,
+    RightBracket,
+    Backslash,
+    CapsLock,
+    A, S, D, F, G, H, J, K, L,
+    Semicolon,
+    Quote,
+    Enter,
+    LeftShift,
+    NonUsBackslash,
+    Z, X, C, V, B, N, M,
+    Comma,
+    Period,
+    Slash,
+    RightShift,
+    LeftCtrl,
+    LeftGui,
+    LeftAlt,
+    Space,
+    RightAlt,
+    RightGui,
+    Menu,
+    RightCtrl,
+    PrintScreen,
+    ScrollLock,
+    Pause,
+    Insert,
+    Home,
+    PageUp,
+    Delete,
+    End,
+    PageDown,
+    ArrowUp,
+    ArrowLeft,
+    ArrowDown,
+    ArrowRight,
+    NumLock,
+    KeypadDivide,
+    KeypadMultiply,
+    KeypadMinus,
+    KeypadPlus,
+    KeypadEnter,
+    KeypadPeriod,
+    Keypad0, Keypad1, Keypad2, Keypad3, Keypad4, Keypad5, Keypad6, Keypad7, Keypad8, Keypad9,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Modifiers {
+    pub left_shift: bool,
+    pub right_shift: bool,
+    pub left_ctrl: bool,
+    pub right_ctrl: bool,
+    pub left_alt: bool,
+    // right alt, which picks the third level on most non-US layouts
+    pub alt_gr: bool,
+    pub left_gui: bool,
+    pub right_gui: bool,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+impl Modifiers {
+    pub fn shift(&self) -> bool {
+        self.left_shift || self.right_shift
+    }
+
+    pub fn ctrl(&self) -> bool {
+        self.left_ctrl || self.right_ctrl
+    }
+
+    pub fn alt(&self) -> bool {
+        self.left_alt
+    }
+
+    pub fn gui(&self) -> bool {
+        self.left_gui || self.right_gui
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    Us,
+    Uk,
+    De,
+}
+
+impl Layout {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "us" => Some(Layout::Us),
+            "uk" | "gb" => Some(Layout::Uk),
+            "de" => Some(Layout::De),
+            _ => None,
+        }
+    }
+
+    // Character a key produces with the given modifiers, if any
+    pub fn translate(&self, key: KeyCode, mods: &Modifiers) -> Option<char> {
+        if let Some(c) = keypad(key, mods) {
+            return Some(c);
+        }
+        if mods.alt_gr {
+            return match self {
+                Layout::Us => None,
+                Layout::Uk => uk_alt_gr(key),
+                Layout::De => de_alt_gr(key),
+            };
+        }
+        let (normal, shifted) = match self {
+            Layout::Us => us(key),
+            Layout::Uk => uk(key),
+            Layout::De => de(key),
+        }?;
+        let c = match normal.is_alphabetic() && mods.caps_lock {
+            true => if mods.shift() { normal } else { shifted },
+            false => if mods.shift() { shifted } else { normal },
+        };
+        // ctrl+letter gives the matching control character
+        if mods.ctrl() && c.is_ascii_alphabetic() {
+            return Some(((c.to_ascii_lowercase() as u8) & 0x1F) as char);
+        }
+        Some(c)
+    }
+}
+
+// keys that don't depend on the layout
+fn common(key: KeyCode) -> Option<(char, char)> {
+    use KeyCode::*;
+    Some(match key {
+        Backspace => ('\x08', '\x08'),
+        Tab => ('\t', '\t'),
+        Enter | KeypadEnter => ('\n', '\n'),
+        Space => (' ', ' '),
+        Escape => ('\x1b', '\x1b'),
+        Delete => ('\x7f', '\x7f'),
+        KeypadDivide => ('/', '/'),
+        KeypadMultiply => ('*', '*'),
+        KeypadMinus => ('-', '-'),
+        KeypadPlus => ('+', '+'),
+        _ => return None,
+    })
+}
+
+// digits and the decimal point with num lock on, shift inverts it;
+// otherwise the keypad is arrows and friends, which aren't characters
+fn keypad(key: KeyCode, mods: &Modifiers) -> Option<char> {
+    use KeyCode::*;
+    if mods.num_lock == mods.shift() {
+        return None;
+    }
+    Some(match key {
+        Keypad0 => '0',
+        Keypad1 => '1',
+        Keypad2 => '2',
+        Keypad3 => '3',
+        Keypad4 => '4',
+        Keypad5 => '5',
+        Keypad6 => '6',
+        Keypad7 => '7',
+        Keypad8 => '8',
+        Keypad9 => '9',
+        KeypadPeriod => '.',
+        _ => return None,
+    })
+}
+
+fn letter(key: KeyCode) -> Option<(char, char)> {
+    use KeyCode::*;
+    let c = match key {
+        A => 'a', B => 'b', C => 'c', D => 'd', E => 'e', F => 'f', G => 'g',
+        H => 'h', I => 'i', J => 'j', K => 'k', L => 'l', M => 'm', N => 'n',
+        O => 'o', P => 'p', Q => 'q', R => 'r', S => 's', T => 't', U => 'u',
+        V => 'v', W => 'w', X => 'x', Y => 'y', Z => 'z',
+        _ => return None,
+    };
+    Some((c, c.to_ascii_uppercase()))
+}
+
+fn us(key: KeyCode) -> Option<(char, char)> {
+    use KeyCode::*;
+    Some(match key {
+        Backtick => ('`', '~'),
+        Num1 => ('1', '!'),
+        Num2 => ('2', '@'),
+        Num3 => ('3', '#'),
+        Num4 => ('4', '$'),
+        Num5 => ('5', '%'),
+        Num6 => ('6', '^'),
+        Num7 => ('7', '&'),
+        Num8 => ('8', '*'),
+        Num9 => ('9', '('),
+        Num0 => ('0', ')'),
+        Minus => ('-', '_'),
+        Equals => ('=', '+'),
+        LeftBracket => ('[', '{'),
+        RightBracket => (']', '}'),
+        Backslash => ('\\', '|'),
+        Semicolon => (';', ':'),
+        Quote => ('\'', '"'),
+        NonUsBackslash => ('\\', '|'),
+        Comma => (',', '<'),
+        Period => ('.', '>'),
+        Slash => ('/', '?'),
+        _ => return letter(key).or_else(|| common(key)),
+    })
+}
+
+fn uk(key: KeyCode) -> Option<(char, char)> {
+    use KeyCode::*;
+    Some(match key {
+        Backtick => ('`', '¬'),
+        Num2 => ('2', '"'),
+        Num3 => ('3', '£'),
+        Quote => ('\'', '@'),
+        Backslash => ('#', '~'),
+        NonUsBackslash => ('\\', '|'),
+        _ => return us(key),
+    })
+}
+
+fn uk_alt_gr(key: KeyCode) -> Option<char> {
+    use KeyCode::*;
+    match key {
+        Backtick => Some('¦'),
+        Num4 => Some('€'),
+        _ => None,
+    }
+}
+
+// QWERTZ, dead keys produce their accent right away
+fn de(key: KeyCode) -> Option<(char, char)> {
+    use KeyCode::*;
+    Some(match key {
+        Backtick => ('^', '°'),
+        Num1 => ('1', '!'),
+        Num2 => ('2', '"'),
+        Num3 => ('3', '§'),
+        Num4 => ('4', '$'),
+        Num5 => ('5', '%'),
+        Num6 => ('6', '&'),
+        Num7 => ('7', '/'),
+        Num8 => ('8', '('),
+        Num9 => ('9', ')'),
+        Num0 => ('0', '='),
+        Minus => ('ß', '?'),
+        Equals => ('´', '`'),
+        Y => ('z', 'Z'),
+        Z => ('y', 'Y'),
+        LeftBracket => ('ü', 'Ü'),
+        RightBracket => ('+', '*'),
+        Backslash => ('#', '\''),
+        Semicolon => ('ö', 'Ö'),
+        Quote => ('ä', 'Ä'),
+        NonUsBackslash => ('<', '>'),
+        Comma => (',', ';'),
+        Period => ('.', ':'),
+        Slash => ('-', '_'),
+        _ => return letter(key).or_else(|| common(key)),
+    })
+}
+
+fn de_alt_gr(key: KeyCode) -> Option<char> {
+    use KeyCode::*;
+    match key {
+        Num2 => Some('²'),
+        Num3 => Some('³'),
+        Num7 => Some('{'),
+        Num8 => Some('['),
+        Num9 => Some(']'),
+        Num0 => Some('}'),
+        Minus => Some('\\'),
+        Q => Some('@'),
+        E => Some('€'),
+        M => Some('µ'),
+        RightBracket => Some('~'),
+        NonUsBackslash => Some('|'),
+        _ => None,
+    }
+}
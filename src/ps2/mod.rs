@@ -0,0 +1,293 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86::io::{inb, outb};
+pub mod keyboard;
+pub mod keymap;
+pub mod mouse;
+
+// https://wiki.osdev.org/%228042%22_PS/2_Controller
+
+const DATA: u16 = 0x60;
+// status on read, command on write
+const STATUS: u16 = 0x64;
+const COMMAND: u16 = 0x64;
+
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+// the byte waiting in the output buffer came from the second port
+pub const STATUS_AUX_DATA: u8 = 1 << 5;
+
+const CMD_READ_CONFIG: u8 = 0x20;
+const CMD_WRITE_CONFIG: u8 = 0x60;
+const CMD_DISABLE_PORT2: u8 = 0xA7;
+const CMD_ENABLE_PORT2: u8 = 0xA8;
+const CMD_TEST_PORT2: u8 = 0xA9;
+const CMD_SELF_TEST: u8 = 0xAA;
+const CMD_TEST_PORT1: u8 = 0xAB;
+const CMD_DISABLE_PORT1: u8 = 0xAD;
+const CMD_ENABLE_PORT1: u8 = 0xAE;
+// next byte written to the data port goes to the second port's device
+const CMD_WRITE_PORT2: u8 = 0xD4;
+// pulse output line 0, which is wired to the CPU reset on PCs
+const CMD_PULSE_RESET: u8 = 0xFE;
+
+const CONFIG_PORT1_IRQ: u8 = 1 << 0;
+const CONFIG_PORT2_IRQ: u8 = 1 << 1;
+const CONFIG_PORT2_CLOCK_DISABLED: u8 = 1 << 5;
+// controller converts set 2 scancodes to set 1
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+const SELF_TEST_PASSED: u8 = 0x55;
+const PORT_TEST_PASSED: u8 = 0x00;
+
+// device replies
+pub const DEVICE_ACK: u8 = 0xFA;
+pub const DEVICE_RESEND: u8 = 0xFE;
+pub const DEVICE_SELF_TEST_PASSED: u8 = 0xAA;
+const DEVICE_RESET: u8 = 0xFF;
+
+const TIMEOUT_SPINS: usize = 100_000;
+const SEND_RETRIES: usize = 3;
+
+// events buffered per device until someone reads them
+pub const EVENT_QUEUE_SIZE: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Port {
+    First,
+    Second,
+}
+
+#[derive(Debug)]
+pub enum Ps2Error {
+    Timeout,
+    ControllerSelfTest(u8),
+    PortTest(Port, u8),
+    NoSecondPort,
+    NoAck(u8),
+    DeviceSelfTest(u8),
+}
+
+// Ring of decoded input events, shared by the keyboard and mouse drivers
+pub struct EventQueue<T: Copy, const N: usize> {
+    events: [Option<T>; N],
+    head: usize,
+    len: usize,
+}
+
+impl<T: Copy, const N: usize> EventQueue<T, N> {
+    pub const fn new() -> Self {
+        EventQueue {
+            events: [None; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    // drops the oldest event when full
+    pub fn push(&mut self, event: T) {
+        let tail = (self.head + self.len) % N;
+        self.events[tail] = Some(event);
+        if self.len == N {
+            self.head = (self.head + 1) % N;
+        } else {
+            self.len += 1;
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+        let event = self.events[self.head].take();
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        event
+    }
+}
+
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+static DUAL_CHANNEL: AtomicBool = AtomicBool::new(false);
+
+fn status() -> u8 {
+    unsafe { inb(STATUS) }
+}
+
+fn wait_input_empty() -> Result<(), Ps2Error> {
+    for _ in 0..TIMEOUT_SPINS {
+        if status() & STATUS_INPUT_FULL == 0 {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err(Ps2Error::Timeout)
+}
+
+fn wait_output_full() -> Result<(), Ps2Error> {
+    for _ in 0..TIMEOUT_SPINS {
+        if status() & STATUS_OUTPUT_FULL != 0 {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err(Ps2Error::Timeout)
+}
+
+fn command(cmd: u8) -> Result<(), Ps2Error> {
+    wait_input_empty()?;
+    unsafe { outb(COMMAND, cmd); }
+    Ok(())
+}
+
+fn command_with_reply(cmd: u8) -> Result<u8, Ps2Error> {
+    command(cmd)?;
+    read_data()
+}
+
+pub fn read_data() -> Result<u8, Ps2Error> {
+    wait_output_full()?;
+    Ok(unsafe { inb(DATA) })
+}
+
+// Next byte from the device on `port`, dropping whatever the other one sends meanwhile
+pub fn read_from(port: Port) -> Result<u8, Ps2Error> {
+    for _ in 0..TIMEOUT_SPINS {
+        wait_output_full()?;
+        let aux = status() & STATUS_AUX_DATA != 0;
+        let b = unsafe { inb(DATA) };
+        if aux == (port == Port::Second) {
+            return Ok(b);
+        }
+    }
+    Err(Ps2Error::Timeout)
+}
+
+pub fn write_data(b: u8) -> Result<(), Ps2Error> {
+    wait_input_empty()?;
+    unsafe { outb(DATA, b); }
+    Ok(())
+}
+
+fn flush_output() {
+    while status() & STATUS_OUTPUT_FULL != 0 {
+        unsafe { inb(DATA); }
+    }
+}
+
+fn read_config() -> Result<u8, Ps2Error> {
+    command_with_reply(CMD_READ_CONFIG)
+}
+
+fn write_config(config: u8) -> Result<(), Ps2Error> {
+    command(CMD_WRITE_CONFIG)?;
+    write_data(config)
+}
+
+// Set and clear bits of the controller configuration byte
+pub fn update_config(set: u8, clear: u8) -> Result<(), Ps2Error> {
+    let config = read_config()?;
+    write_config((config & !clear) | set)
+}
+
+pub fn enable_translation(enable: bool) -> Result<(), Ps2Error> {
+    match enable {
+        true => update_config(CONFIG_TRANSLATION, 0),
+        false => update_config(0, CONFIG_TRANSLATION),
+    }
+}
+
+// Turn on interrupts for a port once its device is set up
+pub fn enable_irq(port: Port) -> Result<(), Ps2Error> {
+    match port {
+        Port::First => update_config(CONFIG_PORT1_IRQ, 0),
+        Port::Second => update_config(CONFIG_PORT2_IRQ, 0),
+    }
+}
+
+pub fn enable_port(port: Port) -> Result<(), Ps2Error> {
+    match port {
+        Port::First => command(CMD_ENABLE_PORT1),
+        Port::Second if DUAL_CHANNEL.load(Ordering::Relaxed) => command(CMD_ENABLE_PORT2),
+        Port::Second => Err(Ps2Error::NoSecondPort),
+    }
+}
+
+// Write a byte to a device without waiting for its answer, for use from
+// interrupt handlers where the reply comes in as an interrupt of its own
+pub fn post(port: Port, b: u8) -> Result<(), Ps2Error> {
+    if port == Port::Second {
+        command(CMD_WRITE_PORT2)?;
+    }
+    write_data(b)
+}
+
+// Send a byte to a device and wait for it to acknowledge, resending if it asks to.
+// Only for use while the port's interrupt is off.
+pub fn send(port: Port, b: u8) -> Result<(), Ps2Error> {
+    for _ in 0..SEND_RETRIES {
+        post(port, b)?;
+        match read_from(port)? {
+            DEVICE_ACK => return Ok(()),
+            DEVICE_RESEND => continue,
+            other => return Err(Ps2Error::NoAck(other)),
+        }
+    }
+    Err(Ps2Error::NoAck(DEVICE_RESEND))
+}
+
+// Reset the device on `port` and check it passes its self-test
+pub fn reset_device(port: Port) -> Result<(), Ps2Error> {
+    send(port, DEVICE_RESET)?;
+    match read_from(port)? {
+        DEVICE_SELF_TEST_PASSED => Ok(()),
+        other => Err(Ps2Error::DeviceSelfTest(other)),
+    }
+}
+
+// Reset the machine the way the PC/AT did. Returns if the controller didn't
+// take the command or nothing is wired to the line.
+pub fn pulse_reset() -> Result<(), Ps2Error> {
+    command(CMD_PULSE_RESET)
+}
+
+pub fn is_dual_channel() -> bool {
+    DUAL_CHANNEL.load(Ordering::Relaxed)
+}
+
+// Bring the controller into a known state: both ports disabled with their
+// interrupts off and translation off, self-tested. Safe to call more than
+// once, only the first call does anything.
+pub fn init_controller() -> Result<(), Ps2Error> {
+    if INITIALIZED.load(Ordering::Acquire) {
+        return Ok(());
+    }
+    command(CMD_DISABLE_PORT1)?;
+    command(CMD_DISABLE_PORT2)?;
+    flush_output();
+
+    let config = read_config()? & !(CONFIG_PORT1_IRQ | CONFIG_PORT2_IRQ | CONFIG_TRANSLATION);
+    write_config(config)?;
+
+    match command_with_reply(CMD_SELF_TEST)? {
+        SELF_TEST_PASSED => {}
+        other => return Err(Ps2Error::ControllerSelfTest(other)),
+    }
+    // the self-test may reset the controller on some hardware
+    write_config(config)?;
+
+    // the second port's clock only follows the enable command on dual channel controllers
+    command(CMD_ENABLE_PORT2)?;
+    let dual = read_config()? & CONFIG_PORT2_CLOCK_DISABLED == 0;
+    if dual {
+        command(CMD_DISABLE_PORT2)?;
+    }
+
+    match command_with_reply(CMD_TEST_PORT1)? {
+        PORT_TEST_PASSED => {}
+        other => return Err(Ps2Error::PortTest(Port::First, other)),
+    }
+    let dual = dual && command_with_reply(CMD_TEST_PORT2)? == PORT_TEST_PASSED;
+
+    DUAL_CHANNEL.store(dual, Ordering::Relaxed);
+    INITIALIZED.store(true, Ordering::Release);
+    Ok(())
+}
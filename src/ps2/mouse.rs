@@ -0,0 +1,218 @@
+use x86::io::inb;
+use crate::interrupts::{InterruptStackFrame, IDT};
+use crate::irqchip;
+use crate::sync::SpinLock;
+use super::{EventQueue, Port, Ps2Error, EVENT_QUEUE_SIZE};
+
+// https://wiki.osdev.org/PS/2_Mouse
+
+const MOUSE_IRQ: u8 = 12;
+const DATA: u16 = 0x60;
+
+const CMD_GET_ID: u8 = 0xF2;
+const CMD_SET_SAMPLE_RATE: u8 = 0xF3;
+const CMD_ENABLE_REPORTING: u8 = 0xF4;
+const CMD_SET_DEFAULTS: u8 = 0xF6;
+
+const ID_WHEEL: u8 = 3;
+const ID_FIVE_BUTTONS: u8 = 4;
+
+// magic sample rate sequences that unlock the IntelliMouse extensions
+const WHEEL_KNOCK: [u8; 3] = [200, 100, 80];
+const FIVE_BUTTON_KNOCK: [u8; 3] = [200, 200, 80];
+const SAMPLE_RATE: u8 = 100;
+
+const PACKET_LEFT: u8 = 1 << 0;
+const PACKET_RIGHT: u8 = 1 << 1;
+const PACKET_MIDDLE: u8 = 1 << 2;
+// always set in the first byte, used to find packet boundaries again
+const PACKET_SYNC: u8 = 1 << 3;
+const PACKET_X_SIGN: u8 = 1 << 4;
+const PACKET_Y_SIGN: u8 = 1 << 5;
+const PACKET_X_OVERFLOW: u8 = 1 << 6;
+const PACKET_Y_OVERFLOW: u8 = 1 << 7;
+// fourth byte of five button mice
+const PACKET_BUTTON4: u8 = 1 << 4;
+const PACKET_BUTTON5: u8 = 1 << 5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MouseKind {
+    Standard,
+    Wheel,
+    FiveButtons,
+}
+
+impl MouseKind {
+    fn packet_size(&self) -> usize {
+        match self {
+            MouseKind::Standard => 3,
+            _ => 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MouseButtons {
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+    pub button4: bool,
+    pub button5: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MouseEvent {
+    // relative motion, positive y is up
+    pub dx: i16,
+    pub dy: i16,
+    // positive is towards the user
+    pub wheel: i8,
+    pub buttons: MouseButtons,
+}
+
+// 9-bit two's complement: the low byte plus a sign bit from the first byte
+fn motion(value: u8, negative: bool) -> i16 {
+    match negative {
+        true => value as i16 - 0x100,
+        false => value as i16,
+    }
+}
+
+fn decode(kind: MouseKind, packet: &[u8; 4]) -> MouseEvent {
+    let flags = packet[0];
+    let mut event = MouseEvent {
+        dx: 0,
+        dy: 0,
+        wheel: 0,
+        buttons: MouseButtons {
+            left: flags & PACKET_LEFT != 0,
+            right: flags & PACKET_RIGHT != 0,
+            middle: flags & PACKET_MIDDLE != 0,
+            button4: false,
+            button5: false,
+        },
+    };
+    // an overflowed axis has nothing useful in it
+    if flags & PACKET_X_OVERFLOW == 0 {
+        event.dx = motion(packet[1], flags & PACKET_X_SIGN != 0);
+    }
+    if flags & PACKET_Y_OVERFLOW == 0 {
+        event.dy = motion(packet[2], flags & PACKET_Y_SIGN != 0);
+    }
+    match kind {
+        MouseKind::Standard => {}
+        MouseKind::Wheel => event.wheel = packet[3] as i8,
+        MouseKind::FiveButtons => {
+            // 4-bit signed wheel movement
+            event.wheel = ((packet[3] << 4) as i8) >> 4;
+            event.buttons.button4 = packet[3] & PACKET_BUTTON4 != 0;
+            event.buttons.button5 = packet[3] & PACKET_BUTTON5 != 0;
+        }
+    }
+    event
+}
+
+pub struct Mouse {
+    kind: MouseKind,
+    packet: [u8; 4],
+    received: usize,
+    buttons: MouseButtons,
+    events: EventQueue<MouseEvent, EVENT_QUEUE_SIZE>,
+}
+
+impl Mouse {
+    const fn new() -> Self {
+        Mouse {
+            kind: MouseKind::Standard,
+            packet: [0; 4],
+            received: 0,
+            buttons: MouseButtons {
+                left: false,
+                right: false,
+                middle: false,
+                button4: false,
+                button5: false,
+            },
+            events: EventQueue::new(),
+        }
+    }
+
+    fn handle_byte(&mut self, b: u8) {
+        // lost a byte somewhere, wait for something that looks like a packet start
+        if self.received == 0 && b & PACKET_SYNC == 0 {
+            return;
+        }
+        self.packet[self.received] = b;
+        self.received += 1;
+        if self.received < self.kind.packet_size() {
+            return;
+        }
+        self.received = 0;
+        let event = decode(self.kind, &self.packet);
+        self.buttons = event.buttons;
+        self.events.push(event);
+    }
+}
+
+pub static MOUSE: SpinLock<Mouse> = SpinLock::new(Mouse::new());
+
+extern "x86-interrupt" fn mouse_handler(_frame: InterruptStackFrame) {
+    let b = unsafe { inb(DATA) };
+    MOUSE.lock().handle_byte(b);
+    irqchip::eoi(MOUSE_IRQ);
+}
+
+fn set_sample_rate(rate: u8) -> Result<(), Ps2Error> {
+    super::send(Port::Second, CMD_SET_SAMPLE_RATE)?;
+    super::send(Port::Second, rate)
+}
+
+fn device_id() -> Result<u8, Ps2Error> {
+    super::send(Port::Second, CMD_GET_ID)?;
+    super::read_from(Port::Second)
+}
+
+fn knock(rates: &[u8]) -> Result<u8, Ps2Error> {
+    for &rate in rates {
+        set_sample_rate(rate)?;
+    }
+    device_id()
+}
+
+// Unlock the wheel, then the extra buttons; each step only works if the previous one did
+fn negotiate() -> Result<MouseKind, Ps2Error> {
+    if knock(&WHEEL_KNOCK)? != ID_WHEEL {
+        return Ok(MouseKind::Standard);
+    }
+    match knock(&FIVE_BUTTON_KNOCK)? {
+        ID_FIVE_BUTTONS => Ok(MouseKind::FiveButtons),
+        _ => Ok(MouseKind::Wheel),
+    }
+}
+
+// Set up the mouse on the controller's second port and start taking IRQ12
+pub fn init() -> Result<MouseKind, Ps2Error> {
+    super::init_controller()?;
+    super::enable_port(Port::Second)?;
+    super::reset_device(Port::Second)?;
+    // a reset mouse follows the self-test result with its id
+    let _ = super::read_from(Port::Second);
+    super::send(Port::Second, CMD_SET_DEFAULTS)?;
+    let kind = negotiate()?;
+    set_sample_rate(SAMPLE_RATE)?;
+    super::send(Port::Second, CMD_ENABLE_REPORTING)?;
+    MOUSE.lock().kind = kind;
+
+    IDT.lock().ps2_mouse.set_handler(mouse_handler);
+    super::enable_irq(Port::Second)?;
+    irqchip::unmask(MOUSE_IRQ);
+    Ok(kind)
+}
+
+pub fn buttons() -> MouseButtons {
+    MOUSE.lock().buttons
+}
+
+pub fn read_event() -> Option<MouseEvent> {
+    MOUSE.lock().events.pop()
+}
@@ -0,0 +1,225 @@
+use alloc::boxed::Box;
+use core::fmt::Write;
+use x86::io::{inb, outb};
+use crate::console::{self, ConsoleSink};
+use crate::interrupts::{InterruptStackFrame, IDT};
+use crate::irqchip;
+use crate::sync::SpinLock;
+
+// https://wiki.osdev.org/Serial_Ports
+
+pub const COM1_BASE: u16 = 0x3F8;
+pub const COM2_BASE: u16 = 0x2F8;
+const COM1_IRQ: u8 = 4;
+const COM2_IRQ: u8 = 3;
+
+// register offsets from the base port
+const DATA: u16 = 0;            // rx/tx buffer, divisor low byte with DLAB
+const INT_ENABLE: u16 = 1;      // divisor high byte with DLAB
+const FIFO_CTRL: u16 = 2;
+const LINE_CTRL: u16 = 3;
+const MODEM_CTRL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+
+const UART_CLOCK: u32 = 115200;
+
+const LINE_CTRL_8N1: u8 = 0b_0000_0011;
+const LINE_CTRL_DLAB: u8 = 0b_1000_0000;
+// enable, clear both FIFOs, interrupt at 14 bytes
+const FIFO_CTRL_ENABLE: u8 = 0b_1100_0111;
+const MODEM_CTRL_DTR: u8 = 1 << 0;
+const MODEM_CTRL_RTS: u8 = 1 << 1;
+// gates the IRQ line to the PIC on PC hardware
+const MODEM_CTRL_OUT2: u8 = 1 << 3;
+const MODEM_CTRL_LOOPBACK: u8 = 1 << 4;
+const INT_ENABLE_RX: u8 = 1 << 0;
+const LINE_STATUS_DATA_READY: u8 = 1 << 0;
+const LINE_STATUS_THR_EMPTY: u8 = 1 << 5;
+
+const RX_BUFFER_SIZE: usize = 256;
+const LOOPBACK_TEST_BYTE: u8 = 0xAE;
+
+#[derive(Debug)]
+pub enum SerialError {
+    BadBaudRate(u32),
+    // loopback self-test didn't read back what was sent, likely no chip at all
+    NotPresent(u16),
+}
+
+pub struct SerialPort {
+    base: u16,
+    rx: [u8; RX_BUFFER_SIZE],
+    rx_head: usize,
+    rx_len: usize,
+}
+
+impl SerialPort {
+    pub const fn new(base: u16) -> Self {
+        SerialPort {
+            base,
+            rx: [0; RX_BUFFER_SIZE],
+            rx_head: 0,
+            rx_len: 0,
+        }
+    }
+
+    fn reg(&self, offset: u16) -> u16 {
+        self.base + offset
+    }
+
+    // Program 8N1 at `baud` with FIFOs on, then check the chip answers in loopback mode
+    pub fn init(&mut self, baud: u32) -> Result<(), SerialError> {
+        if baud == 0 || UART_CLOCK % baud != 0 {
+            return Err(SerialError::BadBaudRate(baud));
+        }
+        let divisor = (UART_CLOCK / baud) as u16;
+        unsafe {
+            outb(self.reg(INT_ENABLE), 0x00);
+            outb(self.reg(LINE_CTRL), LINE_CTRL_DLAB);
+            outb(self.reg(DATA), divisor as u8);
+            outb(self.reg(INT_ENABLE), (divisor >> 8) as u8);
+            outb(self.reg(LINE_CTRL), LINE_CTRL_8N1);
+            outb(self.reg(FIFO_CTRL), FIFO_CTRL_ENABLE);
+
+            outb(self.reg(MODEM_CTRL), MODEM_CTRL_RTS | MODEM_CTRL_OUT2 | MODEM_CTRL_LOOPBACK);
+            outb(self.reg(DATA), LOOPBACK_TEST_BYTE);
+            if inb(self.reg(DATA)) != LOOPBACK_TEST_BYTE {
+                return Err(SerialError::NotPresent(self.base));
+            }
+
+            outb(self.reg(MODEM_CTRL), MODEM_CTRL_DTR | MODEM_CTRL_RTS | MODEM_CTRL_OUT2);
+            outb(self.reg(INT_ENABLE), INT_ENABLE_RX);
+        }
+        Ok(())
+    }
+
+    fn tx_empty(&self) -> bool {
+        unsafe { inb(self.reg(LINE_STATUS)) & LINE_STATUS_THR_EMPTY != 0 }
+    }
+
+    fn rx_ready(&self) -> bool {
+        unsafe { inb(self.reg(LINE_STATUS)) & LINE_STATUS_DATA_READY != 0 }
+    }
+
+    pub fn write_byte(&mut self, b: u8) {
+        while !self.tx_empty() {
+            core::hint::spin_loop();
+        }
+        unsafe { outb(self.reg(DATA), b); }
+    }
+
+    // Move everything the UART has received into the ring buffer,
+    // the oldest bytes are dropped when it's full
+    fn drain_rx(&mut self) {
+        while self.rx_ready() {
+            let b = unsafe { inb(self.reg(DATA)) };
+            let tail = (self.rx_head + self.rx_len) % RX_BUFFER_SIZE;
+            self.rx[tail] = b;
+            if self.rx_len == RX_BUFFER_SIZE {
+                self.rx_head = (self.rx_head + 1) % RX_BUFFER_SIZE;
+            } else {
+                self.rx_len += 1;
+            }
+        }
+    }
+
+    pub fn read_byte(&mut self) -> Option<u8> {
+        if self.rx_len == 0 {
+            return None;
+        }
+        let b = self.rx[self.rx_head];
+        self.rx_head = (self.rx_head + 1) % RX_BUFFER_SIZE;
+        self.rx_len -= 1;
+        Some(b)
+    }
+}
+
+impl Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for b in s.bytes() {
+            // terminals want CRLF
+            if b == b'\n' {
+                self.write_byte(b'\r');
+            }
+            self.write_byte(b);
+        }
+        Ok(())
+    }
+}
+
+pub static COM1: SpinLock<Option<SerialPort>> = SpinLock::new(None);
+pub static COM2: SpinLock<Option<SerialPort>> = SpinLock::new(None);
+
+// Console output to a port brought up by `init`, errors in red
+struct SerialSink(&'static SpinLock<Option<SerialPort>>);
+
+impl ConsoleSink for SerialSink {
+    fn write_str(&mut self, s: &str) {
+        if let Some(port) = self.0.lock().as_mut() {
+            let _ = port.write_str(s);
+        }
+    }
+
+    fn try_write_str(&mut self, s: &str) {
+        if let Some(mut port) = self.0.try_lock() {
+            if let Some(port) = port.as_mut() {
+                let _ = port.write_str(s);
+            }
+        }
+    }
+
+    fn set_error(&mut self, error: bool) {
+        self.write_str(match error {
+            true => "\x1b[31m",
+            false => "\x1b[0m",
+        });
+    }
+}
+
+fn rx_interrupt(port: &SpinLock<Option<SerialPort>>, irq: u8) {
+    if let Some(port) = port.lock().as_mut() {
+        port.drain_rx();
+    }
+    irqchip::eoi(irq);
+}
+
+extern "x86-interrupt" fn com1_handler(_frame: InterruptStackFrame) {
+    rx_interrupt(&COM1, COM1_IRQ);
+}
+
+extern "x86-interrupt" fn com2_handler(_frame: InterruptStackFrame) {
+    rx_interrupt(&COM2, COM2_IRQ);
+}
+
+// Bring up whichever of COM1 and COM2 pass the self-test. Done as early as
+// possible so boot messages make it out, the IRQs come later in `enable_irqs`.
+pub fn init(baud: u32) {
+    let mut port = SerialPort::new(COM1_BASE);
+    if port.init(baud).is_ok() {
+        *COM1.lock() = Some(port);
+        console::register_sink(Box::leak(Box::new(SerialSink(&COM1))));
+        info!("COM1 at {:#x}, {} baud", COM1_BASE, baud);
+    }
+    let mut port = SerialPort::new(COM2_BASE);
+    if port.init(baud).is_ok() {
+        *COM2.lock() = Some(port);
+        info!("COM2 at {:#x}, {} baud", COM2_BASE, baud);
+    }
+}
+
+// Hook up receive interrupts of the ports `init` found, once the interrupt
+// controller is set up
+pub fn enable_irqs() {
+    if COM1.lock().is_some() {
+        IDT.lock().serial1.set_handler(com1_handler);
+        irqchip::unmask(COM1_IRQ);
+    }
+    if COM2.lock().is_some() {
+        IDT.lock().serial2.set_handler(com2_handler);
+        irqchip::unmask(COM2_IRQ);
+    }
+}
+
+pub fn read_byte() -> Option<u8> {
+    COM1.lock().as_mut()?.read_byte()
+}
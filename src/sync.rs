@@ -0,0 +1,89 @@
+use core::cell::UnsafeCell;
+use core::hint::spin_loop;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86::bits64::rflags::{self, RFlags};
+use x86::irq;
+
+// Busy-waiting mutex. Interrupts are disabled for as long as the guard lives
+// and restored to their previous state afterwards, so the same lock can be
+// taken from both regular code and interrupt handlers without deadlocking.
+pub struct SpinLock<T> {
+    locked: AtomicBool,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+unsafe impl<T: Send> Send for SpinLock<T> {}
+
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+    irq_enabled: bool,
+}
+
+impl<T> SpinLock<T> {
+    pub const fn new(data: T) -> Self {
+        SpinLock {
+            locked: AtomicBool::new(false),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> SpinLockGuard<T> {
+        let irq_enabled = rflags::read().contains(RFlags::FLAGS_IF);
+        unsafe { irq::disable(); }
+        while self.locked.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            while self.locked.load(Ordering::Relaxed) {
+                spin_loop();
+            }
+        }
+        SpinLockGuard { lock: self, irq_enabled }
+    }
+
+    pub fn try_lock(&self) -> Option<SpinLockGuard<T>> {
+        let irq_enabled = rflags::read().contains(RFlags::FLAGS_IF);
+        unsafe { irq::disable(); }
+        match self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed) {
+            Ok(_) => Some(SpinLockGuard { lock: self, irq_enabled }),
+            Err(_) => {
+                if irq_enabled {
+                    unsafe { irq::enable(); }
+                }
+                None
+            }
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    // Release the lock regardless of who holds it. Only meant for the panic
+    // path, where the holder is never coming back.
+    pub unsafe fn force_unlock(&self) {
+        self.locked.store(false, Ordering::Release);
+    }
+}
+
+impl<'a, T> Deref for SpinLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for SpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T> Drop for SpinLockGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+        if self.irq_enabled {
+            unsafe { irq::enable(); }
+        }
+    }
+}
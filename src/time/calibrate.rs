@@ -0,0 +1,49 @@
+use core::sync::atomic::Ordering;
+use crate::sync::SpinLock;
+use super::clocksource::ClockSource;
+use super::{hpet, pit, NANOS_PER_SEC};
+
+// Measuring how fast other counters (TSC, local APIC timer) run, against the
+// HPET when there is one and the PIT otherwise
+
+// PIT ticks to count, ~50ms at the default tick rate
+const CALIBRATION_TICKS: u64 = 50;
+const CALIBRATION_NANOS: u64 = 50_000_000;
+
+// held while calibrating, so no interrupt handler gets in between the reads
+static CALIBRATION: SpinLock<()> = SpinLock::new(());
+
+// Count over a number of PIT wraps, which doesn't need the tick interrupt
+fn against_pit(counter: &dyn Fn() -> u64) -> u64 {
+    let divisor = super::PIT_DIVISOR.load(Ordering::Relaxed) as u64;
+    // line up with the start of a tick first
+    pit::sleep_wraps(1);
+    let start = counter();
+    pit::sleep_wraps(CALIBRATION_TICKS);
+    let counts = counter().wrapping_sub(start);
+    (counts as u128 * pit::PIT_FREQUENCY as u128 / (CALIBRATION_TICKS * divisor) as u128) as u64
+}
+
+// Count while `reference` advances by CALIBRATION_NANOS worth of counts
+fn against(reference: &dyn ClockSource, counter: &dyn Fn() -> u64) -> u64 {
+    let target = (CALIBRATION_NANOS as u128 * reference.frequency() as u128 / NANOS_PER_SEC as u128) as u64;
+    let start_ref = reference.read();
+    let start = counter();
+    let mut elapsed = 0;
+    while elapsed < target {
+        core::hint::spin_loop();
+        elapsed = reference.read().wrapping_sub(start_ref) & reference.mask();
+    }
+    let counts = counter().wrapping_sub(start);
+    (counts as u128 * reference.frequency() as u128 / elapsed as u128) as u64
+}
+
+// Frequency of an up-counting `counter` in Hz. Needs the PIT programmed by
+// `time::init`, but not its interrupt.
+pub fn frequency_of(counter: &dyn Fn() -> u64) -> u64 {
+    let _irqs_off = CALIBRATION.lock();
+    match hpet::frequency() {
+        0 => against_pit(counter),
+        _ => against(&hpet::HPET_CLOCK, counter),
+    }
+}
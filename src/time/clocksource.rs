@@ -0,0 +1,136 @@
+use core::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use super::NANOS_PER_SEC;
+
+// Free running counters that can tell the time between ticks. Every source
+// registers itself with a rating and the best one becomes the system clock.
+// `now_nanos` runs from anywhere, the logger included, so nothing here takes
+// a lock: sources are only registered at boot and the base the active source
+// counts from sits behind a sequence counter.
+
+const MAX_SOURCES: usize = 8;
+const NO_SOURCE: usize = usize::MAX;
+
+pub trait ClockSource: Sync {
+    fn name(&self) -> &'static str;
+    // higher is better
+    fn rating(&self) -> u32;
+    fn read(&self) -> u64;
+    // counts per second
+    fn frequency(&self) -> u64;
+    // counters narrower than 64 bits wrap around at this
+    fn mask(&self) -> u64 {
+        u64::MAX
+    }
+}
+
+static mut SOURCES: [Option<&'static dyn ClockSource>; MAX_SOURCES] = [None; MAX_SOURCES];
+static SOURCE_COUNT: AtomicUsize = AtomicUsize::new(0);
+static ACTIVE: AtomicUsize = AtomicUsize::new(NO_SOURCE);
+
+// odd while the base is being written
+static SEQUENCE: AtomicU32 = AtomicU32::new(0);
+static BASE_CYCLES: AtomicU64 = AtomicU64::new(0);
+static BASE_NANOS: AtomicU64 = AtomicU64::new(0);
+// latest time handed out, so switching sources never goes backwards
+static LAST_NANOS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug)]
+pub enum ClockSourceError {
+    TooManySources,
+    ZeroFrequency,
+}
+
+fn source(index: usize) -> Option<&'static dyn ClockSource> {
+    match index < SOURCE_COUNT.load(Ordering::Acquire) {
+        true => unsafe { SOURCES[index] },
+        false => None,
+    }
+}
+
+fn cycles_to_nanos(cycles: u64, frequency: u64) -> u64 {
+    (cycles as u128 * NANOS_PER_SEC as u128 / frequency as u128) as u64
+}
+
+fn read_base() -> (u64, u64) {
+    loop {
+        let sequence = SEQUENCE.load(Ordering::Acquire);
+        if sequence & 1 != 0 {
+            core::hint::spin_loop();
+            continue;
+        }
+        let cycles = BASE_CYCLES.load(Ordering::Acquire);
+        let nanos = BASE_NANOS.load(Ordering::Acquire);
+        if SEQUENCE.load(Ordering::Acquire) == sequence {
+            return (cycles, nanos);
+        }
+    }
+}
+
+// Only the tick handler and `select` write the base, never at the same time
+fn write_base(cycles: u64, nanos: u64) {
+    SEQUENCE.fetch_add(1, Ordering::AcqRel);
+    BASE_CYCLES.store(cycles, Ordering::Release);
+    BASE_NANOS.store(nanos, Ordering::Release);
+    SEQUENCE.fetch_add(1, Ordering::AcqRel);
+}
+
+fn nanos_from(source: &dyn ClockSource, cycles: u64) -> u64 {
+    let (base_cycles, base_nanos) = read_base();
+    let delta = cycles.wrapping_sub(base_cycles) & source.mask();
+    base_nanos + cycles_to_nanos(delta, source.frequency())
+}
+
+pub fn register(source: &'static dyn ClockSource) -> Result<(), ClockSourceError> {
+    if source.frequency() == 0 {
+        return Err(ClockSourceError::ZeroFrequency);
+    }
+    let count = SOURCE_COUNT.load(Ordering::Acquire);
+    if count == MAX_SOURCES {
+        return Err(ClockSourceError::TooManySources);
+    }
+    unsafe { SOURCES[count] = Some(source); }
+    SOURCE_COUNT.store(count + 1, Ordering::Release);
+    Ok(())
+}
+
+// Switch to the highest rated source, carrying the current time over to it
+pub fn select() -> Option<&'static dyn ClockSource> {
+    let best = (0..SOURCE_COUNT.load(Ordering::Acquire))
+        .filter_map(|i| source(i).map(|s| (i, s)))
+        .max_by_key(|(_, s)| s.rating())?;
+    if ACTIVE.load(Ordering::Acquire) != best.0 {
+        let now = now_nanos();
+        write_base(best.1.read(), now);
+        ACTIVE.store(best.0, Ordering::Release);
+    }
+    Some(best.1)
+}
+
+pub fn active() -> Option<&'static dyn ClockSource> {
+    source(ACTIVE.load(Ordering::Acquire))
+}
+
+// Move the base up before a narrow counter gets the chance to wrap past it.
+// Called every tick.
+pub fn update() {
+    let source = match active() {
+        Some(source) => source,
+        None => return,
+    };
+    let cycles = source.read();
+    let (base_cycles, _) = read_base();
+    if cycles.wrapping_sub(base_cycles) & source.mask() < source.mask() / 2 {
+        return;
+    }
+    write_base(cycles, nanos_from(source, cycles));
+}
+
+// Nanoseconds since the first source was selected
+pub fn now_nanos() -> u64 {
+    let nanos = match active() {
+        Some(source) => nanos_from(source, source.read()),
+        None => 0,
+    };
+    let last = LAST_NANOS.fetch_max(nanos, Ordering::AcqRel);
+    nanos.max(last)
+}
@@ -0,0 +1,277 @@
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use x86::bits64::paging::PAddr;
+use crate::acpi::hpet::HpetTable;
+use crate::acpi::madt::IntiFlags;
+use crate::apic::{self, GsiError};
+use crate::interrupts::{self, IntHandler, InterruptStackFrame, IrqError};
+use crate::memory::mmio;
+use crate::memory::vmm::MapError;
+use crate::sync::SpinLock;
+use super::clocksource::ClockSource;
+use super::{Duration, NANOS_PER_SEC};
+
+// https://wiki.osdev.org/HPET
+// IA-PC HPET specification 1.0a
+
+const REGS_SIZE: usize = 0x400;
+
+const REG_CAPABILITIES: u64 = 0x000;
+const REG_CONFIG: u64 = 0x010;
+const REG_MAIN_COUNTER: u64 = 0x0F0;
+// per comparator registers, one block of them every TIMER_STRIDE
+const REG_TIMER_CONFIG: u64 = 0x100;
+const REG_TIMER_COMPARATOR: u64 = 0x108;
+const TIMER_STRIDE: u64 = 0x20;
+
+const CAP_COMPARATORS_SHIFT: u64 = 8;
+const CAP_COMPARATORS_MASK: u64 = 0x1F;
+const CAP_COUNTER_64BIT: u64 = 1 << 13;
+// counter period in femtoseconds
+const CAP_PERIOD_SHIFT: u64 = 32;
+
+const CONFIG_ENABLE: u64 = 1 << 0;
+// comparators 0 and 1 take over IRQ0 and IRQ8
+const CONFIG_LEGACY_REPLACEMENT: u64 = 1 << 1;
+
+const TIMER_LEVEL_TRIGGERED: u64 = 1 << 1;
+const TIMER_INT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAPABLE: u64 = 1 << 4;
+// the next comparator write after this sets the period instead
+const TIMER_VALUE_SET: u64 = 1 << 6;
+const TIMER_ROUTE_SHIFT: u64 = 9;
+const TIMER_ROUTE_MASK: u64 = 0x1F << TIMER_ROUTE_SHIFT;
+const TIMER_FSB_ENABLE: u64 = 1 << 14;
+// bit n set if the comparator can be wired to I/O APIC input n
+const TIMER_ROUTE_CAP_SHIFT: u64 = 32;
+
+const FEMTOS_PER_SEC: u64 = 1_000_000_000_000_000;
+// the spec doesn't allow anything slower than 10MHz
+const MAX_PERIOD_FS: u64 = 100_000_000;
+
+// comparators we have interrupt handlers for
+const MAX_COMPARATORS: usize = 4;
+
+#[derive(Debug)]
+pub enum HpetError {
+    NoTable,
+    Map(MapError),
+    BadPeriod(u64),
+    NotInitialized,
+    NoSuchComparator(u8),
+    NotPeriodic(u8),
+    // none of the I/O APIC inputs the comparator can use are free
+    NoRoute(u8),
+    Irq(IrqError),
+    Gsi(GsiError),
+}
+
+// MMIO base, 0 until `init`
+static BASE: AtomicU64 = AtomicU64::new(0);
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+static COUNTER_MASK: AtomicU64 = AtomicU64::new(0);
+static MIN_TICK: AtomicU64 = AtomicU64::new(0);
+static COMPARATOR_COUNT: AtomicU8 = AtomicU8::new(0);
+
+// Where a comparator's interrupt goes once it's been used
+#[derive(Clone, Copy)]
+struct Comparator {
+    gsi: u32,
+    vector: u8,
+    handler: Option<fn()>,
+}
+
+static COMPARATORS: SpinLock<[Option<Comparator>; MAX_COMPARATORS]> = SpinLock::new([None; MAX_COMPARATORS]);
+
+fn read(reg: u64) -> u64 {
+    unsafe { read_volatile((BASE.load(Ordering::Relaxed) + reg) as *const u64) }
+}
+
+fn write(reg: u64, value: u64) {
+    unsafe { write_volatile((BASE.load(Ordering::Relaxed) + reg) as *mut u64, value) }
+}
+
+fn timer_reg(n: u8, reg: u64) -> u64 {
+    reg + n as u64 * TIMER_STRIDE
+}
+
+pub fn counter() -> u64 {
+    read(REG_MAIN_COUNTER) & COUNTER_MASK.load(Ordering::Relaxed)
+}
+
+pub fn frequency() -> u64 {
+    FREQUENCY.load(Ordering::Relaxed)
+}
+
+pub fn comparators() -> u8 {
+    COMPARATOR_COUNT.load(Ordering::Relaxed)
+}
+
+fn ticks_for(duration: Duration) -> u64 {
+    (duration.as_nanos() * frequency() as u128 / NANOS_PER_SEC as u128) as u64
+}
+
+fn comparator_interrupt(n: usize) {
+    let handler = COMPARATORS.lock()[n].and_then(|comparator| comparator.handler);
+    if let Some(handler) = handler {
+        handler();
+    }
+    if let Some(lapic) = apic::local_apic() {
+        lapic.eoi();
+    }
+}
+
+extern "x86-interrupt" fn comparator_handler<const N: usize>(_frame: InterruptStackFrame) {
+    comparator_interrupt(N);
+}
+
+const HANDLERS: [IntHandler; MAX_COMPARATORS] = [
+    comparator_handler::<0>,
+    comparator_handler::<1>,
+    comparator_handler::<2>,
+    comparator_handler::<3>,
+];
+
+// Wire comparator `n` to the first free I/O APIC input it supports, edge
+// triggered, and give it a vector. Only done the first time it's used.
+fn route(n: u8) -> Result<Comparator, HpetError> {
+    // held throughout, so two callers can't both claim a GSI and vector for `n`
+    let mut comparators = COMPARATORS.lock();
+    if let Some(comparator) = comparators[n as usize] {
+        return Ok(comparator);
+    }
+    let config = read(timer_reg(n, REG_TIMER_CONFIG));
+    let capable = (config >> TIMER_ROUTE_CAP_SHIFT) as u32;
+    let gsi = (0..32).find(|&gsi| capable & 1 << gsi != 0 && apic::gsi_free(gsi)).ok_or(HpetError::NoRoute(n))?;
+    let vector = interrupts::allocate_irq(HANDLERS[n as usize]).map_err(HpetError::Irq)?;
+    let flags = IntiFlags { active_low: false, level_triggered: false };
+    if let Err(e) = apic::claim_gsi(gsi, vector, flags) {
+        let _ = interrupts::unregister_irq(vector);
+        return Err(HpetError::Gsi(e));
+    }
+    let config = config & !(TIMER_ROUTE_MASK | TIMER_FSB_ENABLE | TIMER_LEVEL_TRIGGERED);
+    write(timer_reg(n, REG_TIMER_CONFIG), config | (gsi as u64) << TIMER_ROUTE_SHIFT);
+    let comparator = Comparator { gsi, vector, handler: None };
+    comparators[n as usize] = Some(comparator);
+    Ok(comparator)
+}
+
+fn check_comparator(n: u8) -> Result<(), HpetError> {
+    if BASE.load(Ordering::Relaxed) == 0 {
+        return Err(HpetError::NotInitialized);
+    }
+    if n >= comparators() || n as usize >= MAX_COMPARATORS {
+        return Err(HpetError::NoSuchComparator(n));
+    }
+    Ok(())
+}
+
+fn set_handler(n: u8, handler: fn()) -> Result<Comparator, HpetError> {
+    let comparator = route(n)?;
+    if let Some(comparator) = COMPARATORS.lock()[n as usize].as_mut() {
+        comparator.handler = Some(handler);
+    }
+    Ok(comparator)
+}
+
+// Call `handler` from comparator `n`'s interrupt once, `delay` from now
+pub fn start_oneshot(n: u8, delay: Duration, handler: fn()) -> Result<(), HpetError> {
+    check_comparator(n)?;
+    let comparator = set_handler(n, handler)?;
+    let reg = timer_reg(n, REG_TIMER_CONFIG);
+    write(reg, (read(reg) & !TIMER_PERIODIC) | TIMER_INT_ENABLE);
+    write(timer_reg(n, REG_TIMER_COMPARATOR), (counter() + ticks_for(delay).max(1)) & COUNTER_MASK.load(Ordering::Relaxed));
+    apic::set_gsi_masked(comparator.gsi, false);
+    Ok(())
+}
+
+// Call `handler` from comparator `n`'s interrupt every `period`, if the
+// comparator can do periodic mode at all
+pub fn start_periodic(n: u8, period: Duration, handler: fn()) -> Result<(), HpetError> {
+    check_comparator(n)?;
+    let reg = timer_reg(n, REG_TIMER_CONFIG);
+    if read(reg) & TIMER_PERIODIC_CAPABLE == 0 {
+        return Err(HpetError::NotPeriodic(n));
+    }
+    let comparator = set_handler(n, handler)?;
+    let ticks = ticks_for(period).max(MIN_TICK.load(Ordering::Relaxed)).max(1);
+    write(reg, read(reg) | TIMER_INT_ENABLE | TIMER_PERIODIC | TIMER_VALUE_SET);
+    // first deadline, then the period to add after each one
+    write(timer_reg(n, REG_TIMER_COMPARATOR), (counter() + ticks) & COUNTER_MASK.load(Ordering::Relaxed));
+    write(timer_reg(n, REG_TIMER_COMPARATOR), ticks);
+    apic::set_gsi_masked(comparator.gsi, false);
+    Ok(())
+}
+
+pub fn stop(n: u8) -> Result<(), HpetError> {
+    check_comparator(n)?;
+    let reg = timer_reg(n, REG_TIMER_CONFIG);
+    write(reg, read(reg) & !(TIMER_INT_ENABLE | TIMER_PERIODIC));
+    if let Some(comparator) = COMPARATORS.lock()[n as usize].as_mut() {
+        apic::set_gsi_masked(comparator.gsi, true);
+        comparator.handler = None;
+    }
+    Ok(())
+}
+
+// Map the timer block the ACPI tables point at and start its main counter
+// from 0, with every comparator quiet and legacy replacement off so the PIT
+// and RTC keep their IRQs
+pub fn init() -> Result<(), HpetError> {
+    let table = HpetTable::find().ok_or(HpetError::NoTable)?;
+    let base = mmio::map_mmio(PAddr(table.base_address), REGS_SIZE).map_err(HpetError::Map)?;
+    BASE.store(base.as_u64(), Ordering::Relaxed);
+
+    let caps = read(REG_CAPABILITIES);
+    let period = caps >> CAP_PERIOD_SHIFT;
+    if period == 0 || period > MAX_PERIOD_FS {
+        BASE.store(0, Ordering::Relaxed);
+        return Err(HpetError::BadPeriod(period));
+    }
+    let count = ((caps >> CAP_COMPARATORS_SHIFT) & CAP_COMPARATORS_MASK) as u8 + 1;
+
+    write(REG_CONFIG, read(REG_CONFIG) & !(CONFIG_ENABLE | CONFIG_LEGACY_REPLACEMENT));
+    for n in 0..count {
+        let reg = timer_reg(n, REG_TIMER_CONFIG);
+        write(reg, read(reg) & !(TIMER_INT_ENABLE | TIMER_PERIODIC | TIMER_FSB_ENABLE));
+    }
+    write(REG_MAIN_COUNTER, 0);
+    write(REG_CONFIG, read(REG_CONFIG) | CONFIG_ENABLE);
+
+    let mask = match caps & CAP_COUNTER_64BIT {
+        0 => u32::MAX as u64,
+        _ => u64::MAX,
+    };
+    COUNTER_MASK.store(mask, Ordering::Relaxed);
+    FREQUENCY.store(FEMTOS_PER_SEC / period, Ordering::Relaxed);
+    MIN_TICK.store(table.minimum_tick as u64, Ordering::Relaxed);
+    COMPARATOR_COUNT.store(count, Ordering::Relaxed);
+    Ok(())
+}
+
+pub struct HpetClock;
+
+pub static HPET_CLOCK: HpetClock = HpetClock;
+
+impl ClockSource for HpetClock {
+    fn name(&self) -> &'static str {
+        "hpet"
+    }
+
+    fn rating(&self) -> u32 {
+        250
+    }
+
+    fn read(&self) -> u64 {
+        counter()
+    }
+
+    fn frequency(&self) -> u64 {
+        frequency()
+    }
+
+    fn mask(&self) -> u64 {
+        COUNTER_MASK.load(Ordering::Relaxed)
+    }
+}
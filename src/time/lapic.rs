@@ -0,0 +1,136 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use x86::cpuid::CpuId;
+use x86::msr::{wrmsr, IA32_TSC_DEADLINE};
+use crate::apic::{self, LocalApic, LVT_MASKED, REG_LVT_TIMER, REG_TIMER_CURRENT, REG_TIMER_DIVIDE, REG_TIMER_INITIAL};
+use crate::interrupts::{self, InterruptStackFrame, IrqError};
+use crate::sync::SpinLock;
+use super::{calibrate, tsc, Duration, NANOS_PER_SEC};
+
+// https://wiki.osdev.org/APIC_Timer
+// Intel SDM vol. 3A, section 10.5.4
+
+const MODE_ONESHOT: u32 = 0b00 << 17;
+const MODE_PERIODIC: u32 = 0b01 << 17;
+const MODE_TSC_DEADLINE: u32 = 0b10 << 17;
+
+// divide configuration for the bus clock, 0b0011 is by 16
+const DIVIDE_BY_16: u32 = 0x3;
+
+#[derive(Debug)]
+pub enum LapicTimerError {
+    NoLocalApic,
+    Irq(IrqError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    Periodic,
+    OneShot,
+    TscDeadline,
+}
+
+static VECTOR: AtomicU8 = AtomicU8::new(0);
+// timer counts per second, after the divider
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+static TSC_DEADLINE: AtomicBool = AtomicBool::new(false);
+static HANDLER: SpinLock<Option<fn()>> = SpinLock::new(None);
+
+fn lapic() -> Option<LocalApic> {
+    apic::local_apic()
+}
+
+extern "x86-interrupt" fn timer_handler(_frame: InterruptStackFrame) {
+    let handler = *HANDLER.lock();
+    if let Some(handler) = handler {
+        handler();
+    }
+    if let Some(lapic) = lapic() {
+        lapic.eoi();
+    }
+}
+
+pub fn frequency() -> u64 {
+    FREQUENCY.load(Ordering::Relaxed)
+}
+
+// Deadlines can be given in TSC cycles, with no counter to run out
+pub fn has_tsc_deadline() -> bool {
+    TSC_DEADLINE.load(Ordering::Relaxed)
+}
+
+pub fn is_initialized() -> bool {
+    frequency() != 0
+}
+
+// What the timer calls on every expiry, from interrupt context
+pub fn set_handler(handler: fn()) {
+    *HANDLER.lock() = Some(handler);
+}
+
+fn counts_for(duration: Duration) -> u32 {
+    let counts = duration.as_nanos() * frequency() as u128 / NANOS_PER_SEC as u128;
+    counts.clamp(1, u32::MAX as u128) as u32
+}
+
+fn set_lvt(lapic: &LocalApic, mode: u32) {
+    lapic.write(REG_LVT_TIMER, mode | VECTOR.load(Ordering::Relaxed) as u32);
+}
+
+// Fire every `period`, as close as the timer's resolution gets
+pub fn start_periodic(period: Duration) {
+    if let Some(lapic) = lapic() {
+        set_lvt(&lapic, MODE_PERIODIC);
+        lapic.write(REG_TIMER_INITIAL, counts_for(period));
+    }
+}
+
+// Fire once, `delay` from now. Delays too long for the 32-bit counter fire
+// early and the handler has to notice.
+pub fn start_oneshot(delay: Duration) {
+    if let Some(lapic) = lapic() {
+        set_lvt(&lapic, MODE_ONESHOT);
+        lapic.write(REG_TIMER_INITIAL, counts_for(delay));
+    }
+}
+
+// Fire once the TSC reaches `deadline`, a deadline in the past fires right away
+pub fn start_deadline(deadline: u64) {
+    if let Some(lapic) = lapic() {
+        set_lvt(&lapic, MODE_TSC_DEADLINE);
+        unsafe { wrmsr(IA32_TSC_DEADLINE, deadline.max(1)); }
+    }
+}
+
+pub fn stop() {
+    if let Some(lapic) = lapic() {
+        match has_tsc_deadline() {
+            true => unsafe { wrmsr(IA32_TSC_DEADLINE, 0) },
+            false => lapic.write(REG_TIMER_INITIAL, 0),
+        }
+        lapic.write(REG_LVT_TIMER, LVT_MASKED | VECTOR.load(Ordering::Relaxed) as u32);
+    }
+}
+
+// Calibrate the timer and give it a vector, leaving it stopped. TSC-deadline
+// mode is only used when the TSC is invariant and calibrated.
+pub fn init() -> Result<Mode, LapicTimerError> {
+    let lapic = lapic().ok_or(LapicTimerError::NoLocalApic)?;
+    let vector = interrupts::allocate_irq(timer_handler).map_err(LapicTimerError::Irq)?;
+    VECTOR.store(vector, Ordering::Relaxed);
+
+    // count down from the top, masked, while measuring
+    lapic.write(REG_TIMER_DIVIDE, DIVIDE_BY_16);
+    lapic.write(REG_LVT_TIMER, LVT_MASKED | MODE_ONESHOT | vector as u32);
+    lapic.write(REG_TIMER_INITIAL, u32::MAX);
+    let elapsed = || (u32::MAX - lapic.read(REG_TIMER_CURRENT)) as u64;
+    FREQUENCY.store(calibrate::frequency_of(&elapsed), Ordering::Relaxed);
+    lapic.write(REG_TIMER_INITIAL, 0);
+
+    let deadline = CpuId::new().get_feature_info().map_or(false, |f| f.has_tsc_deadline());
+    TSC_DEADLINE.store(deadline && tsc::frequency() != 0, Ordering::Relaxed);
+    stop();
+    Ok(match has_tsc_deadline() {
+        true => Mode::TscDeadline,
+        false => Mode::OneShot,
+    })
+}
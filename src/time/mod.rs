@@ -0,0 +1,171 @@
+use core::ops::{Add, AddAssign, Sub};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+pub use core::time::Duration;
+use x86::bits64::rflags::{self, RFlags};
+use crate::interrupts::{InterruptStackFrame, IDT};
+use crate::irqchip;
+use clocksource::ClockSource;
+pub mod calibrate;
+pub mod clocksource;
+pub mod hpet;
+pub mod lapic;
+pub mod pit;
+pub mod rtc;
+pub mod timer;
+pub mod tsc;
+
+pub const NANOS_PER_SEC: u64 = 1_000_000_000;
+pub const DEFAULT_TICK_HZ: u32 = 1000;
+
+// timer interrupts since `init`
+static JIFFIES: AtomicU64 = AtomicU64::new(0);
+// what the PIT actually runs at, which is only close to what was asked for
+static TICK_NANOS: AtomicU64 = AtomicU64::new(0);
+static PIT_DIVISOR: AtomicU32 = AtomicU32::new(0);
+// the tick is off and jiffies come from the clocksource instead
+static TICKLESS: AtomicBool = AtomicBool::new(false);
+
+extern "x86-interrupt" fn tick_handler(_frame: InterruptStackFrame) {
+    JIFFIES.fetch_add(1, Ordering::Relaxed);
+    clocksource::update();
+    timer::tick();
+    irqchip::eoi(pit::PIT_IRQ);
+}
+
+// Start the system tick at roughly `hz` interrupts per second
+pub fn init(hz: u32) {
+    let divisor = pit::divisor_for(hz);
+    TICK_NANOS.store(divisor as u64 * NANOS_PER_SEC / pit::PIT_FREQUENCY as u64, Ordering::Relaxed);
+    PIT_DIVISOR.store(divisor, Ordering::Relaxed);
+    IDT.lock().programmable_timer.set_handler(tick_handler);
+    pit::start_periodic(divisor);
+    irqchip::unmask(pit::PIT_IRQ);
+    info!("PIT tick at {} Hz", tick_rate());
+    register_clocksources();
+    timer::init();
+    match rtc::init() {
+        Ok(now) => info!("RTC says {} UTC", now),
+        Err(e) => warn!("no wall clock: {:?}", e),
+    }
+}
+
+// The PIT always works. The HPET, when there is one, is the better clock and
+// what the TSC gets calibrated against. The TSC only counts as a clock if its
+// rate holds steady whatever the CPU does.
+fn register_clocksources() {
+    let _ = clocksource::register(&pit::PIT_CLOCK);
+    match hpet::init() {
+        Ok(()) => {
+            info!("HPET at {} Hz, {} comparators", hpet::frequency(), hpet::comparators());
+            let _ = clocksource::register(&hpet::HPET_CLOCK);
+        }
+        Err(e) => warn!("no HPET: {:?}", e),
+    }
+    match tsc::init() {
+        Ok(hz) => {
+            info!("TSC at {}.{:03} MHz", hz / 1_000_000, hz / 1000 % 1000);
+            let _ = clocksource::register(&tsc::TSC_CLOCK);
+        }
+        Err(e) => warn!("not using the TSC as a clock: {:?}", e),
+    }
+    if let Some(source) = clocksource::select() {
+        info!("clocksource: {}", source.name());
+    }
+}
+
+// Stop the periodic tick, unless the PIT is what keeps the time
+fn stop_tick() -> bool {
+    match clocksource::active() {
+        Some(source) if source.name() != pit::PIT_CLOCK.name() => {}
+        _ => return false,
+    }
+    irqchip::mask(pit::PIT_IRQ);
+    TICKLESS.store(true, Ordering::Relaxed);
+    true
+}
+
+pub fn jiffies() -> u64 {
+    match TICKLESS.load(Ordering::Relaxed) {
+        true => clocksource::now_nanos() / TICK_NANOS.load(Ordering::Relaxed),
+        false => JIFFIES.load(Ordering::Relaxed),
+    }
+}
+
+pub fn tick_duration() -> Duration {
+    Duration::from_nanos(TICK_NANOS.load(Ordering::Relaxed))
+}
+
+pub fn tick_rate() -> u32 {
+    match PIT_DIVISOR.load(Ordering::Relaxed) {
+        0 => 0,
+        divisor => pit::PIT_FREQUENCY / divisor,
+    }
+}
+
+// Busy-wait for `ticks` timer ticks. With interrupts off the tick counter
+// stands still, so then watch the PIT count down instead.
+pub fn sleep_ticks(ticks: u64) {
+    if rflags::read().contains(RFlags::FLAGS_IF) {
+        let end = jiffies() + ticks;
+        while jiffies() < end {
+            core::hint::spin_loop();
+        }
+        return;
+    }
+    pit::sleep_wraps(ticks);
+}
+
+// Point in time since `init` to the nanosecond, as far as the clocksource
+// can tell. Only ever goes forward.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant {
+    nanos: u64,
+}
+
+impl Instant {
+    pub fn now() -> Self {
+        Instant { nanos: clocksource::now_nanos() }
+    }
+
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.nanos.saturating_sub(earlier.nanos))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    pub fn as_nanos(&self) -> u64 {
+        self.nanos
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Instant {
+        Instant { nanos: self.nanos + rhs.as_nanos() as u64 }
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, rhs: Duration) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, rhs: Duration) -> Instant {
+        Instant { nanos: self.nanos.saturating_sub(rhs.as_nanos() as u64) }
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Duration {
+        self.duration_since(rhs)
+    }
+}
@@ -0,0 +1,93 @@
+use x86::io::{inb, outb};
+use core::sync::atomic::Ordering;
+use crate::sync::SpinLock;
+use super::clocksource::ClockSource;
+
+// https://wiki.osdev.org/Programmable_Interval_Timer
+
+pub const PIT_FREQUENCY: u32 = 1_193_182;
+pub const PIT_IRQ: u8 = 0;
+
+const CHANNEL0_DATA: u16 = 0x40;
+const MODE_COMMAND: u16 = 0x43;
+
+// channel 0, low then high byte, mode 2 (rate generator), binary
+const CMD_CHANNEL0_RATE: u8 = 0x34;
+// channel 0, latch the current count
+const CMD_CHANNEL0_LATCH: u8 = 0x00;
+
+// the mode/command port is shared by all channels and reads take two steps
+static PORTS: SpinLock<()> = SpinLock::new(());
+
+// Divisor for the closest rate to `hz` the PIT can do. 0 stands for 65536.
+pub fn divisor_for(hz: u32) -> u32 {
+    (PIT_FREQUENCY / hz.max(1)).clamp(1, 0x10000)
+}
+
+// Start channel 0 counting down from `divisor`, firing IRQ0 every time it wraps
+pub fn start_periodic(divisor: u32) {
+    let _ports = PORTS.lock();
+    unsafe {
+        outb(MODE_COMMAND, CMD_CHANNEL0_RATE);
+        outb(CHANNEL0_DATA, divisor as u8);
+        outb(CHANNEL0_DATA, (divisor >> 8) as u8);
+    }
+}
+
+// Where channel 0 is in its countdown
+pub fn read_count() -> u16 {
+    let _ports = PORTS.lock();
+    unsafe {
+        outb(MODE_COMMAND, CMD_CHANNEL0_LATCH);
+        let low = inb(CHANNEL0_DATA);
+        let high = inb(CHANNEL0_DATA);
+        u16::from_le_bytes([low, high])
+    }
+}
+
+// Spin until channel 0 has wrapped `wraps` times. The count jumps back up to
+// the divisor every time it does.
+pub fn sleep_wraps(wraps: u64) {
+    let mut remaining = wraps;
+    let mut last = read_count();
+    while remaining > 0 {
+        let count = read_count();
+        if count > last {
+            remaining -= 1;
+        }
+        last = count;
+        core::hint::spin_loop();
+    }
+}
+
+// The tick count and how far channel 0 is into the current tick, as one counter.
+// Coarse and slow to read, but always there.
+pub struct PitClock;
+
+pub static PIT_CLOCK: PitClock = PitClock;
+
+impl ClockSource for PitClock {
+    fn name(&self) -> &'static str {
+        "pit"
+    }
+
+    fn rating(&self) -> u32 {
+        100
+    }
+
+    fn read(&self) -> u64 {
+        let divisor = super::PIT_DIVISOR.load(Ordering::Relaxed) as u64;
+        loop {
+            let jiffies = super::JIFFIES.load(Ordering::Relaxed);
+            let count = read_count() as u64;
+            // a tick in between makes the count and jiffies disagree
+            if super::JIFFIES.load(Ordering::Relaxed) == jiffies {
+                return jiffies * divisor + divisor.saturating_sub(count);
+            }
+        }
+    }
+
+    fn frequency(&self) -> u64 {
+        PIT_FREQUENCY as u64
+    }
+}
@@ -0,0 +1,260 @@
+use core::fmt;
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use x86::io::{inb, outb};
+use crate::acpi::fadt::Fadt;
+use crate::interrupts::{InterruptStackFrame, IDT};
+use crate::irqchip;
+use crate::sync::SpinLock;
+use super::{clocksource, NANOS_PER_SEC};
+
+// https://wiki.osdev.org/CMOS
+// https://wiki.osdev.org/RTC
+
+pub const RTC_IRQ: u8 = 8;
+
+const CMOS_INDEX: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0A;
+const REG_STATUS_B: u8 = 0x0B;
+// reading it acknowledges the interrupt, nothing else comes until then
+const REG_STATUS_C: u8 = 0x0C;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_A_RATE_MASK: u8 = 0x0F;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const STATUS_B_PERIODIC: u8 = 1 << 6;
+// set in the hours register for PM in 12-hour mode
+const HOUR_PM: u8 = 1 << 7;
+
+// periodic interrupt at 32768 >> (rate - 1) Hz; 1 and 2 misbehave
+pub const MIN_RATE: u8 = 3;
+pub const MAX_RATE: u8 = 15;
+const BASE_FREQUENCY: u32 = 32768;
+
+// for RTCs without a century register
+const DEFAULT_CENTURY: u16 = 20;
+
+const SECS_PER_DAY: u64 = 86_400;
+
+#[derive(Debug)]
+pub enum RtcError {
+    NotPresent,
+    BadRate(u8),
+}
+
+// index and data port have to be used in pairs
+static CMOS: SpinLock<()> = SpinLock::new(());
+// CMOS register with the century, 0 for none
+static CENTURY_REGISTER: AtomicU8 = AtomicU8::new(0);
+// Unix time in nanoseconds when the clocksource read 0, 0 until `init`
+static BOOT_UNIX_NANOS: AtomicU64 = AtomicU64::new(0);
+static PERIODIC_HANDLER: SpinLock<Option<fn()>> = SpinLock::new(None);
+
+fn read_register(reg: u8) -> u8 {
+    unsafe {
+        outb(CMOS_INDEX, reg);
+        inb(CMOS_DATA)
+    }
+}
+
+fn write_register(reg: u8, value: u8) {
+    unsafe {
+        outb(CMOS_INDEX, reg);
+        outb(CMOS_DATA, value);
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0F)
+}
+
+// Broken down UTC time, as the RTC keeps it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+// Days between 1970-01-01 and the given date in the proleptic Gregorian
+// calendar, http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+impl DateTime {
+    // Seconds since 1970-01-01 00:00:00 UTC
+    pub fn unix_timestamp(&self) -> u64 {
+        let days = days_from_civil(self.year as i64, self.month, self.day).max(0) as u64;
+        days * SECS_PER_DAY + self.hour as u64 * 3600 + self.minute as u64 * 60 + self.second as u64
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+// The registers exactly as the RTC has them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RawTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+fn wait_for_update() {
+    while read_register(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+fn read_raw() -> RawTime {
+    wait_for_update();
+    let century_reg = CENTURY_REGISTER.load(Ordering::Relaxed);
+    RawTime {
+        second: read_register(REG_SECONDS),
+        minute: read_register(REG_MINUTES),
+        hour: read_register(REG_HOURS),
+        day: read_register(REG_DAY),
+        month: read_register(REG_MONTH),
+        year: read_register(REG_YEAR),
+        century: match century_reg {
+            0 => 0,
+            reg => read_register(reg),
+        },
+    }
+}
+
+// Current date and time. An update can still start right after the flag
+// was checked, so read until two reads in a row agree.
+pub fn read() -> DateTime {
+    let _cmos = CMOS.lock();
+    let mut raw = read_raw();
+    loop {
+        let again = read_raw();
+        if again == raw {
+            break;
+        }
+        raw = again;
+    }
+    let status_b = read_register(REG_STATUS_B);
+
+    let pm = raw.hour & HOUR_PM != 0;
+    let mut hour = raw.hour & !HOUR_PM;
+    let convert = |value: u8| match status_b & STATUS_B_BINARY {
+        0 => from_bcd(value),
+        _ => value,
+    };
+    hour = convert(hour);
+    // 12-hour mode runs 12, 1, ..., 11
+    if status_b & STATUS_B_24_HOUR == 0 {
+        hour = match pm {
+            true => hour % 12 + 12,
+            false => hour % 12,
+        };
+    }
+    let century = match CENTURY_REGISTER.load(Ordering::Relaxed) {
+        0 => DEFAULT_CENTURY,
+        _ => convert(raw.century) as u16,
+    };
+    DateTime {
+        year: century * 100 + convert(raw.year) as u16,
+        month: convert(raw.month),
+        day: convert(raw.day),
+        hour,
+        minute: convert(raw.minute),
+        second: convert(raw.second),
+    }
+}
+
+// Nanoseconds since the Unix epoch, counted on from the RTC reading at boot
+// with the clocksource. 0 if the RTC was never read.
+pub fn unix_nanos() -> u64 {
+    match BOOT_UNIX_NANOS.load(Ordering::Relaxed) {
+        0 => 0,
+        boot => boot + clocksource::now_nanos(),
+    }
+}
+
+extern "x86-interrupt" fn rtc_handler(_frame: InterruptStackFrame) {
+    {
+        let _cmos = CMOS.lock();
+        read_register(REG_STATUS_C);
+    }
+    let handler = *PERIODIC_HANDLER.lock();
+    if let Some(handler) = handler {
+        handler();
+    }
+    irqchip::eoi(RTC_IRQ);
+}
+
+pub fn periodic_frequency(rate: u8) -> u32 {
+    BASE_FREQUENCY >> (rate - 1)
+}
+
+// Call `handler` on IRQ8 at 32768 >> (rate - 1) Hz, from 8192 down to 2
+pub fn enable_periodic(rate: u8, handler: fn()) -> Result<(), RtcError> {
+    if !(MIN_RATE..=MAX_RATE).contains(&rate) {
+        return Err(RtcError::BadRate(rate));
+    }
+    *PERIODIC_HANDLER.lock() = Some(handler);
+    IDT.lock().cmos_rtc.set_handler(rtc_handler);
+    {
+        let _cmos = CMOS.lock();
+        let a = read_register(REG_STATUS_A);
+        write_register(REG_STATUS_A, (a & !STATUS_A_RATE_MASK) | rate);
+        let b = read_register(REG_STATUS_B);
+        write_register(REG_STATUS_B, b | STATUS_B_PERIODIC);
+        // anything left pending would keep the line from firing again
+        read_register(REG_STATUS_C);
+    }
+    irqchip::unmask(RTC_IRQ);
+    Ok(())
+}
+
+pub fn disable_periodic() {
+    irqchip::mask(RTC_IRQ);
+    let _cmos = CMOS.lock();
+    let b = read_register(REG_STATUS_B);
+    write_register(REG_STATUS_B, b & !STATUS_B_PERIODIC);
+    *PERIODIC_HANDLER.lock() = None;
+}
+
+// Read the wall clock once, using the century register the FADT names.
+// Needs ACPI and the clocksource up.
+pub fn init() -> Result<DateTime, RtcError> {
+    let fadt = Fadt::find();
+    if !fadt.map_or(true, |fadt| fadt.has_cmos_rtc()) {
+        return Err(RtcError::NotPresent);
+    }
+    let century = fadt.and_then(|fadt| fadt.century_register()).unwrap_or(0);
+    CENTURY_REGISTER.store(century, Ordering::Relaxed);
+    let now = read();
+    let unix = now.unix_timestamp() * NANOS_PER_SEC;
+    BOOT_UNIX_NANOS.store(unix.saturating_sub(clocksource::now_nanos()), Ordering::Relaxed);
+    Ok(now)
+}
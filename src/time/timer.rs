@@ -0,0 +1,159 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::sync::SpinLock;
+use super::clocksource;
+use super::{lapic, tsc, Duration, Instant, NANOS_PER_SEC};
+
+// One-shot high resolution timers. The local APIC timer is programmed for
+// whichever one is due first, so nothing fires in between unless there's
+// something to do. Without a local APIC the periodic tick checks them instead.
+
+const MAX_TIMERS: usize = 64;
+// keeps a 32-bit clocksource's base from falling a wrap behind once the
+// tick that used to move it along is gone
+const HOUSEKEEPING_PERIOD: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+pub enum TimerError {
+    Full,
+}
+
+// Handle for cancelling a timer. Stale handles of timers that already
+// fired don't match whatever took over their slot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId {
+    slot: usize,
+    generation: u64,
+}
+
+#[derive(Clone, Copy)]
+struct Timer {
+    deadline: Instant,
+    callback: fn(),
+    generation: u64,
+}
+
+struct Timers {
+    slots: [Option<Timer>; MAX_TIMERS],
+    generation: u64,
+}
+
+impl Timers {
+    fn next_deadline(&self) -> Option<Instant> {
+        self.slots.iter().flatten().map(|timer| timer.deadline).min()
+    }
+}
+
+static TIMERS: SpinLock<Timers> = SpinLock::new(Timers {
+    slots: [None; MAX_TIMERS],
+    generation: 0,
+});
+// the local APIC timer runs the timers rather than the tick
+static ONESHOT: AtomicBool = AtomicBool::new(false);
+
+// Point the local APIC timer at `deadline`, or stop it if there's none
+fn program(deadline: Option<Instant>) {
+    if !ONESHOT.load(Ordering::Relaxed) {
+        return;
+    }
+    let deadline = match deadline {
+        Some(deadline) => deadline,
+        None => return lapic::stop(),
+    };
+    let delay = deadline.duration_since(Instant::now());
+    match lapic::has_tsc_deadline() {
+        true => {
+            let cycles = delay.as_nanos() * tsc::frequency() as u128 / NANOS_PER_SEC as u128;
+            lapic::start_deadline(tsc::read().saturating_add(cycles as u64));
+        }
+        false => lapic::start_oneshot(delay),
+    }
+}
+
+// Call `callback` from interrupt context once `deadline` has passed
+pub fn add_timer(deadline: Instant, callback: fn()) -> Result<TimerId, TimerError> {
+    let mut timers = TIMERS.lock();
+    let slot = timers.slots.iter().position(|timer| timer.is_none()).ok_or(TimerError::Full)?;
+    timers.generation += 1;
+    let generation = timers.generation;
+    timers.slots[slot] = Some(Timer { deadline, callback, generation });
+    if timers.next_deadline() == Some(deadline) {
+        program(Some(deadline));
+    }
+    Ok(TimerId { slot, generation })
+}
+
+pub fn add_timer_after(delay: Duration, callback: fn()) -> Result<TimerId, TimerError> {
+    add_timer(Instant::now() + delay, callback)
+}
+
+// Returns false if the timer already fired or was cancelled
+pub fn cancel(id: TimerId) -> bool {
+    let mut timers = TIMERS.lock();
+    match timers.slots[id.slot] {
+        Some(timer) if timer.generation == id.generation => {
+            timers.slots[id.slot] = None;
+            let next = timers.next_deadline();
+            program(next);
+            true
+        }
+        _ => false,
+    }
+}
+
+pub fn next_deadline() -> Option<Instant> {
+    TIMERS.lock().next_deadline()
+}
+
+// Run every timer that is due. The callbacks run without the lock held, so
+// they can add timers of their own.
+pub fn run_expired() {
+    let mut due: [Option<fn()>; MAX_TIMERS] = [None; MAX_TIMERS];
+    {
+        let mut timers = TIMERS.lock();
+        let now = Instant::now();
+        for (slot, callback) in timers.slots.iter_mut().zip(due.iter_mut()) {
+            if let Some(timer) = slot.filter(|timer| timer.deadline <= now) {
+                *callback = Some(timer.callback);
+                *slot = None;
+            }
+        }
+        let next = timers.next_deadline();
+        program(next);
+    }
+    for callback in due.iter().flatten() {
+        callback();
+    }
+}
+
+// From the periodic tick, for as long as there is one
+pub fn tick() {
+    if !ONESHOT.load(Ordering::Relaxed) {
+        run_expired();
+    }
+}
+
+fn housekeeping() {
+    clocksource::update();
+    let _ = add_timer_after(HOUSEKEEPING_PERIOD, housekeeping);
+}
+
+// Hand the timers over to the local APIC timer and, when something better
+// than the PIT keeps the time, stop the periodic tick so an idle CPU sleeps
+// until the next timer is due
+pub fn init() {
+    match lapic::init() {
+        Ok(mode) => info!("local APIC timer at {} Hz, {:?}", lapic::frequency(), mode),
+        Err(e) => {
+            warn!("no local APIC timer, timers run off the tick: {:?}", e);
+            return;
+        }
+    }
+    lapic::set_handler(run_expired);
+    ONESHOT.store(true, Ordering::Relaxed);
+    if super::stop_tick() {
+        info!("periodic tick stopped");
+        let _ = add_timer_after(HOUSEKEEPING_PERIOD, housekeeping);
+    }
+    let next = next_deadline();
+    program(next);
+}
@@ -0,0 +1,69 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86::cpuid::CpuId;
+use x86::time::rdtsc;
+use super::clocksource::ClockSource;
+use super::calibrate;
+
+// https://wiki.osdev.org/TSC
+
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug)]
+pub enum TscError {
+    NoTsc,
+    // the TSC rate follows the CPU's P-state or stops in deep C-states
+    NotInvariant,
+}
+
+pub fn is_invariant() -> bool {
+    CpuId::new().get_advanced_power_mgmt_info().map_or(false, |apm| apm.has_invariant_tsc())
+}
+
+pub fn read() -> u64 {
+    unsafe { rdtsc() }
+}
+
+pub fn frequency() -> u64 {
+    FREQUENCY.load(Ordering::Relaxed)
+}
+
+// Newer CPUs say what their TSC runs at, hypervisors often don't
+fn cpuid_frequency() -> Option<u64> {
+    CpuId::new().get_tsc_info().and_then(|info| info.tsc_frequency()).filter(|&hz| hz != 0)
+}
+
+// Work out the TSC frequency, unless CPUID knows it
+pub fn init() -> Result<u64, TscError> {
+    let features = CpuId::new().get_feature_info();
+    if !features.map_or(false, |f| f.has_tsc()) {
+        return Err(TscError::NoTsc);
+    }
+    if !is_invariant() {
+        return Err(TscError::NotInvariant);
+    }
+    let hz = cpuid_frequency().unwrap_or_else(|| calibrate::frequency_of(&read));
+    FREQUENCY.store(hz, Ordering::Relaxed);
+    Ok(hz)
+}
+
+pub struct TscClock;
+
+pub static TSC_CLOCK: TscClock = TscClock;
+
+impl ClockSource for TscClock {
+    fn name(&self) -> &'static str {
+        "tsc"
+    }
+
+    fn rating(&self) -> u32 {
+        300
+    }
+
+    fn read(&self) -> u64 {
+        read()
+    }
+
+    fn frequency(&self) -> u64 {
+        frequency()
+    }
+}
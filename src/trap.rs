@@ -0,0 +1,319 @@
+use core::arch::global_asm;
+use core::fmt;
+use x86::controlregs;
+use x86::segmentation;
+use crate::interrupts::{InterruptDescriptorTable, PageFaultInfo};
+use crate::mce::{self, MachineCheckReport};
+
+// Entry stubs for the 32 exception vectors. Vectors without an error code
+// push a zero so every stub leaves the same layout on the stack, then the
+// vector number goes on top and the common path saves the rest of the
+// register file, making a `TrapFrame` for `trap_dispatch`.
+global_asm!(r#"
+.macro TRAP_STUB vector, has_error_code
+.global trap_stub_\vector
+trap_stub_\vector:
+.if \has_error_code == 0
+    push 0
+.endif
+    push \vector
+    jmp trap_common
+.endm
+
+.section .text.trap_stubs, "ax"
+trap_common:
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+    cld
+    mov rdi, rsp
+    call trap_dispatch
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+    // vector and error code
+    add rsp, 16
+    iretq
+
+TRAP_STUB 0, 0
+TRAP_STUB 1, 0
+TRAP_STUB 2, 0
+TRAP_STUB 3, 0
+TRAP_STUB 4, 0
+TRAP_STUB 5, 0
+TRAP_STUB 6, 0
+TRAP_STUB 7, 0
+TRAP_STUB 8, 1
+TRAP_STUB 9, 0
+TRAP_STUB 10, 1
+TRAP_STUB 11, 1
+TRAP_STUB 12, 1
+TRAP_STUB 13, 1
+TRAP_STUB 14, 1
+TRAP_STUB 15, 0
+TRAP_STUB 16, 0
+TRAP_STUB 17, 1
+TRAP_STUB 18, 0
+TRAP_STUB 19, 0
+TRAP_STUB 20, 0
+TRAP_STUB 21, 1
+TRAP_STUB 22, 0
+TRAP_STUB 23, 0
+TRAP_STUB 24, 0
+TRAP_STUB 25, 0
+TRAP_STUB 26, 0
+TRAP_STUB 27, 0
+TRAP_STUB 28, 0
+TRAP_STUB 29, 1
+TRAP_STUB 30, 1
+TRAP_STUB 31, 0
+
+.section .rodata.trap_stub_table, "a"
+.global trap_stub_table
+.balign 8
+trap_stub_table:
+.irp vector, 0,1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20,21,22,23,24,25,26,27,28,29,30,31
+    .quad trap_stub_\vector
+.endr
+"#);
+
+extern "C" {
+    static trap_stub_table: [u64; 32];
+}
+
+pub const EXCEPTION_NAMES: [&str; 32] = [
+    "divide error",
+    "debug",
+    "non-maskable interrupt",
+    "breakpoint",
+    "overflow",
+    "bound range exceeded",
+    "invalid opcode",
+    "device not available",
+    "double fault",
+    "coprocessor segment overrun",
+    "invalid TSS",
+    "segment not present",
+    "stack-segment fault",
+    "general protection fault",
+    "page fault",
+    "reserved (15)",
+    "x87 floating-point exception",
+    "alignment check",
+    "machine check",
+    "SIMD floating-point exception",
+    "virtualization exception",
+    "control protection exception",
+    "reserved (22)",
+    "reserved (23)",
+    "reserved (24)",
+    "reserved (25)",
+    "reserved (26)",
+    "reserved (27)",
+    "hypervisor injection exception",
+    "VMM communication exception",
+    "security exception",
+    "reserved (31)",
+];
+
+// Register file as laid out on the stack by the entry stubs, lowest address first
+#[derive(Debug, Clone)]
+#[repr(C)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    // zero for vectors that don't push one
+    pub error_code: u64,
+    // pushed by the CPU
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DescriptorTable {
+    Gdt,
+    Idt,
+    Ldt,
+}
+
+// Error code of #TS, #NP, #SS and #GP, referring to a segment selector or IDT vector
+#[derive(Debug, Clone, Copy)]
+pub struct SelectorErrorCode {
+    pub external: bool,
+    pub table: DescriptorTable,
+    pub index: u16,
+}
+
+impl SelectorErrorCode {
+    pub fn from_err_code(code: u64) -> Self {
+        SelectorErrorCode {
+            external: code & 1 > 0,
+            table: match (code >> 1) & 0b11 {
+                0b00 => DescriptorTable::Gdt,
+                0b10 => DescriptorTable::Ldt,
+                _ => DescriptorTable::Idt,
+            },
+            index: ((code >> 3) & 0x1FFF) as u16,
+        }
+    }
+}
+
+impl fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}[{:#x}]", self.table, self.index)?;
+        if self.external {
+            write!(f, " (external)")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum ErrorCodeInfo {
+    None,
+    Selector(SelectorErrorCode),
+    PageFault(PageFaultInfo),
+    Raw(u64),
+}
+
+impl TrapFrame {
+    pub fn name(&self) -> &'static str {
+        EXCEPTION_NAMES.get(self.vector as usize).copied().unwrap_or("unknown")
+    }
+
+    pub fn error_info(&self) -> ErrorCodeInfo {
+        match self.vector {
+            // selector errors; a zero code means the fault wasn't selector related
+            10..=13 if self.error_code != 0 => {
+                ErrorCodeInfo::Selector(SelectorErrorCode::from_err_code(self.error_code))
+            }
+            14 => ErrorCodeInfo::PageFault(PageFaultInfo::from_err_code(self.error_code)),
+            8 | 10 | 11 | 12 | 13 | 17 | 21 | 29 | 30 => ErrorCodeInfo::Raw(self.error_code),
+            _ => ErrorCodeInfo::None,
+        }
+    }
+}
+
+// Everything we know about the CPU state at the time of an exception
+pub struct CrashReport<'a> {
+    pub frame: &'a TrapFrame,
+}
+
+impl<'a> fmt::Display for CrashReport<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let t = self.frame;
+        writeln!(f, "exception {} ({}) at {:#018x}", t.vector, t.name(), t.rip)?;
+        match t.error_info() {
+            ErrorCodeInfo::None => {}
+            ErrorCodeInfo::Selector(sel) => writeln!(f, "error code {:#x}: selector {}", t.error_code, sel)?,
+            ErrorCodeInfo::PageFault(info) => writeln!(f, "error code {:#x}: accessing {:#018x} {:?}",
+                                                       t.error_code, unsafe { controlregs::cr2() }, info)?,
+            ErrorCodeInfo::Raw(code) => writeln!(f, "error code {:#x}", code)?,
+        }
+        writeln!(f, "rax {:016x} rbx {:016x} rcx {:016x}", t.rax, t.rbx, t.rcx)?;
+        writeln!(f, "rdx {:016x} rsi {:016x} rdi {:016x}", t.rdx, t.rsi, t.rdi)?;
+        writeln!(f, "rbp {:016x} rsp {:016x} r8  {:016x}", t.rbp, t.rsp, t.r8)?;
+        writeln!(f, "r9  {:016x} r10 {:016x} r11 {:016x}", t.r9, t.r10, t.r11)?;
+        writeln!(f, "r12 {:016x} r13 {:016x} r14 {:016x}", t.r12, t.r13, t.r14)?;
+        writeln!(f, "r15 {:016x} rip {:016x} rfl {:016x}", t.r15, t.rip, t.rflags)?;
+        writeln!(f, "cs {:04x} ss {:04x} ds {:04x} es {:04x} fs {:04x} gs {:04x}",
+                 t.cs, t.ss, segmentation::ds().bits(), segmentation::es().bits(),
+                 segmentation::fs().bits(), segmentation::gs().bits())?;
+        unsafe {
+            write!(f, "cr0 {:08x} cr2 {:016x} cr3 {:016x} cr4 {:08x}",
+                   controlregs::cr0().bits(), controlregs::cr2(),
+                   controlregs::cr3(), controlregs::cr4().bits())
+        }
+    }
+}
+
+// Returning from a handler resumes at `frame.rip`
+pub type ExceptionHandler = fn(&mut TrapFrame);
+
+fn fatal(frame: &mut TrapFrame) {
+    panic!("{}", CrashReport { frame });
+}
+
+// Traps report the instruction after the one that raised them,
+// so there is nothing to fix up before carrying on. These can land while
+// this CPU holds the console or the log's clock, so nothing here may wait
+// for a lock: the line is dropped if the console is busy.
+fn report_and_resume(frame: &mut TrapFrame) {
+    try_println!("{} at {:#018x}, resuming", frame.name(), frame.rip);
+}
+
+fn machine_check(frame: &mut TrapFrame) {
+    if !mce::supported() {
+        fatal(frame);
+    }
+    let report = MachineCheckReport::read();
+    if !report.recoverable() {
+        panic!("{}\n{}", report, CrashReport { frame });
+    }
+    try_println!("{}", report);
+    report.clear();
+}
+
+const EXCEPTION_HANDLERS: [ExceptionHandler; 32] = {
+    let mut handlers = [fatal as ExceptionHandler; 32];
+    handlers[3] = report_and_resume;
+    handlers[4] = report_and_resume;
+    handlers[18] = machine_check;
+    handlers
+};
+
+#[no_mangle]
+extern "C" fn trap_dispatch(frame: &mut TrapFrame) {
+    match EXCEPTION_HANDLERS.get(frame.vector as usize) {
+        Some(handler) => handler(frame),
+        None => fatal(frame),
+    }
+}
+
+// Point all 32 exception vectors of `idt` at the entry stubs
+pub fn install_exception_stubs(idt: &mut InterruptDescriptorTable) {
+    for (vector, &stub) in unsafe { trap_stub_table.iter().enumerate() } {
+        idt.set_raw_handler(vector as u8, stub);
+    }
+}
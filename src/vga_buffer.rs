@@ -29,7 +29,7 @@ pub enum VGAColor {
 pub struct VGAColorCode(u8);
 
 impl VGAColorCode {
-    pub fn new(fg: VGAColor, bg: VGAColor) -> Self {
+    pub const fn new(fg: VGAColor, bg: VGAColor) -> Self {
         VGAColorCode((bg as u8) << 4 | (fg as u8))
     }
 }
@@ -53,14 +53,27 @@ pub struct VGAWriter {
 }
 
 impl VGAWriter {
-    pub fn new(x: usize, y: usize) -> Self {
+    pub const fn new(x: usize, y: usize) -> Self {
         VGAWriter {
             offset: x + y * SCREEN_WIDTH,
             color: VGAColorCode::new(VGAColor::White, VGAColor::Black),
-            buf: VGA_VADDR.as_mut_ptr()
+            buf: VGA_VADDR.0 as *mut VGABuffer
         }
     }
 
+    pub fn set_color(&mut self, color: VGAColorCode) {
+        self.color = color;
+    }
+
+    // (column, row) of the next character
+    pub fn position(&self) -> (usize, usize) {
+        (self.offset % SCREEN_WIDTH, self.offset / SCREEN_WIDTH)
+    }
+
+    pub fn set_position(&mut self, x: usize, y: usize) {
+        self.offset = x.min(SCREEN_WIDTH - 1) + y.min(SCREEN_HEIGHT - 1) * SCREEN_WIDTH;
+    }
+
     fn newline(&mut self) {
         self.offset -= self.offset % SCREEN_WIDTH;
         self.offset += SCREEN_WIDTH;
@@ -83,6 +96,11 @@ impl VGAWriter {
                 b => {
                     (*self.buf).chars[self.offset] = VGAChar { char: b, color: self.color };
                     self.offset += 1;
+                    // wrapped past the last column of the bottom row
+                    if self.offset == SCREEN_WIDTH * SCREEN_HEIGHT {
+                        self.offset -= SCREEN_WIDTH;
+                        self.newline();
+                    }
                 }
             }
         }
@@ -103,6 +121,9 @@ impl VGAWriter {
     }
 }
 
+// only ever points at the VGA text buffer, which is mapped for everyone
+unsafe impl Send for VGAWriter {}
+
 impl Write for VGAWriter {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
         self.print(s);
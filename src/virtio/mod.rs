@@ -0,0 +1,99 @@
+use crate::memory::vmm::MapError;
+use crate::pci::device::PciDevice;
+use crate::pci::msi::MsiError;
+use crate::pci::{self, PciMatch};
+pub mod transport;
+pub mod queue;
+
+// https://wiki.osdev.org/Virtio
+// Virtual I/O Device (VIRTIO) Version 1.2, sections 2, 3 and 4.1
+
+pub const VENDOR_VIRTIO: u16 = 0x1AF4;
+// transitional devices, the device type is in the subsystem id
+pub const LEGACY_DEVICE_IDS: core::ops::RangeInclusive<u16> = 0x1000..=0x103F;
+// modern only devices are 0x1040 plus the device type
+pub const MODERN_DEVICE_ID_BASE: u16 = 0x1040;
+
+pub const DEVICE_NETWORK: u16 = 1;
+pub const DEVICE_BLOCK: u16 = 2;
+pub const DEVICE_CONSOLE: u16 = 3;
+pub const DEVICE_ENTROPY: u16 = 4;
+pub const DEVICE_BALLOON: u16 = 5;
+pub const DEVICE_SCSI: u16 = 8;
+pub const DEVICE_GPU: u16 = 16;
+pub const DEVICE_INPUT: u16 = 18;
+
+// device status
+pub const STATUS_ACKNOWLEDGE: u8 = 1 << 0;
+pub const STATUS_DRIVER: u8 = 1 << 1;
+pub const STATUS_DRIVER_OK: u8 = 1 << 2;
+pub const STATUS_FEATURES_OK: u8 = 1 << 3;
+pub const STATUS_NEEDS_RESET: u8 = 1 << 6;
+pub const STATUS_FAILED: u8 = 1 << 7;
+
+// device independent feature bits
+pub const F_INDIRECT_DESC: u64 = 1 << 28;
+pub const F_EVENT_IDX: u64 = 1 << 29;
+pub const F_VERSION_1: u64 = 1 << 32;
+pub const F_ACCESS_PLATFORM: u64 = 1 << 33;
+
+#[derive(Debug)]
+pub enum VirtioError {
+    NotVirtio,
+    // neither a usable legacy I/O BAR nor the modern capabilities
+    NoTransport,
+    BadCapability(u8),
+    Map(MapError),
+    FeaturesRejected,
+    NoSuchQueue(u16),
+    QueueInUse(u16),
+    QueueFull,
+    // the device couldn't take the MSI-X vector it was given
+    NoVector(u16),
+    Msi(MsiError),
+    // every queue interrupt trampoline is taken
+    TooManyHandlers,
+}
+
+// The virtio device type of a PCI function, if it's a virtio device at all
+pub fn device_type(dev: &PciDevice) -> Option<u16> {
+    if dev.vendor_id != VENDOR_VIRTIO {
+        return None;
+    }
+    match dev.device_id {
+        id if LEGACY_DEVICE_IDS.contains(&id) => Some(dev.subsystem_id),
+        id if id >= MODERN_DEVICE_ID_BASE => Some(id - MODERN_DEVICE_ID_BASE),
+        _ => None,
+    }
+}
+
+pub fn type_name(device_type: u16) -> &'static str {
+    match device_type {
+        DEVICE_NETWORK => "network",
+        DEVICE_BLOCK => "block",
+        DEVICE_CONSOLE => "console",
+        DEVICE_ENTROPY => "entropy",
+        DEVICE_BALLOON => "balloon",
+        DEVICE_SCSI => "SCSI",
+        DEVICE_GPU => "GPU",
+        DEVICE_INPUT => "input",
+        _ => "unknown",
+    }
+}
+
+// Log the virtio devices PCI enumeration found, for drivers to pick up
+pub fn init() {
+    let devices = pci::find(&PciMatch::vendor(VENDOR_VIRTIO));
+    let mut count = 0;
+    for dev in devices.iter() {
+        if let Some(device_type) = device_type(dev) {
+            let kind = match dev.device_id >= MODERN_DEVICE_ID_BASE {
+                true => "modern",
+                false => "transitional",
+            };
+            info!("virtio {} at {}, {}", type_name(device_type), dev.address, kind);
+            count += 1;
+        }
+    }
+    debug!("virtio: {} devices", count);
+}
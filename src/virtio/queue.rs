@@ -0,0 +1,280 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{fence, Ordering};
+use x86::bits64::paging::{PAddr, BASE_PAGE_SIZE};
+use crate::memory::buddy::Zone;
+use crate::memory::dma::DmaBuffer;
+use super::VirtioError;
+
+// Virtual I/O Device (VIRTIO) Version 1.2, section 2.7
+
+const DESC_F_NEXT: u16 = 1 << 0;
+const DESC_F_WRITE: u16 = 1 << 1;
+const AVAIL_F_NO_INTERRUPT: u16 = 1 << 0;
+const USED_F_NO_NOTIFY: u16 = 1 << 0;
+
+// legacy devices want the used ring on its own page
+const QUEUE_ALIGN: usize = BASE_PAGE_SIZE;
+pub const MAX_QUEUE_SIZE: u16 = 1024;
+
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct UsedElement {
+    id: u32,
+    len: u32,
+}
+
+// One piece of a request, in memory the device can reach
+#[derive(Debug, Clone, Copy)]
+pub struct Segment {
+    pub addr: PAddr,
+    pub len: u32,
+    pub device_writable: bool,
+}
+
+impl Segment {
+    // the device reads it
+    pub fn to_device(addr: PAddr, len: u32) -> Self {
+        Segment { addr, len, device_writable: false }
+    }
+
+    // the device fills it in
+    pub fn from_device(addr: PAddr, len: u32) -> Self {
+        Segment { addr, len, device_writable: true }
+    }
+}
+
+// A chain the device is done with: the head `add` returned and how many
+// bytes the device wrote
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Used {
+    pub head: u16,
+    pub len: u32,
+}
+
+const fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+// flags, idx, ring and used_event / avail_event
+const fn avail_size(size: usize) -> usize {
+    6 + 2 * size
+}
+
+const fn used_size(size: usize) -> usize {
+    6 + size_of::<UsedElement>() * size
+}
+
+// Descriptor table, available ring and used ring in one buffer, laid out
+// the way legacy devices expect; modern ones are fine with it too
+const fn used_offset(size: usize) -> usize {
+    align_up(size_of::<Descriptor>() * size + avail_size(size), QUEUE_ALIGN)
+}
+
+const fn ring_size(size: usize) -> usize {
+    used_offset(size) + align_up(used_size(size), QUEUE_ALIGN)
+}
+
+// A split virtqueue. Free descriptors are linked through their `next`
+// fields, starting at `free_head`.
+pub struct VirtQueue {
+    index: u16,
+    size: u16,
+    ring: DmaBuffer,
+    free_head: u16,
+    free_count: u16,
+    // our copy of avail->idx, and the used->idx we've caught up with
+    avail_idx: u16,
+    last_used: u16,
+    // descriptors in the chain starting at each head
+    chain_len: Vec<u16>,
+}
+
+impl VirtQueue {
+    // `size` has to be a power of two
+    pub fn new(index: u16, size: u16) -> Result<Self, VirtioError> {
+        assert!(size.is_power_of_two() && size <= MAX_QUEUE_SIZE, "virtio: bad queue size {}", size);
+        let ring = DmaBuffer::new(ring_size(size as usize), Zone::Normal).map_err(VirtioError::Map)?;
+        let queue = VirtQueue {
+            index,
+            size,
+            ring,
+            free_head: 0,
+            free_count: size,
+            avail_idx: 0,
+            last_used: 0,
+            chain_len: vec![0; size as usize],
+        };
+        for i in 0..size {
+            queue.write_desc(i, Descriptor { next: (i + 1) % size, ..Descriptor::default() });
+        }
+        Ok(queue)
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub fn free_count(&self) -> u16 {
+        self.free_count
+    }
+
+    pub fn desc_paddr(&self) -> PAddr {
+        self.ring.paddr()
+    }
+
+    pub fn avail_paddr(&self) -> PAddr {
+        self.ring.paddr() + self.avail_offset() as u64
+    }
+
+    pub fn used_paddr(&self) -> PAddr {
+        self.ring.paddr() + used_offset(self.size as usize) as u64
+    }
+
+    fn avail_offset(&self) -> usize {
+        size_of::<Descriptor>() * self.size as usize
+    }
+
+    fn ptr<T>(&self, offset: usize) -> *mut T {
+        (self.ring.vaddr().as_u64() + offset as u64) as *mut T
+    }
+
+    fn desc(&self, i: u16) -> Descriptor {
+        unsafe { read_volatile(self.ptr(size_of::<Descriptor>() * i as usize)) }
+    }
+
+    fn write_desc(&self, i: u16, desc: Descriptor) {
+        unsafe { write_volatile(self.ptr(size_of::<Descriptor>() * i as usize), desc) }
+    }
+
+    fn avail_flags(&self) -> *mut u16 {
+        self.ptr(self.avail_offset())
+    }
+
+    fn avail_idx_ptr(&self) -> *mut u16 {
+        self.ptr(self.avail_offset() + 2)
+    }
+
+    fn avail_ring(&self, slot: u16) -> *mut u16 {
+        self.ptr(self.avail_offset() + 4 + 2 * slot as usize)
+    }
+
+    fn used_flags(&self) -> *mut u16 {
+        self.ptr(used_offset(self.size as usize))
+    }
+
+    fn used_idx(&self) -> *mut u16 {
+        self.ptr(used_offset(self.size as usize) + 2)
+    }
+
+    fn used_ring(&self, slot: u16) -> *mut UsedElement {
+        self.ptr(used_offset(self.size as usize) + 4 + size_of::<UsedElement>() * slot as usize)
+    }
+
+    // Chain `segments` together and make them available to the device.
+    // Returns the head descriptor, which comes back from `pop_used` once the
+    // device is done. The device still has to be notified.
+    pub fn add(&mut self, segments: &[Segment]) -> Result<u16, VirtioError> {
+        if segments.is_empty() || segments.len() > self.free_count as usize {
+            return Err(VirtioError::QueueFull);
+        }
+        let head = self.free_head;
+        let mut i = head;
+        for (n, segment) in segments.iter().enumerate() {
+            let next = self.desc(i).next;
+            let last = n == segments.len() - 1;
+            let mut flags = if segment.device_writable { DESC_F_WRITE } else { 0 };
+            if !last {
+                flags |= DESC_F_NEXT;
+            }
+            self.write_desc(i, Descriptor { addr: segment.addr.as_u64(), len: segment.len, flags, next });
+            if last {
+                self.free_head = next;
+            }
+            i = next;
+        }
+        self.free_count -= segments.len() as u16;
+        self.chain_len[head as usize] = segments.len() as u16;
+
+        unsafe { write_volatile(self.avail_ring(self.avail_idx % self.size), head); }
+        // the descriptors and ring entry have to be visible before the index
+        fence(Ordering::Release);
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        unsafe { write_volatile(self.avail_idx_ptr(), self.avail_idx); }
+        Ok(head)
+    }
+
+    // Whether the device wants to hear about new buffers
+    pub fn needs_notify(&self) -> bool {
+        // the index update has to be visible before we look at the flags
+        fence(Ordering::SeqCst);
+        unsafe { read_volatile(self.used_flags()) & USED_F_NO_NOTIFY == 0 }
+    }
+
+    pub fn has_used(&self) -> bool {
+        unsafe { read_volatile(self.used_idx()) != self.last_used }
+    }
+
+    // Take the next chain the device is done with and free its descriptors
+    pub fn pop_used(&mut self) -> Option<Used> {
+        if !self.has_used() {
+            return None;
+        }
+        fence(Ordering::Acquire);
+        let element = unsafe { read_volatile(self.used_ring(self.last_used % self.size)) };
+        self.last_used = self.last_used.wrapping_add(1);
+        let head = (element.id as u16) % self.size;
+        self.free_chain(head);
+        Some(Used { head, len: element.len })
+    }
+
+    // Spin until the device hands something back, for when there's no
+    // interrupt or it's too early to wait for one
+    pub fn poll_used(&mut self) -> Used {
+        loop {
+            if let Some(used) = self.pop_used() {
+                return used;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    fn free_chain(&mut self, head: u16) {
+        let count = core::mem::take(&mut self.chain_len[head as usize]);
+        // not a head we handed out, don't let the device wreck the free list
+        if count == 0 {
+            warn!("virtio: queue {} used unknown descriptor {}", self.index, head);
+            return;
+        }
+        let mut last = head;
+        for _ in 1..count {
+            last = self.desc(last).next;
+        }
+        let desc = self.desc(last);
+        self.write_desc(last, Descriptor { flags: 0, next: self.free_head, ..desc });
+        self.free_head = head;
+        self.free_count += count;
+    }
+
+    // Ask the device not to interrupt when it uses buffers. Only a hint, it
+    // may interrupt anyway.
+    pub fn set_interrupts(&mut self, enabled: bool) {
+        let flags = if enabled { 0 } else { AVAIL_F_NO_INTERRUPT };
+        unsafe { write_volatile(self.avail_flags(), flags); }
+    }
+}
@@ -0,0 +1,475 @@
+use alloc::vec::Vec;
+use core::ptr::{read_volatile, write_volatile};
+use x86::bits64::paging::PAddr;
+use x86::io::{inb, inl, inw, outb, outl, outw};
+use crate::interrupts::{IntHandler, InterruptStackFrame};
+use crate::memory::mmio;
+use crate::pci::device::{self, Bar, PciDevice, CAP_VENDOR};
+use crate::pci::msi;
+use crate::sync::SpinLock;
+use super::queue::{VirtQueue, MAX_QUEUE_SIZE};
+use super::{VirtioError, F_VERSION_1, LEGACY_DEVICE_IDS, STATUS_ACKNOWLEDGE, STATUS_DRIVER, STATUS_DRIVER_OK,
+    STATUS_FAILED, STATUS_FEATURES_OK};
+
+// Virtual I/O Device (VIRTIO) Version 1.2, section 4.1
+
+// legacy registers, in I/O BAR 0
+const LEGACY_DEVICE_FEATURES: u16 = 0x00;
+const LEGACY_DRIVER_FEATURES: u16 = 0x04;
+const LEGACY_QUEUE_PFN: u16 = 0x08;
+const LEGACY_QUEUE_SIZE: u16 = 0x0C;
+const LEGACY_QUEUE_SELECT: u16 = 0x0E;
+const LEGACY_QUEUE_NOTIFY: u16 = 0x10;
+const LEGACY_STATUS: u16 = 0x12;
+const LEGACY_ISR: u16 = 0x13;
+const LEGACY_CONFIG_VECTOR: u16 = 0x14;
+const LEGACY_QUEUE_VECTOR: u16 = 0x16;
+// device config moves past the vector registers once MSI-X is on
+const LEGACY_CONFIG: u16 = 0x14;
+const LEGACY_CONFIG_MSIX: u16 = 0x18;
+const LEGACY_QUEUE_PFN_SHIFT: u64 = 12;
+
+// virtio_pci_cap
+const CAP_CFG_TYPE: u16 = 3;
+const CAP_BAR: u16 = 4;
+const CAP_OFFSET: u16 = 8;
+const CAP_LENGTH: u16 = 12;
+const CAP_NOTIFY_MULTIPLIER: u16 = 16;
+const CFG_TYPE_COMMON: u8 = 1;
+const CFG_TYPE_NOTIFY: u8 = 2;
+const CFG_TYPE_ISR: u8 = 3;
+const CFG_TYPE_DEVICE: u8 = 4;
+
+// virtio_pci_common_cfg
+const COMMON_DEVICE_FEATURE_SELECT: u64 = 0x00;
+const COMMON_DEVICE_FEATURE: u64 = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: u64 = 0x08;
+const COMMON_DRIVER_FEATURE: u64 = 0x0C;
+const COMMON_CONFIG_MSIX_VECTOR: u64 = 0x10;
+const COMMON_NUM_QUEUES: u64 = 0x12;
+const COMMON_DEVICE_STATUS: u64 = 0x14;
+const COMMON_CONFIG_GENERATION: u64 = 0x15;
+const COMMON_QUEUE_SELECT: u64 = 0x16;
+const COMMON_QUEUE_SIZE: u64 = 0x18;
+const COMMON_QUEUE_MSIX_VECTOR: u64 = 0x1A;
+const COMMON_QUEUE_ENABLE: u64 = 0x1C;
+const COMMON_QUEUE_NOTIFY_OFF: u64 = 0x1E;
+const COMMON_QUEUE_DESC: u64 = 0x20;
+const COMMON_QUEUE_DRIVER: u64 = 0x28;
+const COMMON_QUEUE_DEVICE: u64 = 0x30;
+
+pub const NO_VECTOR: u16 = 0xFFFF;
+pub const ISR_QUEUE: u8 = 1 << 0;
+pub const ISR_CONFIG: u8 = 1 << 1;
+
+const RESET_SPINS: usize = 1_000_000;
+const MAX_QUEUE_IRQS: usize = 8;
+
+// Where the modern register blocks ended up in the MMIO window
+#[derive(Debug, Clone, Copy)]
+struct ModernRegs {
+    common: u64,
+    notify: u64,
+    notify_multiplier: u32,
+    isr: u64,
+    // 0 for devices without device specific config
+    device: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Registers {
+    Legacy { port: u16 },
+    Modern(ModernRegs),
+}
+
+// The PCI side of one virtio device: status, features, queue setup,
+// notifications and device config, the same for legacy and modern devices.
+// Queue interrupts need MSI-X; there's no INTx path, so on devices without
+// it drivers have to `poll_used`.
+pub struct Transport {
+    dev: PciDevice,
+    regs: Registers,
+    // queue_notify_off of each queue that's been set up, modern only
+    notify_offsets: Vec<(u16, u16)>,
+}
+
+// driver callbacks behind each queue interrupt trampoline
+type QueueHandlers = [Option<fn()>; MAX_QUEUE_IRQS];
+
+static QUEUE_HANDLERS: SpinLock<QueueHandlers> = SpinLock::new([None; MAX_QUEUE_IRQS]);
+
+extern "x86-interrupt" fn queue_handler<const N: usize>(_frame: InterruptStackFrame) {
+    let handler = QUEUE_HANDLERS.lock()[N];
+    if let Some(handler) = handler {
+        handler();
+    }
+    msi::eoi();
+}
+
+const HANDLERS: [IntHandler; MAX_QUEUE_IRQS] = [
+    queue_handler::<0>,
+    queue_handler::<1>,
+    queue_handler::<2>,
+    queue_handler::<3>,
+    queue_handler::<4>,
+    queue_handler::<5>,
+    queue_handler::<6>,
+    queue_handler::<7>,
+];
+
+// First of each vendor capability type we know, mapped
+fn find_modern(dev: &PciDevice) -> Result<Option<ModernRegs>, VirtioError> {
+    let address = dev.address;
+    let mut regs = ModernRegs { common: 0, notify: 0, notify_multiplier: 0, isr: 0, device: 0 };
+    for (id, offset) in device::capabilities(address) {
+        let cap = offset as u16;
+        let cfg_type = device::read_u8(address, cap + CAP_CFG_TYPE);
+        let slot = match (id, cfg_type) {
+            (CAP_VENDOR, CFG_TYPE_COMMON) => &mut regs.common,
+            (CAP_VENDOR, CFG_TYPE_NOTIFY) => &mut regs.notify,
+            (CAP_VENDOR, CFG_TYPE_ISR) => &mut regs.isr,
+            (CAP_VENDOR, CFG_TYPE_DEVICE) => &mut regs.device,
+            _ => continue,
+        };
+        if *slot != 0 {
+            continue;
+        }
+        let bar = device::read_u8(address, cap + CAP_BAR);
+        let bar_address = match dev.bars.get(bar as usize).copied().flatten() {
+            Some(Bar::Memory { address, .. }) => address,
+            _ => return Err(VirtioError::BadCapability(cfg_type)),
+        };
+        let start = bar_address + device::read_u32(address, cap + CAP_OFFSET) as u64;
+        let length = device::read_u32(address, cap + CAP_LENGTH) as usize;
+        *slot = mmio::map_mmio(PAddr(start), length).map_err(VirtioError::Map)?.as_u64();
+        if cfg_type == CFG_TYPE_NOTIFY {
+            regs.notify_multiplier = device::read_u32(address, cap + CAP_NOTIFY_MULTIPLIER);
+        }
+    }
+    match regs.common != 0 && regs.notify != 0 && regs.isr != 0 {
+        true => Ok(Some(regs)),
+        false => Ok(None),
+    }
+}
+
+impl ModernRegs {
+    fn read<T>(&self, reg: u64) -> T {
+        unsafe { read_volatile((self.common + reg) as *const T) }
+    }
+
+    fn write<T>(&self, reg: u64, value: T) {
+        unsafe { write_volatile((self.common + reg) as *mut T, value) }
+    }
+
+    // 64-bit registers as two halves, devices have to accept that
+    fn write_u64(&self, reg: u64, value: u64) {
+        self.write(reg, value as u32);
+        self.write(reg + 4, (value >> 32) as u32);
+    }
+}
+
+impl Transport {
+    // Take over the device: modern interface if it has one, legacy if not.
+    // Leaves it reset, acknowledged and ready for `negotiate`.
+    pub fn new(dev: &PciDevice) -> Result<Self, VirtioError> {
+        if super::device_type(dev).is_none() {
+            return Err(VirtioError::NotVirtio);
+        }
+        let regs = match find_modern(dev)? {
+            Some(regs) => Registers::Modern(regs),
+            None => match dev.bars[0] {
+                Some(Bar::Io { port, .. }) if LEGACY_DEVICE_IDS.contains(&dev.device_id) => {
+                    Registers::Legacy { port: port as u16 }
+                }
+                _ => return Err(VirtioError::NoTransport),
+            },
+        };
+        dev.enable(true);
+        let transport = Transport { dev: *dev, regs, notify_offsets: Vec::new() };
+        transport.reset();
+        transport.add_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        Ok(transport)
+    }
+
+    pub fn device(&self) -> &PciDevice {
+        &self.dev
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        matches!(self.regs, Registers::Legacy { .. })
+    }
+
+    pub fn status(&self) -> u8 {
+        match self.regs {
+            Registers::Legacy { port } => unsafe { inb(port + LEGACY_STATUS) },
+            Registers::Modern(regs) => regs.read(COMMON_DEVICE_STATUS),
+        }
+    }
+
+    fn set_status(&self, status: u8) {
+        match self.regs {
+            Registers::Legacy { port } => unsafe { outb(port + LEGACY_STATUS, status) },
+            Registers::Modern(regs) => regs.write(COMMON_DEVICE_STATUS, status),
+        }
+    }
+
+    pub fn add_status(&self, bits: u8) {
+        self.set_status(self.status() | bits);
+    }
+
+    // Writing 0 resets the device, modern ones say when they're done
+    pub fn reset(&self) {
+        self.set_status(0);
+        for _ in 0..RESET_SPINS {
+            if self.status() == 0 {
+                return;
+            }
+            core::hint::spin_loop();
+        }
+        warn!("virtio {}: reset timed out", self.dev.address);
+    }
+
+    // Let the driver know something's wrong, the device gives up on it
+    pub fn fail(&self) {
+        self.add_status(STATUS_FAILED);
+    }
+
+    pub fn device_features(&self) -> u64 {
+        match self.regs {
+            Registers::Legacy { port } => unsafe { inl(port + LEGACY_DEVICE_FEATURES) as u64 },
+            Registers::Modern(regs) => {
+                regs.write(COMMON_DEVICE_FEATURE_SELECT, 0u32);
+                let low = regs.read::<u32>(COMMON_DEVICE_FEATURE) as u64;
+                regs.write(COMMON_DEVICE_FEATURE_SELECT, 1u32);
+                let high = regs.read::<u32>(COMMON_DEVICE_FEATURE) as u64;
+                low | high << 32
+            }
+        }
+    }
+
+    fn set_driver_features(&self, features: u64) {
+        match self.regs {
+            Registers::Legacy { port } => unsafe { outl(port + LEGACY_DRIVER_FEATURES, features as u32) },
+            Registers::Modern(regs) => {
+                regs.write(COMMON_DRIVER_FEATURE_SELECT, 0u32);
+                regs.write(COMMON_DRIVER_FEATURE, features as u32);
+                regs.write(COMMON_DRIVER_FEATURE_SELECT, 1u32);
+                regs.write(COMMON_DRIVER_FEATURE, (features >> 32) as u32);
+            }
+        }
+    }
+
+    // Accept what both the driver (`supported`) and the device can do.
+    // Modern devices always get VERSION_1 and have to agree to the result.
+    pub fn negotiate(&self, supported: u64) -> Result<u64, VirtioError> {
+        let offered = self.device_features();
+        let features = match self.regs {
+            Registers::Legacy { .. } => offered & supported & u32::MAX as u64,
+            Registers::Modern(_) => offered & (supported | F_VERSION_1),
+        };
+        self.set_driver_features(features);
+        if let Registers::Modern(_) = self.regs {
+            self.add_status(STATUS_FEATURES_OK);
+            if self.status() & STATUS_FEATURES_OK == 0 {
+                self.fail();
+                return Err(VirtioError::FeaturesRejected);
+            }
+        }
+        Ok(features)
+    }
+
+    pub fn queue_count(&self) -> u16 {
+        match self.regs {
+            // no way to ask, queues just read as size 0 past the end
+            Registers::Legacy { .. } => u16::MAX,
+            Registers::Modern(regs) => regs.read(COMMON_NUM_QUEUES),
+        }
+    }
+
+    fn select_queue(&self, index: u16) {
+        match self.regs {
+            Registers::Legacy { port } => unsafe { outw(port + LEGACY_QUEUE_SELECT, index) },
+            Registers::Modern(regs) => regs.write(COMMON_QUEUE_SELECT, index),
+        }
+    }
+
+    // Allocate queue `index` and hand it to the device. Modern devices get
+    // at most `max_size` entries, legacy ones decide for themselves. With a
+    // `handler`, it runs whenever the device uses buffers of the queue; the
+    // vector is in place before the queue goes live, as virtio 1.x wants.
+    pub fn setup_queue(&mut self, index: u16, max_size: u16, handler: Option<fn()>) -> Result<VirtQueue, VirtioError> {
+        if index >= self.queue_count() {
+            return Err(VirtioError::NoSuchQueue(index));
+        }
+        self.select_queue(index);
+        match self.regs {
+            Registers::Legacy { port } => {
+                let size = unsafe { inw(port + LEGACY_QUEUE_SIZE) };
+                if size == 0 || !size.is_power_of_two() || size > MAX_QUEUE_SIZE {
+                    return Err(VirtioError::NoSuchQueue(index));
+                }
+                if unsafe { inl(port + LEGACY_QUEUE_PFN) } != 0 {
+                    return Err(VirtioError::QueueInUse(index));
+                }
+                let queue = VirtQueue::new(index, size)?;
+                if let Some(handler) = handler {
+                    self.attach_handler(index, handler)?;
+                }
+                let pfn = queue.desc_paddr().as_u64() >> LEGACY_QUEUE_PFN_SHIFT;
+                unsafe { outl(port + LEGACY_QUEUE_PFN, pfn as u32); }
+                Ok(queue)
+            }
+            Registers::Modern(regs) => {
+                let device_size = regs.read::<u16>(COMMON_QUEUE_SIZE);
+                if device_size == 0 {
+                    return Err(VirtioError::NoSuchQueue(index));
+                }
+                if regs.read::<u16>(COMMON_QUEUE_ENABLE) != 0 {
+                    return Err(VirtioError::QueueInUse(index));
+                }
+                // largest power of two both sides are happy with
+                let limit = device_size.min(max_size).min(MAX_QUEUE_SIZE).max(1);
+                let size = 1 << (15 - limit.leading_zeros());
+                let queue = VirtQueue::new(index, size)?;
+                if let Some(handler) = handler {
+                    self.attach_handler(index, handler)?;
+                }
+                regs.write(COMMON_QUEUE_SIZE, size);
+                regs.write_u64(COMMON_QUEUE_DESC, queue.desc_paddr().as_u64());
+                regs.write_u64(COMMON_QUEUE_DRIVER, queue.avail_paddr().as_u64());
+                regs.write_u64(COMMON_QUEUE_DEVICE, queue.used_paddr().as_u64());
+                let notify_offset = regs.read::<u16>(COMMON_QUEUE_NOTIFY_OFF);
+                regs.write(COMMON_QUEUE_ENABLE, 1u16);
+                self.notify_offsets.push((index, notify_offset));
+                Ok(queue)
+            }
+        }
+    }
+
+    // Tell the device there's something new in `queue`
+    pub fn notify(&self, queue: &VirtQueue) {
+        let index = queue.index();
+        match self.regs {
+            Registers::Legacy { port } => unsafe { outw(port + LEGACY_QUEUE_NOTIFY, index) },
+            Registers::Modern(regs) => {
+                let offset = match self.notify_offsets.iter().find(|(queue, _)| *queue == index) {
+                    Some(&(_, offset)) => offset,
+                    None => return,
+                };
+                let address = regs.notify + offset as u64 * regs.notify_multiplier as u64;
+                unsafe { write_volatile(address as *mut u16, index); }
+            }
+        }
+    }
+
+    // Done setting up, the device can start using its queues
+    pub fn driver_ok(&self) {
+        self.add_status(STATUS_DRIVER_OK);
+    }
+
+    // What INTx would have been about, reading it clears it
+    pub fn isr_status(&self) -> u8 {
+        match self.regs {
+            Registers::Legacy { port } => unsafe { inb(port + LEGACY_ISR) },
+            Registers::Modern(regs) => unsafe { read_volatile(regs.isr as *const u8) },
+        }
+    }
+
+    // Point queue `index` at MSI-X entry `index`, which runs `handler`.
+    // Leaves the queue selected.
+    fn attach_handler(&mut self, index: u16, handler: fn()) -> Result<(), VirtioError> {
+        let slot = {
+            let mut handlers = QUEUE_HANDLERS.lock();
+            let slot = handlers.iter().position(Option::is_none).ok_or(VirtioError::TooManyHandlers)?;
+            handlers[slot] = Some(handler);
+            slot
+        };
+        if let Err(e) = msi::request_msix(&self.dev, index, HANDLERS[slot]) {
+            QUEUE_HANDLERS.lock()[slot] = None;
+            return Err(VirtioError::Msi(e));
+        }
+        self.select_queue(index);
+        let accepted = match self.regs {
+            Registers::Legacy { port } => unsafe {
+                outw(port + LEGACY_QUEUE_VECTOR, index);
+                inw(port + LEGACY_QUEUE_VECTOR)
+            },
+            Registers::Modern(regs) => {
+                regs.write(COMMON_QUEUE_MSIX_VECTOR, index);
+                regs.read::<u16>(COMMON_QUEUE_MSIX_VECTOR)
+            }
+        };
+        if accepted == NO_VECTOR {
+            let _ = msi::free_msix(&self.dev, index);
+            QUEUE_HANDLERS.lock()[slot] = None;
+            return Err(VirtioError::NoVector(index));
+        }
+        Ok(())
+    }
+
+    // Configuration change interrupts are unused, say so explicitly so
+    // legacy devices don't fall back to INTx for them. Without MSI-X the
+    // legacy vector register isn't there, its offset is device config.
+    pub fn disable_config_interrupt(&self) {
+        match self.regs {
+            Registers::Legacy { port } if msi::msix_enabled(&self.dev) => unsafe {
+                outw(port + LEGACY_CONFIG_VECTOR, NO_VECTOR)
+            },
+            Registers::Legacy { .. } => {}
+            Registers::Modern(regs) => regs.write(COMMON_CONFIG_MSIX_VECTOR, NO_VECTOR),
+        }
+    }
+
+    // Bumped by the device whenever its config changes, reads spanning
+    // more than one field should be retried until it stays the same
+    pub fn config_generation(&self) -> u8 {
+        match self.regs {
+            Registers::Legacy { .. } => 0,
+            Registers::Modern(regs) => regs.read(COMMON_CONFIG_GENERATION),
+        }
+    }
+
+    fn legacy_config(&self, port: u16, offset: u16) -> u16 {
+        port + if msi::msix_enabled(&self.dev) { LEGACY_CONFIG_MSIX } else { LEGACY_CONFIG } + offset
+    }
+
+    pub fn read_config_u8(&self, offset: u16) -> u8 {
+        match self.regs {
+            Registers::Legacy { port } => unsafe { inb(self.legacy_config(port, offset)) },
+            Registers::Modern(regs) if regs.device != 0 => unsafe {
+                read_volatile((regs.device + offset as u64) as *const u8)
+            },
+            Registers::Modern(_) => 0,
+        }
+    }
+
+    pub fn read_config_u16(&self, offset: u16) -> u16 {
+        match self.regs {
+            Registers::Legacy { port } => unsafe { inw(self.legacy_config(port, offset)) },
+            Registers::Modern(regs) if regs.device != 0 => unsafe {
+                read_volatile((regs.device + offset as u64) as *const u16)
+            },
+            Registers::Modern(_) => 0,
+        }
+    }
+
+    pub fn read_config_u32(&self, offset: u16) -> u32 {
+        match self.regs {
+            Registers::Legacy { port } => unsafe { inl(self.legacy_config(port, offset)) },
+            Registers::Modern(regs) if regs.device != 0 => unsafe {
+                read_volatile((regs.device + offset as u64) as *const u32)
+            },
+            Registers::Modern(_) => 0,
+        }
+    }
+
+    pub fn write_config_u8(&self, offset: u16, value: u8) {
+        match self.regs {
+            Registers::Legacy { port } => unsafe { outb(self.legacy_config(port, offset), value) },
+            Registers::Modern(regs) if regs.device != 0 => unsafe {
+                write_volatile((regs.device + offset as u64) as *mut u8, value)
+            },
+            Registers::Modern(_) => {}
+        }
+    }
+}